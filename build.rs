@@ -3,8 +3,8 @@ use std::fs::File;
 use std::io::Write;
 use std::path::PathBuf;
 fn main() {
+    let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
     if env::var_os("CARGO_FEATURE_RT").is_some() {
-        let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
         File::create(out.join("memory.x"))
             .unwrap()
             .write_all(include_bytes!("memory.x"))
@@ -12,5 +12,21 @@ fn main() {
         println!("cargo:rustc-link-search={}", out.display());
         println!("cargo:rerun-if-changed=memory.x");
     }
+
+    // Standard MIDI File to build into the firmware, which plays without a host until another
+    // sequence is uploaded
+    let sequence = match env::var_os("MAGNET_ZITHER_SEQUENCE") {
+        Some(path) => {
+            println!("cargo:rerun-if-changed={}", path.to_string_lossy());
+            std::fs::read(&path)
+                .unwrap_or_else(|e| panic!("Failed to read {}: {}", path.to_string_lossy(), e))
+        }
+        None => Vec::new(),
+    };
+    File::create(out.join("sequence.mid"))
+        .unwrap()
+        .write_all(&sequence)
+        .unwrap();
+    println!("cargo:rerun-if-env-changed=MAGNET_ZITHER_SEQUENCE");
     println!("cargo:rerun-if-changed=build.rs");
 }
@@ -0,0 +1,200 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Context, Result};
+use magnet_zither_protocol::{GlobalParam, StringParam, PROTOCOL_VERSION};
+
+use crate::client::Client;
+use crate::transport::Transport;
+
+/// Every setting of a device, stored as a text file with one setting per line:
+///
+/// ```text
+/// string <string> <param> <value>
+/// note <note> <string> <harmonic>
+/// global <param> <value>
+/// ```
+///
+/// Blank lines and lines starting with `#` are ignored. Notes that aren't listed are unmapped
+/// when restoring.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Backup {
+    pub strings: Vec<Vec<(StringParam, u32)>>,
+    pub notes: Vec<(u8, (u8, u8))>,
+    pub globals: Vec<(GlobalParam, u32)>,
+}
+
+impl Backup {
+    pub fn read<T: Transport>(client: &mut Client<T>) -> Result<Self> {
+        Ok(Self {
+            strings: client.list_strings()?,
+            notes: client.list_notes()?,
+            globals: client.list_globals()?,
+        })
+    }
+
+    pub fn restore<T: Transport>(&self, client: &mut Client<T>) -> Result<()> {
+        for (string, params) in self.strings.iter().enumerate() {
+            for &(param, value) in params {
+                client
+                    .set_string(string as u8, param, value)
+                    .with_context(|| format!("Failed to restore string {}", string))?;
+            }
+        }
+
+        for note in 0..128 {
+            let mapping = self
+                .notes
+                .iter()
+                .find(|&&(n, _)| n == note)
+                .map(|&(_, mapping)| mapping);
+            client
+                .set_note(note, mapping)
+                .with_context(|| format!("Failed to restore note {}", note))?;
+        }
+
+        for &(param, value) in &self.globals {
+            client
+                .set_global(param, value)
+                .with_context(|| format!("Failed to restore {}", param.name()))?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Backup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "# Magnet zither settings, protocol version {}",
+            PROTOCOL_VERSION
+        )?;
+        for (string, params) in self.strings.iter().enumerate() {
+            for (param, value) in params {
+                writeln!(f, "string {} {} {}", string, param.name(), value)?;
+            }
+        }
+        for (note, (string, harmonic)) in &self.notes {
+            writeln!(f, "note {} {} {}", note, string, harmonic)?;
+        }
+        for (param, value) in &self.globals {
+            writeln!(f, "global {} {}", param.name(), value)?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_number<N: FromStr>(s: Option<&str>, what: &str) -> Result<N> {
+    let s = s.ok_or_else(|| anyhow!("Missing {}", what))?;
+    s.parse()
+        .map_err(|_| anyhow!("Invalid {}: \"{}\"", what, s))
+}
+
+fn parse_string_param(s: Option<&str>) -> Result<StringParam> {
+    let s = s.ok_or_else(|| anyhow!("Missing parameter"))?;
+    StringParam::from_name(s).ok_or_else(|| anyhow!("Unknown string parameter \"{}\"", s))
+}
+
+fn parse_global_param(s: Option<&str>) -> Result<GlobalParam> {
+    let s = s.ok_or_else(|| anyhow!("Missing parameter"))?;
+    GlobalParam::from_name(s).ok_or_else(|| anyhow!("Unknown global parameter \"{}\"", s))
+}
+
+impl FromStr for Backup {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut backup = Self::default();
+
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut words = line.split_whitespace();
+            let result = (|| {
+                match words.next() {
+                    Some("string") => {
+                        let string: usize = parse_number(words.next(), "string")?;
+                        let param = parse_string_param(words.next())?;
+                        let value = parse_number(words.next(), "value")?;
+                        if backup.strings.len() <= string {
+                            backup.strings.resize(string + 1, Vec::new());
+                        }
+                        backup.strings[string].push((param, value));
+                    }
+                    Some("note") => {
+                        let note = parse_number(words.next(), "note")?;
+                        let string = parse_number(words.next(), "string")?;
+                        let harmonic = parse_number(words.next(), "harmonic")?;
+                        backup.notes.push((note, (string, harmonic)));
+                    }
+                    Some("global") => {
+                        let param = parse_global_param(words.next())?;
+                        let value = parse_number(words.next(), "value")?;
+                        backup.globals.push((param, value));
+                    }
+                    Some(kind) => bail!("Unknown setting \"{}\"", kind),
+                    None => unreachable!(),
+                }
+                if words.next().is_some() {
+                    bail!("Too many values");
+                }
+                Ok(())
+            })();
+            result.with_context(|| format!("Line {}", i + 1))?;
+        }
+
+        Ok(backup)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::SimulatedDevice;
+
+    #[test]
+    fn text_round_trip() {
+        let backup = Backup::read(&mut Client::new(SimulatedDevice::new())).unwrap();
+        assert_eq!(backup.to_string().parse::<Backup>().unwrap(), backup);
+    }
+
+    #[test]
+    fn restore() {
+        let mut original = Client::new(SimulatedDevice::new());
+        original
+            .set_string(4, StringParam::Period, 1_234_567)
+            .unwrap();
+        original.set_note(67, None).unwrap();
+        original.set_note(48, Some((7, 2))).unwrap();
+        original.set_global(GlobalParam::PowerBudget, 500).unwrap();
+        let backup = Backup::read(&mut original).unwrap();
+
+        let mut restored = Client::new(SimulatedDevice::new());
+        backup.restore(&mut restored).unwrap();
+        assert_eq!(Backup::read(&mut restored).unwrap(), backup);
+    }
+
+    #[test]
+    fn comments_and_blank_lines() {
+        let backup: Backup = "# comment\n\nglobal power-budget 100\n".parse().unwrap();
+        assert_eq!(backup.globals, vec![(GlobalParam::PowerBudget, 100)]);
+    }
+
+    #[test]
+    fn parse_errors() {
+        for text in [
+            "string 0 bogus 1",
+            "string 0 period",
+            "string 0 period 1 2",
+            "note 300 0 1",
+            "global period 1",
+            "bogus",
+        ] {
+            assert!(text.parse::<Backup>().is_err(), "{}", text);
+        }
+    }
+}
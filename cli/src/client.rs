@@ -0,0 +1,647 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use magnet_zither_protocol::{
+    decode_panic_report, Command, DecodeError, DiagnosticParam, ErrorCode, GlobalParam,
+    PanicReport, PresetName, Reply, Report, SequenceChunk, StringParam, MAX_PRESET_NAME_LEN,
+    MAX_SEQUENCE_CHUNK_LEN, PROTOCOL_VERSION,
+};
+
+use crate::transport::Transport;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Error reply from the device
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DeviceError {
+    pub command: Command,
+    pub code: ErrorCode,
+}
+
+impl fmt::Display for DeviceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self.code {
+            ErrorCode::UnknownCommand => "unknown command",
+            ErrorCode::Malformed => "malformed command",
+            ErrorCode::InvalidString => "no such string",
+            ErrorCode::InvalidParam => "no such parameter",
+            ErrorCode::InvalidValue => "value out of range",
+            ErrorCode::Unsupported => "not supported by this string",
+            ErrorCode::Busy => "busy playing",
+            ErrorCode::InvalidPreset => "no such preset",
+            ErrorCode::StorageFailed => "writing to flash failed",
+            ErrorCode::InvalidSequence => "not a playable MIDI file",
+        };
+        write!(f, "Device rejected {:?}: {}", self.command, reason)
+    }
+}
+
+impl std::error::Error for DeviceError {}
+
+/// Sends commands to the device and waits for their replies
+pub struct Client<T: Transport> {
+    transport: T,
+    timeout: Duration,
+    panic_reports: Vec<String>,
+    /// Reports received while waiting for replies
+    reports: VecDeque<Report>,
+}
+
+impl<T: Transport> Client<T> {
+    pub fn new(transport: T) -> Self {
+        Self {
+            transport,
+            timeout: DEFAULT_TIMEOUT,
+            panic_reports: Vec::new(),
+            reports: VecDeque::new(),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    /// Panic reports received from the device since the last call
+    pub fn take_panic_reports(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.panic_reports)
+    }
+
+    /// Next report from the device, or `None` if nothing arrives before the timeout
+    pub fn next_report(&mut self, timeout: Duration) -> Result<Option<Report>> {
+        if let Some(report) = self.reports.pop_front() {
+            return Ok(Some(report));
+        }
+
+        let message = match self.transport.receive(timeout)? {
+            Some(message) => message,
+            None => return Ok(None),
+        };
+        if let Some(report) = decode_panic_report(&message) {
+            self.panic_reports.push(describe_panic(report));
+            return Ok(None);
+        }
+        // Anything else is a late reply to an earlier command
+        Ok(Report::decode(&message).ok())
+    }
+
+    /// Send a command and collect its replies. Get commands return their value reply, and
+    /// acknowledged commands return any value replies that were sent before the acknowledgement.
+    /// Replies left over from earlier commands that timed out are skipped.
+    fn transact(&mut self, command: Command) -> Result<Vec<Reply>> {
+        self.transport.send(&command.encode())?;
+
+        let mut replies = Vec::new();
+        loop {
+            let message = self
+                .transport
+                .receive(self.timeout)?
+                .ok_or_else(|| anyhow!("Timed out waiting for reply to {:?}", command))?;
+
+            if let Some(report) = decode_panic_report(&message) {
+                self.panic_reports.push(describe_panic(report));
+                continue;
+            }
+
+            if let Ok(report) = Report::decode(&message) {
+                self.reports.push_back(report);
+                continue;
+            }
+
+            match Reply::decode(&message) {
+                Err(DecodeError::NotForUs) => continue,
+                Err(e) => bail!("Invalid reply from device: {:?}", e),
+                Ok(Reply::Error { command: id, code }) if id == command.id() => {
+                    return Err(DeviceError { command, code }.into())
+                }
+                Ok(Reply::Ack { command: id }) if id == command.id() => return Ok(replies),
+                // Left over from an earlier command that timed out
+                Ok(Reply::Ack { .. } | Reply::Error { .. }) => continue,
+                Ok(reply) if command.is_acknowledged() => replies.push(reply),
+                Ok(reply) if answers(&command, &reply) => return Ok(vec![reply]),
+                Ok(_) => continue,
+            }
+        }
+    }
+
+    fn get(&mut self, command: Command) -> Result<Reply> {
+        Ok(self.transact(command)?.remove(0))
+    }
+
+    pub fn version(&mut self) -> Result<u8> {
+        match self.get(Command::GetVersion)? {
+            Reply::Version(version) => Ok(version),
+            reply => bail!("Unexpected reply: {:?}", reply),
+        }
+    }
+
+    /// Make sure the device speaks the same protocol version
+    pub fn check_version(&mut self) -> Result<()> {
+        let version = self.version()?;
+        if version != PROTOCOL_VERSION {
+            bail!(
+                "Device uses protocol version {}, but this tool supports version {}",
+                version,
+                PROTOCOL_VERSION
+            );
+        }
+        Ok(())
+    }
+
+    pub fn get_string(&mut self, string: u8, param: StringParam) -> Result<u32> {
+        match self.get(Command::GetString { string, param })? {
+            Reply::StringValue { value, .. } => Ok(value),
+            reply => bail!("Unexpected reply: {:?}", reply),
+        }
+    }
+
+    pub fn set_string(&mut self, string: u8, param: StringParam, value: u32) -> Result<()> {
+        self.transact(Command::SetString {
+            string,
+            param,
+            value,
+        })?;
+        Ok(())
+    }
+
+    pub fn list_string(&mut self, string: u8) -> Result<Vec<(StringParam, u32)>> {
+        self.transact(Command::ListString { string })?
+            .into_iter()
+            .map(|reply| match reply {
+                Reply::StringValue { param, value, .. } => Ok((param, value)),
+                reply => bail!("Unexpected reply: {:?}", reply),
+            })
+            .collect()
+    }
+
+    /// Parameters of every string on the device
+    pub fn list_strings(&mut self) -> Result<Vec<Vec<(StringParam, u32)>>> {
+        let mut strings = Vec::new();
+        for string in 0..=u8::MAX >> 1 {
+            match self.list_string(string) {
+                Ok(params) => strings.push(params),
+                Err(e) if is_invalid_string(&e) => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(strings)
+    }
+
+    pub fn get_note(&mut self, note: u8) -> Result<Option<(u8, u8)>> {
+        match self.get(Command::GetNote { note })? {
+            Reply::NoteValue { mapping, .. } => Ok(mapping),
+            reply => bail!("Unexpected reply: {:?}", reply),
+        }
+    }
+
+    pub fn set_note(&mut self, note: u8, mapping: Option<(u8, u8)>) -> Result<()> {
+        self.transact(Command::SetNote { note, mapping })?;
+        Ok(())
+    }
+
+    /// All mapped notes, with their string and harmonic
+    pub fn list_notes(&mut self) -> Result<Vec<(u8, (u8, u8))>> {
+        self.transact(Command::ListNotes)?
+            .into_iter()
+            .filter_map(|reply| match reply {
+                Reply::NoteValue { note, mapping } => mapping.map(|m| Ok((note, m))),
+                reply => Some(Err(anyhow!("Unexpected reply: {:?}", reply))),
+            })
+            .collect()
+    }
+
+    pub fn get_global(&mut self, param: GlobalParam) -> Result<u32> {
+        match self.get(Command::GetGlobal { param })? {
+            Reply::GlobalValue { value, .. } => Ok(value),
+            reply => bail!("Unexpected reply: {:?}", reply),
+        }
+    }
+
+    pub fn set_global(&mut self, param: GlobalParam, value: u32) -> Result<()> {
+        self.transact(Command::SetGlobal { param, value })?;
+        Ok(())
+    }
+
+    pub fn list_globals(&mut self) -> Result<Vec<(GlobalParam, u32)>> {
+        self.transact(Command::ListGlobals)?
+            .into_iter()
+            .map(|reply| match reply {
+                Reply::GlobalValue { param, value } => Ok((param, value)),
+                reply => bail!("Unexpected reply: {:?}", reply),
+            })
+            .collect()
+    }
+
+    /// Pluck a string and measure its frequency. The new period can be read back once the
+    /// string has stopped ringing.
+    pub fn calibrate(&mut self, string: u8) -> Result<()> {
+        self.transact(Command::Calibrate { string })?;
+        Ok(())
+    }
+
+    pub fn list_diagnostics(&mut self) -> Result<Vec<(DiagnosticParam, u8, u32)>> {
+        self.transact(Command::ListDiagnostics)?
+            .into_iter()
+            .map(|reply| match reply {
+                Reply::DiagnosticValue {
+                    param,
+                    index,
+                    value,
+                } => Ok((param, index, value)),
+                reply => bail!("Unexpected reply: {:?}", reply),
+            })
+            .collect()
+    }
+
+    /// Release outputs that were shut down by a fault. Fails if a fault input is still active.
+    pub fn clear_faults(&mut self) -> Result<()> {
+        self.transact(Command::ClearFaults)?;
+        Ok(())
+    }
+
+    /// Store the current settings on the device as a preset, which can then be selected with a
+    /// program change
+    pub fn save_preset(&mut self, preset: u8, name: &str) -> Result<()> {
+        let name = PresetName::new(name.as_bytes()).ok_or_else(|| {
+            anyhow!(
+                "Preset names must be ASCII and at most {} characters long",
+                MAX_PRESET_NAME_LEN
+            )
+        })?;
+        self.transact(Command::SavePreset { preset, name })?;
+        Ok(())
+    }
+
+    pub fn load_preset(&mut self, preset: u8) -> Result<()> {
+        self.transact(Command::LoadPreset { preset })?;
+        Ok(())
+    }
+
+    /// Numbers and names of all stored presets
+    pub fn list_presets(&mut self) -> Result<Vec<(u8, String)>> {
+        self.transact(Command::ListPresets)?
+            .into_iter()
+            .map(|reply| match reply {
+                Reply::PresetValue { preset, name } => Ok((
+                    preset,
+                    String::from_utf8_lossy(name.as_bytes()).into_owned(),
+                )),
+                reply => bail!("Unexpected reply: {:?}", reply),
+            })
+            .collect()
+    }
+
+    pub fn delete_preset(&mut self, preset: u8) -> Result<()> {
+        self.transact(Command::DeletePreset { preset })?;
+        Ok(())
+    }
+
+    /// Store a Standard MIDI File on the device, replacing any sequence it already has. An empty
+    /// file deletes the stored sequence.
+    pub fn upload_sequence(&mut self, data: &[u8]) -> Result<()> {
+        let len = u32::try_from(data.len()).map_err(|_| anyhow!("Sequence is too long"))?;
+        self.transact(Command::BeginSequence { len })?;
+        for chunk in data.chunks(MAX_SEQUENCE_CHUNK_LEN) {
+            let data = SequenceChunk::new(chunk).unwrap();
+            self.transact(Command::SequenceData { data })?;
+        }
+        self.transact(Command::EndSequence)?;
+        Ok(())
+    }
+
+    pub fn play_sequence(&mut self) -> Result<()> {
+        self.transact(Command::PlaySequence)?;
+        Ok(())
+    }
+
+    pub fn stop_sequence(&mut self) -> Result<()> {
+        self.transact(Command::StopSequence)?;
+        Ok(())
+    }
+}
+
+/// Panic report as text, followed by where the panic happened if that is known
+fn describe_panic(report: PanicReport) -> String {
+    let text = String::from_utf8_lossy(report.text);
+    if report.line == 0 {
+        text.into_owned()
+    } else {
+        format!("{} (line {}, column {})", text, report.line, report.column)
+    }
+}
+
+/// Whether `reply` is the value a get command asked for, rather than a late reply to an earlier
+/// command
+fn answers(command: &Command, reply: &Reply) -> bool {
+    match (command, reply) {
+        (Command::GetVersion, Reply::Version(_)) => true,
+        (
+            Command::GetString { string, param },
+            Reply::StringValue {
+                string: s,
+                param: p,
+                ..
+            },
+        ) => s == string && p == param,
+        (Command::GetNote { note }, Reply::NoteValue { note: n, .. }) => n == note,
+        (Command::GetGlobal { param }, Reply::GlobalValue { param: p, .. }) => p == param,
+        _ => false,
+    }
+}
+
+fn is_invalid_string(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<DeviceError>(),
+        Some(DeviceError {
+            code: ErrorCode::InvalidString,
+            ..
+        })
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::SimulatedDevice;
+    use magnet_zither_protocol::{SampleResult, StringState};
+
+    /// A device that never replies
+    struct Silent;
+
+    impl Transport for Silent {
+        fn send(&mut self, _message: &[u8]) -> Result<()> {
+            Ok(())
+        }
+
+        fn receive(&mut self, _timeout: Duration) -> Result<Option<Vec<u8>>> {
+            Ok(None)
+        }
+    }
+
+    /// A device with replies to earlier commands still waiting to be received
+    struct Late {
+        device: SimulatedDevice,
+        stale: Vec<Vec<u8>>,
+    }
+
+    impl Transport for Late {
+        fn send(&mut self, message: &[u8]) -> Result<()> {
+            self.device.send(message)
+        }
+
+        fn receive(&mut self, timeout: Duration) -> Result<Option<Vec<u8>>> {
+            if !self.stale.is_empty() {
+                return Ok(Some(self.stale.remove(0)));
+            }
+            self.device.receive(timeout)
+        }
+    }
+
+    #[test]
+    fn late_replies_skipped() {
+        let stale = [
+            Reply::StringValue {
+                string: 1,
+                param: StringParam::SustainAmplitude,
+                value: 7,
+            },
+            Reply::StringValue {
+                string: 2,
+                param: StringParam::AttackAmplitude,
+                value: 7,
+            },
+            Reply::GlobalValue {
+                param: GlobalParam::PowerBudget,
+                value: 7,
+            },
+            Reply::Ack { command: 0x7f },
+        ];
+        let mut client = Client::new(Late {
+            device: SimulatedDevice::new(),
+            stale: stale.iter().map(|reply| reply.encode().to_vec()).collect(),
+        });
+        assert_eq!(
+            client.get_string(2, StringParam::SustainAmplitude).unwrap(),
+            100
+        );
+    }
+
+    #[test]
+    fn get_and_set() {
+        let mut client = Client::new(SimulatedDevice::new());
+        client.check_version().unwrap();
+
+        assert_eq!(
+            client.get_string(2, StringParam::SustainAmplitude).unwrap(),
+            100
+        );
+        client
+            .set_string(2, StringParam::SustainAmplitude, 180)
+            .unwrap();
+        assert_eq!(
+            client.get_string(2, StringParam::SustainAmplitude).unwrap(),
+            180
+        );
+        // Other strings are unaffected
+        assert_eq!(
+            client.get_string(3, StringParam::SustainAmplitude).unwrap(),
+            100
+        );
+    }
+
+    #[test]
+    fn device_error() {
+        let mut client = Client::new(SimulatedDevice::new());
+        let e = client
+            .set_string(0, StringParam::AttackAmplitude, 256)
+            .unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<DeviceError>().unwrap().code,
+            ErrorCode::InvalidValue
+        );
+
+        let e = client.calibrate(0).unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<DeviceError>().unwrap().code,
+            ErrorCode::Unsupported
+        );
+    }
+
+    #[test]
+    fn drive_period_limit() {
+        let mut client = Client::new(SimulatedDevice::new());
+        let invalid = |e: anyhow::Error| e.downcast_ref::<DeviceError>().unwrap().code;
+        assert_eq!(
+            invalid(client.set_string(0, StringParam::Period, 0).unwrap_err()),
+            ErrorCode::InvalidValue
+        );
+        client.set_string(0, StringParam::Period, 800_000).unwrap();
+
+        // A harmonic can't be mapped if its period would be too short to drive
+        assert_eq!(
+            invalid(client.set_note(100, Some((0, 11))).unwrap_err()),
+            ErrorCode::InvalidValue
+        );
+        client.set_note(100, Some((0, 10))).unwrap();
+
+        // Nor can the period be shortened below what a mapped harmonic needs
+        assert_eq!(
+            invalid(
+                client
+                    .set_string(0, StringParam::Period, 799_999)
+                    .unwrap_err()
+            ),
+            ErrorCode::InvalidValue
+        );
+    }
+
+    #[test]
+    fn list_strings() {
+        let mut client = Client::new(SimulatedDevice::new());
+        let strings = client.list_strings().unwrap();
+        assert_eq!(strings.len(), 8);
+        for params in strings {
+            assert_eq!(params.len(), StringParam::ALL.len());
+        }
+    }
+
+    #[test]
+    fn notes() {
+        let mut client = Client::new(SimulatedDevice::new());
+        assert_eq!(client.get_note(67).unwrap(), Some((0, 1)));
+        client.set_note(67, None).unwrap();
+        client.set_note(60, Some((0, 1))).unwrap();
+
+        let notes = client.list_notes().unwrap();
+        assert!(notes.contains(&(60, (0, 1))));
+        assert!(!notes.iter().any(|&(note, _)| note == 67));
+    }
+
+    #[test]
+    fn presets() {
+        let mut client = Client::new(SimulatedDevice::new());
+        client
+            .set_string(4, StringParam::SustainAmplitude, 60)
+            .unwrap();
+        client.save_preset(2, "Quiet").unwrap();
+        client
+            .set_string(4, StringParam::SustainAmplitude, 200)
+            .unwrap();
+        client
+            .set_string(4, StringParam::Period, 1_500_000)
+            .unwrap();
+        assert_eq!(
+            client.list_presets().unwrap(),
+            vec![(2, "Quiet".to_string())]
+        );
+
+        client.load_preset(2).unwrap();
+        assert_eq!(
+            client.get_string(4, StringParam::SustainAmplitude).unwrap(),
+            60
+        );
+        // The tuning isn't part of the preset
+        assert_eq!(
+            client.get_string(4, StringParam::Period).unwrap(),
+            1_500_000
+        );
+
+        client.delete_preset(2).unwrap();
+        assert!(client.list_presets().unwrap().is_empty());
+        let e = client.load_preset(2).unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<DeviceError>().unwrap().code,
+            ErrorCode::InvalidPreset
+        );
+        assert!(client
+            .save_preset(0, "A name that is far too long")
+            .is_err());
+    }
+
+    #[test]
+    fn sequence() {
+        let mut client = Client::new(SimulatedDevice::new());
+        let e = client.play_sequence().unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<DeviceError>().unwrap().code,
+            ErrorCode::InvalidSequence
+        );
+
+        let mut file = b"MThd\0\0\0\x06\0\0\0\x01\x01\xe0MTrk\0\0\0\x04\0\xff\x2f\0".to_vec();
+        // Padded with an unknown chunk, which players skip, to take several messages
+        file.extend_from_slice(b"XPad\0\0\x01\xc2");
+        file.resize(file.len() + 0x1c2, 0xff);
+        client.upload_sequence(&file).unwrap();
+        assert_eq!(client.transport().sequence(), Some(&file[..]));
+        client.play_sequence().unwrap();
+        assert!(client.transport().playing());
+        // Flash can't be written while playing
+        let e = client.save_preset(0, "Playing").unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<DeviceError>().unwrap().code,
+            ErrorCode::Busy
+        );
+        client.stop_sequence().unwrap();
+        assert!(!client.transport().playing());
+
+        assert!(client.upload_sequence(b"RIFF").is_err());
+        // The previous sequence is kept
+        assert_eq!(client.transport().sequence(), Some(&file[..]));
+        client.upload_sequence(&[]).unwrap();
+        assert_eq!(client.transport().sequence(), None);
+    }
+
+    #[test]
+    fn panic_report() {
+        let mut client = Client::new(
+            SimulatedDevice::new()
+                .with_panic_report("src/main.rs: explicit panic", 12, 5)
+                .with_panic_report("unknown location", 0, 0),
+        );
+        client.version().unwrap();
+        assert_eq!(
+            client.take_panic_reports(),
+            vec![
+                "src/main.rs: explicit panic (line 12, column 5)".to_string(),
+                "unknown location".to_string(),
+            ]
+        );
+        assert!(client.take_panic_reports().is_empty());
+    }
+
+    #[test]
+    fn reports_between_replies() {
+        let mut client = Client::new(SimulatedDevice::new());
+        client.set_global(GlobalParam::ReportInterval, 100).unwrap();
+        client.calibrate(1).unwrap();
+        assert_eq!(client.list_globals().unwrap().len(), GlobalParam::ALL.len());
+
+        let mut reports = Vec::new();
+        while let Some(report) = client.next_report(Duration::ZERO).unwrap() {
+            reports.push(report);
+        }
+        assert!(reports.contains(&Report::Sample {
+            string: 1,
+            result: SampleResult::Accepted,
+            sample: 2_000_000,
+            period: 2_000_000,
+        }));
+        assert_eq!(
+            reports.last(),
+            Some(&Report::State {
+                string: 1,
+                state: StringState::Off,
+            })
+        );
+    }
+
+    #[test]
+    fn timeout() {
+        let mut client = Client::new(Silent);
+        client.timeout = Duration::ZERO;
+        assert!(client.version().is_err());
+    }
+}
@@ -0,0 +1,456 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use clap::{Parser, Subcommand};
+use magnet_zither_protocol::{GlobalParam, Report, StringParam};
+
+use backup::Backup;
+use client::Client;
+use simulator::SimulatedDevice;
+use transport::Transport;
+
+mod backup;
+mod client;
+mod simulator;
+mod transport;
+
+/// How long to wait for each report while monitoring, before checking again
+const REPORT_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Configure and tune the magnet zither over MIDI
+#[derive(Parser)]
+struct Args {
+    /// Name, or part of the name, of the MIDI port the zither is connected to
+    #[arg(short, long, default_value = "Magnet Zither")]
+    port: String,
+    /// Talk to a simulated zither instead of real hardware
+    #[arg(long)]
+    simulate: bool,
+    #[command(subcommand)]
+    command: Cmd,
+}
+
+#[derive(Subcommand)]
+enum Cmd {
+    /// List MIDI ports
+    Ports,
+    /// Print the protocol version of the firmware
+    Version,
+    /// Print a string parameter
+    Get { string: u8, param: String },
+    /// Set a string parameter
+    Set {
+        string: u8,
+        param: String,
+        value: u32,
+    },
+    /// Print all parameters of a string
+    Show { string: u8 },
+    /// Print the string and harmonic each note is mapped to
+    Notes,
+    /// Print the string and harmonic a note is mapped to
+    Note { note: u8 },
+    /// Map a note to a harmonic of a string
+    Map { note: u8, string: u8, harmonic: u8 },
+    /// Stop a note from playing anything
+    Unmap { note: u8 },
+    /// Print all global settings
+    Globals,
+    /// Print a global setting
+    GetGlobal { param: String },
+    /// Set a global setting
+    SetGlobal { param: String, value: u32 },
+    /// Pluck a string and measure its frequency
+    Calibrate { string: u8 },
+    /// Print fault counters, queue overflows and coil temperatures
+    Diagnostics,
+    /// Release outputs that were shut down by a fault
+    ClearFaults,
+    /// Print state changes, frequency measurements and errors as they happen
+    Monitor {
+        /// Minimum time between reports of each string, in milliseconds
+        #[arg(long, default_value_t = 100)]
+        interval: u32,
+        /// Stop after this many reports
+        #[arg(long)]
+        count: Option<usize>,
+    },
+    /// Print the number and name of each stored preset
+    Presets,
+    /// Store the current settings on the device as a preset, which program change `preset`
+    /// switches to
+    SavePreset { preset: u8, name: String },
+    /// Switch to a stored preset
+    LoadPreset { preset: u8 },
+    /// Delete a stored preset
+    DeletePreset { preset: u8 },
+    /// Store a Standard MIDI File on the device to play without a host, or delete the stored one
+    /// if no file is given
+    UploadSequence { file: Option<PathBuf> },
+    /// Start playing the stored sequence
+    Play,
+    /// Stop playing the stored sequence
+    Stop,
+    /// Save all settings to a file
+    Backup { file: PathBuf },
+    /// Load all settings from a file created by backup
+    Restore { file: PathBuf },
+}
+
+fn string_param(name: &str) -> Result<StringParam> {
+    StringParam::from_name(name).ok_or_else(|| {
+        let names: Vec<_> = StringParam::ALL.iter().map(|p| p.name()).collect();
+        anyhow!(
+            "Unknown string parameter \"{}\", expected one of: {}",
+            name,
+            names.join(", ")
+        )
+    })
+}
+
+fn global_param(name: &str) -> Result<GlobalParam> {
+    GlobalParam::from_name(name).ok_or_else(|| {
+        let names: Vec<_> = GlobalParam::ALL.iter().map(|p| p.name()).collect();
+        anyhow!(
+            "Unknown global parameter \"{}\", expected one of: {}",
+            name,
+            names.join(", ")
+        )
+    })
+}
+
+fn print_report(out: &mut impl Write, report: Report) -> Result<()> {
+    match report {
+        Report::State { string, state } => writeln!(out, "state {} {}", string, state.name())?,
+        Report::Sample {
+            string,
+            result,
+            sample,
+            period,
+        } => writeln!(
+            out,
+            "sample {} {} {} {}",
+            string,
+            result.name(),
+            sample,
+            period
+        )?,
+        Report::Diagnostic {
+            param,
+            index,
+            value,
+        } => writeln!(out, "{} {} {}", param.name(), index, value)?,
+    }
+    Ok(())
+}
+
+fn run<T: Transport>(command: Cmd, client: &mut Client<T>, out: &mut impl Write) -> Result<()> {
+    client.check_version()?;
+
+    match command {
+        Cmd::Ports => unreachable!("Handled without connecting"),
+        Cmd::Version => writeln!(out, "{}", client.version()?)?,
+        Cmd::Get { string, param } => {
+            writeln!(out, "{}", client.get_string(string, string_param(&param)?)?)?
+        }
+        Cmd::Set {
+            string,
+            param,
+            value,
+        } => client.set_string(string, string_param(&param)?, value)?,
+        Cmd::Show { string } => {
+            for (param, value) in client.list_string(string)? {
+                writeln!(out, "{} {}", param.name(), value)?;
+            }
+        }
+        Cmd::Notes => {
+            for (note, (string, harmonic)) in client.list_notes()? {
+                writeln!(out, "{} {} {}", note, string, harmonic)?;
+            }
+        }
+        Cmd::Note { note } => match client.get_note(note)? {
+            Some((string, harmonic)) => writeln!(out, "{} {}", string, harmonic)?,
+            None => writeln!(out, "unmapped")?,
+        },
+        Cmd::Map {
+            note,
+            string,
+            harmonic,
+        } => client.set_note(note, Some((string, harmonic)))?,
+        Cmd::Unmap { note } => client.set_note(note, None)?,
+        Cmd::Globals => {
+            for (param, value) in client.list_globals()? {
+                writeln!(out, "{} {}", param.name(), value)?;
+            }
+        }
+        Cmd::GetGlobal { param } => writeln!(out, "{}", client.get_global(global_param(&param)?)?)?,
+        Cmd::SetGlobal { param, value } => client.set_global(global_param(&param)?, value)?,
+        Cmd::Calibrate { string } => client.calibrate(string)?,
+        Cmd::Diagnostics => {
+            for (param, index, value) in client.list_diagnostics()? {
+                writeln!(out, "{} {} {}", param.name(), index, value)?;
+            }
+        }
+        Cmd::ClearFaults => client.clear_faults()?,
+        Cmd::Monitor { interval, count } => {
+            let previous = client.get_global(GlobalParam::ReportInterval)?;
+            client.set_global(GlobalParam::ReportInterval, interval)?;
+            let mut received = 0;
+            while count.is_none_or(|count| received < count) {
+                if let Some(report) = client.next_report(REPORT_TIMEOUT)? {
+                    print_report(out, report)?;
+                    out.flush()?;
+                    received += 1;
+                }
+            }
+            client.set_global(GlobalParam::ReportInterval, previous)?;
+        }
+        Cmd::Presets => {
+            for (preset, name) in client.list_presets()? {
+                writeln!(out, "{} {}", preset, name)?;
+            }
+        }
+        Cmd::SavePreset { preset, name } => client.save_preset(preset, &name)?,
+        Cmd::LoadPreset { preset } => client.load_preset(preset)?,
+        Cmd::DeletePreset { preset } => client.delete_preset(preset)?,
+        Cmd::UploadSequence { file } => {
+            let data = match file {
+                Some(file) => std::fs::read(&file)
+                    .with_context(|| format!("Failed to read {}", file.display()))?,
+                None => Vec::new(),
+            };
+            client.upload_sequence(&data)?;
+        }
+        Cmd::Play => client.play_sequence()?,
+        Cmd::Stop => client.stop_sequence()?,
+        Cmd::Backup { file } => {
+            let backup = Backup::read(client)?;
+            std::fs::write(&file, backup.to_string())
+                .with_context(|| format!("Failed to write {}", file.display()))?;
+        }
+        Cmd::Restore { file } => {
+            let backup: Backup = std::fs::read_to_string(&file)
+                .with_context(|| format!("Failed to read {}", file.display()))?
+                .parse()
+                .with_context(|| format!("Failed to parse {}", file.display()))?;
+            backup.restore(client)?;
+        }
+    }
+    Ok(())
+}
+
+/// Run a command, then report any panics the device sent along the way
+fn run_and_report<T: Transport>(command: Cmd, transport: T) -> Result<()> {
+    let mut client = Client::new(transport);
+    let result = run(command, &mut client, &mut std::io::stdout());
+    for report in client.take_panic_reports() {
+        eprintln!("Device panicked before the last reset: {}", report);
+    }
+    result
+}
+
+#[cfg(feature = "midir")]
+fn run_hardware(args: Args) -> Result<()> {
+    if let Cmd::Ports = args.command {
+        for port in transport::ports()? {
+            println!("{}", port);
+        }
+        return Ok(());
+    }
+    run_and_report(args.command, transport::MidiTransport::connect(&args.port)?)
+}
+
+#[cfg(not(feature = "midir"))]
+fn run_hardware(_args: Args) -> Result<()> {
+    Err(anyhow!(
+        "Built without MIDI support, only --simulate is available"
+    ))
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    if args.simulate {
+        if let Cmd::Ports = args.command {
+            println!("Simulated zither");
+            return Ok(());
+        }
+        run_and_report(args.command, SimulatedDevice::new())
+    } else {
+        run_hardware(args)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_args(client: &mut Client<SimulatedDevice>, args: &[&str]) -> Result<String> {
+        let args = Args::try_parse_from(["zither", "--simulate"].iter().chain(args.iter()))?;
+        let mut out = Vec::new();
+        run(args.command, client, &mut out)?;
+        Ok(String::from_utf8(out)?)
+    }
+
+    #[test]
+    fn set_then_get() {
+        let mut client = Client::new(SimulatedDevice::new());
+        run_args(&mut client, &["set", "3", "attack-time", "250"]).unwrap();
+        assert_eq!(
+            run_args(&mut client, &["get", "3", "attack-time"]).unwrap(),
+            "250\n"
+        );
+    }
+
+    #[test]
+    fn show() {
+        let mut client = Client::new(SimulatedDevice::new());
+        let out = run_args(&mut client, &["show", "0"]).unwrap();
+        assert_eq!(out.lines().count(), StringParam::ALL.len());
+        assert!(out.contains("sustain-amplitude 100\n"));
+    }
+
+    #[test]
+    fn unknown_param() {
+        let mut client = Client::new(SimulatedDevice::new());
+        let e = run_args(&mut client, &["get", "0", "bogus"]).unwrap_err();
+        assert!(e.to_string().contains("attack-time"));
+    }
+
+    #[test]
+    fn map_and_unmap() {
+        let mut client = Client::new(SimulatedDevice::new());
+        run_args(&mut client, &["map", "60", "2", "1"]).unwrap();
+        run_args(&mut client, &["unmap", "71"]).unwrap();
+        assert_eq!(run_args(&mut client, &["note", "60"]).unwrap(), "2 1\n");
+        assert_eq!(
+            run_args(&mut client, &["note", "71"]).unwrap(),
+            "unmapped\n"
+        );
+        let out = run_args(&mut client, &["notes"]).unwrap();
+        assert!(out.contains("60 2 1\n"));
+        assert!(!out.contains("71 2 1\n"));
+    }
+
+    #[test]
+    fn globals() {
+        let mut client = Client::new(SimulatedDevice::new());
+        run_args(&mut client, &["set-global", "channel-mode", "17"]).unwrap();
+        assert_eq!(
+            run_args(&mut client, &["get-global", "channel-mode"]).unwrap(),
+            "17\n"
+        );
+        assert!(run_args(&mut client, &["set-global", "channel-mode", "3"]).is_err());
+        let out = run_args(&mut client, &["globals"]).unwrap();
+        assert!(out.contains("channel-mode 17\n"));
+        run_args(&mut client, &["set-global", "arp-mode", "6"]).unwrap();
+        assert!(run_args(&mut client, &["set-global", "arp-mode", "8"]).is_err());
+        assert!(run_args(&mut client, &["set-global", "arp-interval", "0"]).is_err());
+    }
+
+    #[test]
+    fn calibrate() {
+        let mut client = Client::new(SimulatedDevice::new());
+        run_args(&mut client, &["calibrate", "1"]).unwrap();
+        assert!(run_args(&mut client, &["calibrate", "2"]).is_err());
+        assert!(run_args(&mut client, &["calibrate", "8"]).is_err());
+        assert_eq!(client.transport().calibrations(), 1);
+    }
+
+    #[test]
+    fn diagnostics() {
+        let mut client = Client::new(SimulatedDevice::new());
+        let out = run_args(&mut client, &["diagnostics"]).unwrap();
+        assert!(out.contains("fault-count 2 0\n"));
+        assert!(out.contains("temperature 7 0\n"));
+        assert!(out.contains("thermal-limiting 7 0\n"));
+    }
+
+    #[test]
+    fn clear_faults() {
+        let mut client = Client::new(SimulatedDevice::new().with_fault(1));
+        let out = run_args(&mut client, &["diagnostics"]).unwrap();
+        assert!(out.contains("fault-count 1 1\n"));
+        assert!(out.contains("last-fault 1 2\n"));
+        assert!(out.contains("last-fault 2 0\n"));
+        run_args(&mut client, &["clear-faults"]).unwrap();
+        let out = run_args(&mut client, &["diagnostics"]).unwrap();
+        // The count is kept, but the outputs are running again
+        assert!(out.contains("fault-count 1 1\n"));
+        assert!(out.contains("last-fault 1 0\n"));
+    }
+
+    #[test]
+    fn monitor() {
+        let mut client = Client::new(SimulatedDevice::new());
+        run_args(&mut client, &["set-global", "report-interval", "50"]).unwrap();
+        run_args(&mut client, &["calibrate", "1"]).unwrap();
+        let out = run_args(&mut client, &["monitor", "--count", "6"]).unwrap();
+        assert!(out.starts_with("state 1 attack\n"));
+        assert!(out.contains("sample 1 accepted 2000000 2000000\n"));
+        assert!(out.ends_with("state 1 off\n"));
+        // The previous interval is restored
+        assert_eq!(
+            run_args(&mut client, &["get-global", "report-interval"]).unwrap(),
+            "50\n"
+        );
+    }
+
+    #[test]
+    fn presets() {
+        let mut client = Client::new(SimulatedDevice::new());
+        run_args(&mut client, &["save-preset", "1", "Bridge"]).unwrap();
+        run_args(&mut client, &["save-preset", "0", "Intro"]).unwrap();
+        assert_eq!(
+            run_args(&mut client, &["presets"]).unwrap(),
+            "0 Intro\n1 Bridge\n"
+        );
+        run_args(&mut client, &["delete-preset", "0"]).unwrap();
+        run_args(&mut client, &["load-preset", "1"]).unwrap();
+        assert!(run_args(&mut client, &["load-preset", "0"]).is_err());
+    }
+
+    #[test]
+    fn sequence() {
+        let file = std::env::temp_dir().join(format!("zither-sequence-{}.mid", std::process::id()));
+        std::fs::write(
+            &file,
+            b"MThd\0\0\0\x06\0\0\0\x01\x01\xe0MTrk\0\0\0\x04\0\xff\x2f\0",
+        )
+        .unwrap();
+
+        let mut client = Client::new(SimulatedDevice::new());
+        assert!(run_args(&mut client, &["play"]).is_err());
+        run_args(&mut client, &["upload-sequence", file.to_str().unwrap()]).unwrap();
+        std::fs::remove_file(&file).unwrap();
+        run_args(&mut client, &["play"]).unwrap();
+        run_args(&mut client, &["stop"]).unwrap();
+        run_args(&mut client, &["upload-sequence"]).unwrap();
+        assert!(run_args(&mut client, &["play"]).is_err());
+    }
+
+    #[test]
+    fn backup_and_restore() {
+        let file = std::env::temp_dir().join(format!("zither-backup-{}.txt", std::process::id()));
+        let file_arg = file.to_str().unwrap();
+
+        let mut original = Client::new(SimulatedDevice::new());
+        run_args(&mut original, &["set", "5", "period", "1500000"]).unwrap();
+        run_args(&mut original, &["map", "40", "0", "1"]).unwrap();
+        run_args(&mut original, &["backup", file_arg]).unwrap();
+
+        let mut restored = Client::new(SimulatedDevice::new());
+        run_args(&mut restored, &["restore", file_arg]).unwrap();
+        std::fs::remove_file(&file).unwrap();
+
+        assert_eq!(
+            run_args(&mut restored, &["get", "5", "period"]).unwrap(),
+            "1500000\n"
+        );
+        assert!(run_args(&mut restored, &["notes"])
+            .unwrap()
+            .contains("40 0 1\n"));
+    }
+}
@@ -0,0 +1,510 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use anyhow::Result;
+use magnet_zither_protocol::{
+    arp_mode, fault, smf, thru, thru_filter, Command, DecodeError, DiagnosticParam, ErrorCode,
+    GlobalParam, PresetName, Reply, Report, SampleResult, StringParam, StringState,
+    PROTOCOL_VERSION,
+};
+
+use crate::transport::Transport;
+
+const NUM_STRINGS: u8 = 8;
+
+/// Number of presets that fit in the flash reserved for them
+const NUM_PRESETS: u8 = 16;
+
+/// Longest sequence that fits in the flash reserved for it
+const MAX_SEQUENCE_LEN: u32 = 32 * 1024 - 64;
+
+/// Only this string has a frequency meter
+const CALIBRATED_STRING: u8 = 1;
+
+/// Longest duration accepted by the firmware, in milliseconds
+const MAX_DURATION_MS: u32 = 1_000_000;
+
+/// Shortest report interval accepted by the firmware, in milliseconds
+const MIN_REPORT_INTERVAL_MS: u32 = 10;
+
+/// Shortest arpeggio interval accepted by the firmware, in milliseconds
+const MIN_ARP_INTERVAL_MS: u32 = 10;
+
+/// Shortest period the firmware drives a string at, in nanoseconds
+const MIN_DRIVE_PERIOD_NS: u32 = 80_000;
+
+/// Defaults from `string::Config`
+fn default_string_param(param: StringParam) -> u32 {
+    match param {
+        StringParam::Period => 2_000_000,
+        StringParam::AttackTime => 100,
+        StringParam::AttackAmplitude => 255,
+        StringParam::SustainAmplitude => 100,
+        StringParam::ReleaseTime => 0,
+        StringParam::ReleaseAmplitude => 0,
+        StringParam::StabilizeTime => 50,
+        StringParam::SampleTime => 500,
+        StringParam::MaxNoteTime => 60_000,
+        StringParam::ThermalFastTimeConstant => 10_000,
+        StringParam::ThermalSlowTimeConstant => 300_000,
+        StringParam::ThermalFastRise => 20_000,
+        StringParam::ThermalSlowRise => 60_000,
+        StringParam::ThermalDerateStart => 40_000,
+        StringParam::ThermalDerateEnd => 60_000,
+        StringParam::PressureDepth => 0,
+        StringParam::PressureSmoothing => 50,
+        StringParam::BowingController => 0,
+    }
+}
+
+fn check_drive_period(period: u32, harmonic: u8) -> Result<(), ErrorCode> {
+    match period.checked_div(harmonic as u32) {
+        Some(period) if period >= MIN_DRIVE_PERIOD_NS => Ok(()),
+        _ => Err(ErrorCode::InvalidValue),
+    }
+}
+
+fn check_string_param(param: StringParam, value: u32) -> Result<(), ErrorCode> {
+    let valid = match param {
+        StringParam::Period => value >= MIN_DRIVE_PERIOD_NS,
+        StringParam::AttackAmplitude
+        | StringParam::SustainAmplitude
+        | StringParam::ReleaseAmplitude
+        | StringParam::PressureDepth => value <= u8::MAX as u32,
+        StringParam::AttackTime
+        | StringParam::ReleaseTime
+        | StringParam::StabilizeTime
+        | StringParam::SampleTime
+        | StringParam::MaxNoteTime
+        | StringParam::ThermalFastTimeConstant
+        | StringParam::ThermalSlowTimeConstant
+        | StringParam::PressureSmoothing => value <= MAX_DURATION_MS,
+        StringParam::ThermalFastRise
+        | StringParam::ThermalSlowRise
+        | StringParam::ThermalDerateStart
+        | StringParam::ThermalDerateEnd => true,
+        StringParam::BowingController => matches!(value, 0 | 2 | 11),
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(ErrorCode::InvalidValue)
+    }
+}
+
+fn check_global_param(param: GlobalParam, value: u32) -> Result<(), ErrorCode> {
+    let valid = match param {
+        GlobalParam::ChannelMode => matches!(value, 0 | 0x10..=0x1f | 0x20..=0x2f),
+        GlobalParam::PowerBudget => value <= u16::MAX as u32,
+        GlobalParam::ReportInterval => {
+            value == 0 || (MIN_REPORT_INTERVAL_MS..=MAX_DURATION_MS).contains(&value)
+        }
+        GlobalParam::Thru => value <= thru::ALL as u32,
+        GlobalParam::ThruFilter => value <= thru_filter::ALL as u32,
+        GlobalParam::SequenceLoop => value <= 1,
+        GlobalParam::ArpMode => value <= arp_mode::STRUM_DOWN as u32,
+        GlobalParam::ArpInterval => (MIN_ARP_INTERVAL_MS..=MAX_DURATION_MS).contains(&value),
+        GlobalParam::ArpClock => value <= u8::MAX as u32,
+        GlobalParam::StrumSpacing => value <= MAX_DURATION_MS,
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(ErrorCode::InvalidValue)
+    }
+}
+
+/// Settings stored in a preset. The period of each string isn't included, since it belongs to
+/// the physical string.
+#[derive(Clone)]
+struct Preset {
+    name: PresetName,
+    strings: Vec<Vec<u32>>,
+    notes: [Option<(u8, u8)>; 128],
+    channel_mode: u32,
+}
+
+/// Behaves like the firmware's SysEx handling, so the CLI can be used and tested without
+/// hardware
+pub struct SimulatedDevice {
+    strings: Vec<Vec<u32>>,
+    notes: [Option<(u8, u8)>; 128],
+    globals: Vec<u32>,
+    calibrations: u32,
+    presets: Vec<Option<Preset>>,
+    sequence: Option<Vec<u8>>,
+    /// Sequence being uploaded and its expected length
+    upload: Option<(Vec<u8>, usize)>,
+    playing: bool,
+    fault_counts: [u32; 3],
+    /// Last fault of each TCC since faults were cleared
+    last_faults: [u8; 3],
+    /// Replies waiting to be received
+    replies: VecDeque<Vec<u8>>,
+}
+
+impl SimulatedDevice {
+    pub fn new() -> Self {
+        let mut notes = [None; 128];
+        // G major scale from G4 to G5, with the second and third harmonics of all but the lowest
+        // string covering the next two octaves
+        let fundamentals = [67, 69, 71, 72, 74, 76, 77, 79];
+        for (string, &note) in fundamentals.iter().enumerate() {
+            notes[note as usize] = Some((string as u8, 1));
+        }
+        for harmonic in 2..=3 {
+            for (string, &note) in fundamentals.iter().enumerate().skip(1) {
+                notes[(note + 12 * (harmonic - 1)) as usize] = Some((string as u8, harmonic));
+            }
+        }
+
+        Self {
+            strings: (0..NUM_STRINGS)
+                .map(|_| {
+                    StringParam::ALL
+                        .iter()
+                        .map(|&p| default_string_param(p))
+                        .collect()
+                })
+                .collect(),
+            notes,
+            globals: vec![0, 4 * 255, 0, 0, 0, 0, 0, 125, 0, 20],
+            calibrations: 0,
+            presets: vec![None; NUM_PRESETS as usize],
+            sequence: None,
+            upload: None,
+            playing: false,
+            fault_counts: [0; 3],
+            last_faults: [fault::NONE; 3],
+            replies: VecDeque::new(),
+        }
+    }
+
+    /// Queue a panic report, like the firmware sends after a panic
+    #[cfg(test)]
+    pub fn with_panic_report(mut self, text: &str, line: u32, column: u32) -> Self {
+        let report = magnet_zither_protocol::PanicReport {
+            line,
+            column,
+            text: text.as_bytes(),
+        };
+        self.replies
+            .push_back(magnet_zither_protocol::encode_panic_report(&report).to_vec());
+        self
+    }
+
+    /// Trip the non-recoverable fault of a TCC, as if its fault input had been active
+    #[cfg(test)]
+    pub fn with_fault(mut self, tcc: u8) -> Self {
+        self.fault_counts[tcc as usize] += 1;
+        self.last_faults[tcc as usize] = fault::NON_RECOVERABLE;
+        self
+    }
+
+    /// Number of calibrations that have been started
+    #[cfg(test)]
+    pub fn calibrations(&self) -> u32 {
+        self.calibrations
+    }
+
+    #[cfg(test)]
+    pub fn sequence(&self) -> Option<&[u8]> {
+        self.sequence.as_deref()
+    }
+
+    #[cfg(test)]
+    pub fn playing(&self) -> bool {
+        self.playing
+    }
+
+    fn reply(&mut self, reply: Reply) {
+        self.replies.push_back(reply.encode().to_vec());
+    }
+
+    fn report(&mut self, report: Report) {
+        if self.globals[GlobalParam::ReportInterval as usize] != 0 {
+            self.replies.push_back(report.encode().to_vec());
+        }
+    }
+
+    fn check_string(string: u8) -> Result<(), ErrorCode> {
+        if string < NUM_STRINGS {
+            Ok(())
+        } else {
+            Err(ErrorCode::InvalidString)
+        }
+    }
+
+    fn check_preset(preset: u8) -> Result<(), ErrorCode> {
+        if preset < NUM_PRESETS {
+            Ok(())
+        } else {
+            Err(ErrorCode::InvalidPreset)
+        }
+    }
+
+    /// The firmware refuses to write flash while anything is playing
+    fn check_idle(&self) -> Result<(), ErrorCode> {
+        if self.playing {
+            Err(ErrorCode::Busy)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn execute(&mut self, command: Command) -> Result<(), ErrorCode> {
+        match command {
+            Command::GetVersion => self.reply(Reply::Version(PROTOCOL_VERSION)),
+            Command::GetString { string, param } => {
+                Self::check_string(string)?;
+                let value = self.strings[string as usize][param as usize];
+                self.reply(Reply::StringValue {
+                    string,
+                    param,
+                    value,
+                });
+            }
+            Command::SetString {
+                string,
+                param,
+                value,
+            } => {
+                Self::check_string(string)?;
+                check_string_param(param, value)?;
+                if param == StringParam::Period {
+                    // Every harmonic the string plays has to stay drivable at the new period
+                    let harmonic = self
+                        .notes
+                        .iter()
+                        .flatten()
+                        .filter(|&&(s, _)| s == string)
+                        .map(|&(_, harmonic)| harmonic)
+                        .fold(1, u8::max);
+                    check_drive_period(value, harmonic)?;
+                }
+                self.strings[string as usize][param as usize] = value;
+            }
+            Command::ListString { string } => {
+                Self::check_string(string)?;
+                for &param in StringParam::ALL {
+                    self.execute(Command::GetString { string, param })?;
+                }
+            }
+            Command::GetNote { note } => {
+                let mapping = self.notes[note as usize];
+                self.reply(Reply::NoteValue { note, mapping });
+            }
+            Command::SetNote { note, mapping } => {
+                if let Some((string, harmonic)) = mapping {
+                    Self::check_string(string)?;
+                    let period = self.strings[string as usize][StringParam::Period as usize];
+                    check_drive_period(period, harmonic)?;
+                }
+                self.notes[note as usize] = mapping;
+            }
+            Command::ListNotes => {
+                for note in 0..128 {
+                    if self.notes[note as usize].is_some() {
+                        self.execute(Command::GetNote { note })?;
+                    }
+                }
+            }
+            Command::GetGlobal { param } => {
+                let value = self.globals[param as usize];
+                self.reply(Reply::GlobalValue { param, value });
+            }
+            Command::SetGlobal { param, value } => {
+                check_global_param(param, value)?;
+                self.globals[param as usize] = value;
+            }
+            Command::ListGlobals => {
+                for &param in GlobalParam::ALL {
+                    self.execute(Command::GetGlobal { param })?;
+                }
+            }
+            Command::Calibrate { string } => {
+                Self::check_string(string)?;
+                if string != CALIBRATED_STRING {
+                    return Err(ErrorCode::Unsupported);
+                }
+                self.calibrations += 1;
+
+                // The string rings down and is measured spot on
+                for state in [
+                    StringState::Attack,
+                    StringState::Release,
+                    StringState::Stabilize,
+                    StringState::Sample,
+                ] {
+                    self.report(Report::State { string, state });
+                }
+                let period = self.strings[string as usize][StringParam::Period as usize];
+                self.report(Report::Sample {
+                    string,
+                    result: SampleResult::Accepted,
+                    sample: period,
+                    period,
+                });
+                self.report(Report::State {
+                    string,
+                    state: StringState::Off,
+                });
+            }
+            Command::ListDiagnostics => {
+                for tcc in 0..3 {
+                    self.reply(Reply::DiagnosticValue {
+                        param: DiagnosticParam::FaultCount,
+                        index: tcc as u8,
+                        value: self.fault_counts[tcc],
+                    });
+                    self.reply(Reply::DiagnosticValue {
+                        param: DiagnosticParam::LastFault,
+                        index: tcc as u8,
+                        value: self.last_faults[tcc] as u32,
+                    });
+                }
+                for param in [
+                    DiagnosticParam::DroppedMidi,
+                    DiagnosticParam::DeferredNoteOffs,
+                    DiagnosticParam::DroppedUpdates,
+                    DiagnosticParam::SerialErrors,
+                    DiagnosticParam::DroppedThru,
+                ] {
+                    self.reply(Reply::DiagnosticValue {
+                        param,
+                        index: 0,
+                        value: 0,
+                    });
+                }
+                for string in 0..NUM_STRINGS {
+                    for param in [
+                        DiagnosticParam::Temperature,
+                        DiagnosticParam::ForcedReleases,
+                        DiagnosticParam::TargetPeriod,
+                        DiagnosticParam::ThermalLimiting,
+                    ] {
+                        self.reply(Reply::DiagnosticValue {
+                            param,
+                            index: string,
+                            value: 0,
+                        });
+                    }
+                }
+            }
+            // The simulated fault inputs are never still active
+            Command::ClearFaults => self.last_faults = [fault::NONE; 3],
+            Command::SavePreset { preset, name } => {
+                Self::check_preset(preset)?;
+                self.check_idle()?;
+                self.presets[preset as usize] = Some(Preset {
+                    name,
+                    strings: self.strings.clone(),
+                    notes: self.notes,
+                    channel_mode: self.globals[GlobalParam::ChannelMode as usize],
+                });
+            }
+            Command::LoadPreset { preset } => {
+                Self::check_preset(preset)?;
+                let preset = self.presets[preset as usize]
+                    .clone()
+                    .ok_or(ErrorCode::InvalidPreset)?;
+                for (string, params) in self.strings.iter_mut().zip(preset.strings) {
+                    let period = string[StringParam::Period as usize];
+                    *string = params;
+                    string[StringParam::Period as usize] = period;
+                }
+                self.notes = preset.notes;
+                self.globals[GlobalParam::ChannelMode as usize] = preset.channel_mode;
+            }
+            Command::ListPresets => {
+                let names: Vec<_> = self
+                    .presets
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(preset, stored)| Some((preset as u8, stored.as_ref()?.name)))
+                    .collect();
+                for (preset, name) in names {
+                    self.reply(Reply::PresetValue { preset, name });
+                }
+            }
+            Command::DeletePreset { preset } => {
+                Self::check_preset(preset)?;
+                self.check_idle()?;
+                self.presets[preset as usize] = None;
+            }
+            Command::BeginSequence { len } => {
+                if len > MAX_SEQUENCE_LEN {
+                    return Err(ErrorCode::InvalidValue);
+                }
+                // Starting an upload stops playback
+                self.playing = false;
+                self.upload = Some((Vec::new(), len as usize));
+            }
+            Command::SequenceData { data } => {
+                self.check_idle()?;
+                let (sequence, len) = self.upload.as_mut().ok_or(ErrorCode::InvalidSequence)?;
+                if sequence.len() + data.as_bytes().len() > *len {
+                    self.upload = None;
+                    return Err(ErrorCode::InvalidSequence);
+                }
+                sequence.extend_from_slice(data.as_bytes());
+            }
+            Command::EndSequence => {
+                self.check_idle()?;
+                let (sequence, len) = self.upload.take().ok_or(ErrorCode::InvalidSequence)?;
+                if sequence.len() != len || (len != 0 && smf::Sequence::parse(&sequence).is_err()) {
+                    return Err(ErrorCode::InvalidSequence);
+                }
+                self.sequence = Some(sequence).filter(|s| !s.is_empty());
+            }
+            Command::PlaySequence => {
+                if self.sequence.is_none() {
+                    return Err(ErrorCode::InvalidSequence);
+                }
+                self.playing = true;
+            }
+            Command::StopSequence => self.playing = false,
+        }
+        Ok(())
+    }
+}
+
+impl Default for SimulatedDevice {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Transport for SimulatedDevice {
+    fn send(&mut self, message: &[u8]) -> Result<()> {
+        let command = match Command::decode(message) {
+            Ok(command) => command,
+            Err(DecodeError::NotForUs) => return Ok(()),
+            Err(DecodeError::Invalid(command, code)) => {
+                self.reply(Reply::Error { command, code });
+                return Ok(());
+            }
+        };
+
+        match self.execute(command) {
+            Ok(()) if command.is_acknowledged() => self.reply(Reply::Ack {
+                command: command.id(),
+            }),
+            Ok(()) => {}
+            Err(code) => self.reply(Reply::Error {
+                command: command.id(),
+                code,
+            }),
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self, timeout: Duration) -> Result<Option<Vec<u8>>> {
+        let reply = self.replies.pop_front();
+        if reply.is_none() {
+            std::thread::sleep(timeout);
+        }
+        Ok(reply)
+    }
+}
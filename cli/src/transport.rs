@@ -0,0 +1,110 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+use std::time::Duration;
+
+use anyhow::Result;
+
+/// Carries SysEx messages to and from the device
+pub trait Transport {
+    /// Send a complete SysEx message
+    fn send(&mut self, message: &[u8]) -> Result<()>;
+
+    /// Wait for the next SysEx message from the device, returning `None` if nothing arrives
+    /// before the timeout
+    fn receive(&mut self, timeout: Duration) -> Result<Option<Vec<u8>>>;
+}
+
+#[cfg(feature = "midir")]
+pub use self::midi::{ports, MidiTransport};
+
+#[cfg(feature = "midir")]
+mod midi {
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    use anyhow::{anyhow, Result};
+    use magnet_zither_protocol::SYSEX_START;
+    use midir::{Ignore, MidiIO, MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
+
+    const CLIENT_NAME: &str = "zither";
+
+    /// Names of all MIDI ports that can be both read from and written to
+    pub fn ports() -> Result<Vec<String>> {
+        let input = MidiInput::new(CLIENT_NAME)?;
+        let output = MidiOutput::new(CLIENT_NAME)?;
+        let outputs = output
+            .ports()
+            .iter()
+            .filter_map(|p| output.port_name(p).ok())
+            .collect::<Vec<_>>();
+        Ok(input
+            .ports()
+            .iter()
+            .filter_map(|p| input.port_name(p).ok())
+            .filter(|name| outputs.contains(name))
+            .collect())
+    }
+
+    fn find_port<T: MidiIO>(io: &T, name: &str) -> Result<T::Port> {
+        io.ports()
+            .into_iter()
+            .find(|p| io.port_name(p).is_ok_and(|n| n.contains(name)))
+            .ok_or_else(|| anyhow!("No MIDI port matching \"{}\"", name))
+    }
+
+    /// Talks to the device through the system MIDI API
+    pub struct MidiTransport {
+        _input: MidiInputConnection<()>,
+        output: MidiOutputConnection,
+        messages: mpsc::Receiver<Vec<u8>>,
+    }
+
+    impl MidiTransport {
+        /// Connect to the first input and output ports whose names contain `port_name`
+        pub fn connect(port_name: &str) -> Result<Self> {
+            let mut input = MidiInput::new(CLIENT_NAME)?;
+            // SysEx is ignored by default
+            input.ignore(Ignore::None);
+            let output = MidiOutput::new(CLIENT_NAME)?;
+
+            let input_port = find_port(&input, port_name)?;
+            let output_port = find_port(&output, port_name)?;
+
+            let (sender, messages) = mpsc::channel();
+            let input = input
+                .connect(
+                    &input_port,
+                    CLIENT_NAME,
+                    move |_, message, _| {
+                        if message.first() == Some(&SYSEX_START) {
+                            sender.send(message.to_vec()).ok();
+                        }
+                    },
+                    (),
+                )
+                .map_err(|e| anyhow!("Failed to open MIDI input: {}", e))?;
+            let output = output
+                .connect(&output_port, CLIENT_NAME)
+                .map_err(|e| anyhow!("Failed to open MIDI output: {}", e))?;
+
+            Ok(Self {
+                _input: input,
+                output,
+                messages,
+            })
+        }
+    }
+
+    impl super::Transport for MidiTransport {
+        fn send(&mut self, message: &[u8]) -> Result<()> {
+            Ok(self.output.send(message)?)
+        }
+
+        fn receive(&mut self, timeout: Duration) -> Result<Option<Vec<u8>>> {
+            match self.messages.recv_timeout(timeout) {
+                Ok(message) => Ok(Some(message)),
+                Err(mpsc::RecvTimeoutError::Timeout) => Ok(None),
+                Err(mpsc::RecvTimeoutError::Disconnected) => Err(anyhow!("MIDI input closed")),
+            }
+        }
+    }
+}
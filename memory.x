@@ -1,6 +1,8 @@
 MEMORY
 {
-  FLASH (rx) : ORIGIN = 0x00000000, LENGTH = 256K
+  /* The last 48K of flash are reserved for the uploaded sequence and presets, see
+     src/midi/sequence.rs and src/midi/preset.rs */
+  FLASH (rx) : ORIGIN = 0x00000000, LENGTH = 208K
   RAM (xrw)  : ORIGIN = 0x20000000, LENGTH = 32K
 }
 _stack_start = ORIGIN(RAM) + LENGTH(RAM);
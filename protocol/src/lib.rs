@@ -0,0 +1,1397 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// Configuration protocol shared by the firmware and host tools
+//
+// Every message is framed as `F0 7D <command> <data...> F7`. Values are unsigned 32-bit integers
+// sent as 5 data bytes of 7 bits each, least significant first.
+//
+// Commands sent to the device:
+//
+//   00                      Get the protocol version
+//   10 <string> <param>     Get a string parameter
+//   11 <string> <param> <v> Set a string parameter
+//   12 <string>             List all parameters of a string
+//   20 <note>               Get the mapping of a MIDI note
+//   21 <note> <string> <h>  Map a note to harmonic `h` of a string, or unmap it if string is 7F
+//   22                      List all mapped notes
+//   30 <param>              Get a global setting
+//   31 <param> <v>          Set a global setting
+//   32                      List all global settings
+//   40 <string>             Pluck a string and measure its frequency as it rings down
+//   41                      List all diagnostic values
+//   60 <preset> <name...>   Store the current settings as a preset
+//   61 <preset>             Switch to a preset, like a program change
+//   62                      List all stored presets
+//   63 <preset>             Delete a preset
+//   70 <length>             Start uploading a sequence of `length` bytes, replacing the stored one
+//   71 <data...>            Next part of the sequence being uploaded
+//   72                      Finish uploading the sequence and store it
+//   73                      Start playing the stored sequence from the beginning
+//   74                      Stop playing the sequence
+//
+// Replies sent by the device:
+//
+//   01 <text...>            Panic report, sent once after reset if the firmware panicked
+//   02 <command>            Set command succeeded, or list is complete
+//   03 <command> <error>    Command failed
+//   04 <version>            Protocol version
+//   13 <string> <param> <v> Value of a string parameter
+//   23 <note> <string> <h>  Mapping of a note, where string is 7F if the note is unmapped
+//   33 <param> <v>          Value of a global setting
+//   43 <param> <index> <v>  Value of a diagnostic counter or measurement
+//   64 <preset> <name...>   Name of a stored preset
+//
+// Reports sent by the device while the `report-interval` global setting is non-zero:
+//
+//   50 <string> <state>                String changed state
+//   51 <string> <result> <v> <period>  Frequency measurement and the resulting tuning
+//   52 <param> <index> <v>             Diagnostic counter that indicates errors changed
+//
+// Sequences are Standard MIDI Files. Their bytes are sent 7 at a time, in groups of 8 data bytes
+// where the first holds the top bit of each of the others. Uploading an empty sequence deletes the
+// stored one.
+//
+// Get and list commands are answered with value replies, and list commands are followed by an
+// acknowledgement once every value has been sent. Calibration is acknowledged once it has
+// started. Reports can arrive at any time, including between a command and its replies.
+// Replies and reports are also sent out of the DIN MIDI port if the `thru` global setting
+// includes `STATUS_TO_DIN`.
+//
+// The protocol version is incremented whenever a change would be misinterpreted by the other
+// side, so hosts should check it before sending anything else. Adding commands, parameters or
+// error codes doesn't change the version, since those are rejected with an error by older
+// firmware.
+
+#![no_std]
+
+use heapless::Vec;
+
+pub mod pwm_plan;
+pub mod smf;
+
+pub const SYSEX_START: u8 = 0xf0;
+pub const SYSEX_END: u8 = 0xf7;
+
+/// Manufacturer ID reserved for non-commercial use, which we use for all of our SysEx messages
+pub const SYSEX_MANUFACTURER_ID: u8 = 0x7d;
+
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// Longest preset name
+pub const MAX_PRESET_NAME_LEN: usize = 16;
+
+/// Most bytes of a sequence sent in one message
+pub const MAX_SEQUENCE_CHUNK_LEN: usize = 56;
+
+/// Longest message in either direction, including the framing bytes, except for panic reports.
+/// This is a sequence data command with a full chunk.
+pub const MAX_MESSAGE_LEN: usize = packed_len(MAX_SEQUENCE_CHUNK_LEN) + 4;
+
+/// Longest panic report text
+pub const MAX_PANIC_TEXT_LEN: usize = 128;
+
+/// Longest panic report, including the framing bytes and the line and column
+pub const MAX_PANIC_REPORT_LEN: usize = MAX_PANIC_TEXT_LEN + 14;
+
+/// String number used in note mappings to mark a note that doesn't play anything
+const UNMAPPED: u8 = 0x7f;
+
+mod command {
+    pub const GET_VERSION: u8 = 0x00;
+    pub const GET_STRING: u8 = 0x10;
+    pub const SET_STRING: u8 = 0x11;
+    pub const LIST_STRING: u8 = 0x12;
+    pub const GET_NOTE: u8 = 0x20;
+    pub const SET_NOTE: u8 = 0x21;
+    pub const LIST_NOTES: u8 = 0x22;
+    pub const GET_GLOBAL: u8 = 0x30;
+    pub const SET_GLOBAL: u8 = 0x31;
+    pub const LIST_GLOBALS: u8 = 0x32;
+    pub const CALIBRATE: u8 = 0x40;
+    pub const LIST_DIAGNOSTICS: u8 = 0x41;
+    pub const CLEAR_FAULTS: u8 = 0x42;
+    pub const SAVE_PRESET: u8 = 0x60;
+    pub const LOAD_PRESET: u8 = 0x61;
+    pub const LIST_PRESETS: u8 = 0x62;
+    pub const DELETE_PRESET: u8 = 0x63;
+    pub const BEGIN_SEQUENCE: u8 = 0x70;
+    pub const SEQUENCE_DATA: u8 = 0x71;
+    pub const END_SEQUENCE: u8 = 0x72;
+    pub const PLAY_SEQUENCE: u8 = 0x73;
+    pub const STOP_SEQUENCE: u8 = 0x74;
+}
+
+mod reply {
+    pub const PANIC_REPORT: u8 = 0x01;
+    pub const ACK: u8 = 0x02;
+    pub const ERROR: u8 = 0x03;
+    pub const VERSION: u8 = 0x04;
+    pub const STRING_VALUE: u8 = 0x13;
+    pub const NOTE_VALUE: u8 = 0x23;
+    pub const GLOBAL_VALUE: u8 = 0x33;
+    pub const DIAGNOSTIC_VALUE: u8 = 0x43;
+    pub const PRESET_VALUE: u8 = 0x64;
+}
+
+mod report {
+    pub const STATE: u8 = 0x50;
+    pub const SAMPLE: u8 = 0x51;
+    pub const DIAGNOSTIC: u8 = 0x52;
+}
+
+/// Bits of the `thru` global setting
+pub mod thru {
+    /// Messages from the DIN input are sent to the host
+    pub const DIN_TO_USB: u8 = 1 << 0;
+    /// Messages from the host are sent out of the DIN output
+    pub const USB_TO_DIN: u8 = 1 << 1;
+    /// Replies and reports are sent out of the DIN output as well as to the host
+    pub const STATUS_TO_DIN: u8 = 1 << 2;
+    pub const ALL: u8 = DIN_TO_USB | USB_TO_DIN | STATUS_TO_DIN;
+}
+
+/// Bits of the `thru-filter` global setting
+pub mod thru_filter {
+    pub const CHANNEL: u8 = 1 << 0;
+    pub const SYSTEM_COMMON: u8 = 1 << 1;
+    pub const REALTIME: u8 = 1 << 2;
+    pub const SYSEX: u8 = 1 << 3;
+    pub const ALL: u8 = CHANNEL | SYSTEM_COMMON | REALTIME | SYSEX;
+}
+
+/// Values of the `arp-mode` global setting
+pub mod arp_mode {
+    /// Notes are played as they arrive
+    pub const OFF: u8 = 0;
+    /// Held notes are played one at a time, from the lowest to the highest
+    pub const UP: u8 = 1;
+    pub const DOWN: u8 = 2;
+    /// Up and then back down, without repeating the highest and lowest notes
+    pub const UP_DOWN: u8 = 3;
+    pub const RANDOM: u8 = 4;
+    /// Held notes are played one at a time, in the order they were played
+    pub const AS_PLAYED: u8 = 5;
+    /// The notes of each chord are spread out from the lowest to the highest
+    pub const STRUM_UP: u8 = 6;
+    pub const STRUM_DOWN: u8 = 7;
+}
+
+/// Values of the `last-fault` diagnostic
+pub mod fault {
+    pub const NONE: u8 = 0;
+    /// Outputs were halted while the fault input was active, and restarted by themselves
+    pub const RECOVERABLE: u8 = 1;
+    /// Outputs are held low until the fault is cleared
+    pub const NON_RECOVERABLE: u8 = 2;
+}
+
+macro_rules! params {
+    (
+        $(#[$meta:meta])* $name:ident {
+            $($(#[$vmeta:meta])* $variant:ident = $id:literal => $str:literal,)+
+        }
+    ) => {
+        $(#[$meta])*
+        #[derive(Clone, Copy, Debug, PartialEq)]
+        pub enum $name {
+            $($(#[$vmeta])* $variant = $id,)+
+        }
+
+        impl $name {
+            pub const ALL: &'static [Self] = &[$(Self::$variant,)+];
+
+            pub fn from_id(id: u8) -> Option<Self> {
+                match id {
+                    $($id => Some(Self::$variant),)+
+                    _ => None,
+                }
+            }
+
+            /// Name used by host tools
+            pub fn name(&self) -> &'static str {
+                match self {
+                    $(Self::$variant => $str,)+
+                }
+            }
+
+            pub fn from_name(name: &str) -> Option<Self> {
+                match name {
+                    $($str => Some(Self::$variant),)+
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+params! {
+    /// Fields of `string::Config`. Durations are in milliseconds and temperatures in millidegrees
+    /// Celsius.
+    StringParam {
+        /// Period of the fundamental, in nanoseconds
+        Period = 0 => "period",
+        AttackTime = 1 => "attack-time",
+        AttackAmplitude = 2 => "attack-amplitude",
+        SustainAmplitude = 3 => "sustain-amplitude",
+        ReleaseTime = 4 => "release-time",
+        ReleaseAmplitude = 5 => "release-amplitude",
+        StabilizeTime = 6 => "stabilize-time",
+        SampleTime = 7 => "sample-time",
+        /// Zero disables the limit
+        MaxNoteTime = 8 => "max-note-time",
+        ThermalFastTimeConstant = 9 => "thermal-fast-time-constant",
+        ThermalSlowTimeConstant = 10 => "thermal-slow-time-constant",
+        ThermalFastRise = 11 => "thermal-fast-rise",
+        ThermalSlowRise = 12 => "thermal-slow-rise",
+        ThermalDerateStart = 13 => "thermal-derate-start",
+        ThermalDerateEnd = 14 => "thermal-derate-end",
+        /// How much channel pressure and polyphonic aftertouch scale the sustain amplitude, from
+        /// 0 (ignored) to 255 (silent without pressure)
+        PressureDepth = 15 => "pressure-depth",
+        /// Time constant of the smoothing applied to pressure changes
+        PressureSmoothing = 16 => "pressure-smoothing",
+        /// Control change that shapes held notes instead of the envelope: 2 (breath), 11
+        /// (expression) or 0 to use the envelope
+        BowingController = 17 => "bowing-controller",
+    }
+}
+
+params! {
+    GlobalParam {
+        /// 0 for omni, 0x10 + channel for a single channel, or 0x20 + base channel for one
+        /// channel per string
+        ChannelMode = 0 => "channel-mode",
+        /// Maximum total amplitude of all strings
+        PowerBudget = 1 => "power-budget",
+        /// Minimum time between reports of each string, in milliseconds, or zero to disable
+        /// reports
+        ReportInterval = 2 => "report-interval",
+        /// Streams relayed between the MIDI ports, as a combination of the bits in [`thru`]
+        Thru = 3 => "thru",
+        /// Kinds of messages that aren't relayed, as a combination of the bits in
+        /// [`thru_filter`]
+        ThruFilter = 4 => "thru-filter",
+        /// 1 to start the sequence over when it ends, or 0 to play it once
+        SequenceLoop = 5 => "sequence-loop",
+        /// How held notes are played, as one of the values in [`arp_mode`]
+        ArpMode = 6 => "arp-mode",
+        /// Time between arpeggiated notes, in milliseconds, unless `arp-clock` is set
+        ArpInterval = 7 => "arp-interval",
+        /// MIDI clock pulses between arpeggiated notes, at 24 per quarter note, or zero to time
+        /// them with `arp-interval`
+        ArpClock = 8 => "arp-clock",
+        /// Time between the notes of a strum, in milliseconds
+        StrumSpacing = 9 => "strum-spacing",
+    }
+}
+
+params! {
+    /// Read-only values for troubleshooting. The index selects the TCC or string for values that
+    /// have one per TCC or string, and is zero otherwise.
+    DiagnosticParam {
+        /// Faults reported by each TCC
+        FaultCount = 0 => "fault-count",
+        /// MIDI messages dropped because the queue was full
+        DroppedMidi = 1 => "dropped-midi",
+        /// Note offs applied outside the queue because it was full
+        DeferredNoteOffs = 2 => "deferred-note-offs",
+        /// Envelope updates and arpeggio steps dropped because the queue was full
+        DroppedUpdates = 3 => "dropped-updates",
+        /// Estimated coil temperature rise of each string, in millidegrees Celsius
+        Temperature = 4 => "temperature",
+        /// Notes released on each string because they were held too long
+        ForcedReleases = 5 => "forced-releases",
+        /// Bytes from the DIN MIDI input that were corrupted or lost
+        SerialErrors = 6 => "serial-errors",
+        /// Messages that couldn't be relayed because an output was full
+        DroppedThru = 7 => "dropped-thru",
+        /// Period each string needs to be tuned to for the notes mapped to it, in nanoseconds,
+        /// or zero if no notes are mapped to it
+        TargetPeriod = 8 => "target-period",
+        /// Kind of the last fault reported by each TCC since faults were cleared, one of the
+        /// `fault` values
+        LastFault = 9 => "last-fault",
+        /// Whether the amplitude of each string is being reduced to keep its coil from
+        /// overheating
+        ThermalLimiting = 10 => "thermal-limiting",
+    }
+}
+
+params! {
+    /// What a string is doing, as sent in reports
+    StringState {
+        Off = 0 => "off",
+        Attack = 1 => "attack",
+        Sustain = 2 => "sustain",
+        Release = 3 => "release",
+        /// Waiting for the string to ring steadily before measuring its frequency
+        Stabilize = 4 => "stabilize",
+        /// Measuring the frequency
+        Sample = 5 => "sample",
+    }
+}
+
+params! {
+    /// Outcome of a frequency measurement
+    SampleResult {
+        /// The measurement was used to update the tuning
+        Accepted = 0 => "accepted",
+        /// The measurement was too far from the current tuning and was ignored
+        Outlier = 1 => "outlier",
+        /// The frequency meter overflowed, so there is no measurement
+        Error = 2 => "error",
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorCode {
+    UnknownCommand = 0x01,
+    /// The message is too short or too long for the command
+    Malformed = 0x02,
+    InvalidString = 0x03,
+    InvalidParam = 0x04,
+    InvalidValue = 0x05,
+    /// The string doesn't support this command
+    Unsupported = 0x06,
+    /// The string is playing a note, the command writes to flash while something is playing, or
+    /// a fault can't be cleared because its input is still active
+    Busy = 0x07,
+    /// The preset number is out of range, or no preset is stored there
+    InvalidPreset = 0x08,
+    /// Writing to flash failed
+    StorageFailed = 0x09,
+    /// The sequence isn't a Standard MIDI File that can be played, or there is no sequence
+    InvalidSequence = 0x0a,
+}
+
+impl ErrorCode {
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0x01 => Some(Self::UnknownCommand),
+            0x02 => Some(Self::Malformed),
+            0x03 => Some(Self::InvalidString),
+            0x04 => Some(Self::InvalidParam),
+            0x05 => Some(Self::InvalidValue),
+            0x06 => Some(Self::Unsupported),
+            0x07 => Some(Self::Busy),
+            0x08 => Some(Self::InvalidPreset),
+            0x09 => Some(Self::StorageFailed),
+            0x0a => Some(Self::InvalidSequence),
+            _ => None,
+        }
+    }
+}
+
+/// Name of a preset, made up of up to `MAX_PRESET_NAME_LEN` 7-bit characters
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PresetName {
+    len: u8,
+    bytes: [u8; MAX_PRESET_NAME_LEN],
+}
+
+impl PresetName {
+    /// Returns `None` if the name is too long or contains bytes that aren't 7-bit
+    pub fn new(name: &[u8]) -> Option<Self> {
+        if name.len() > MAX_PRESET_NAME_LEN || name.iter().any(|&c| c >= 0x80) {
+            return None;
+        }
+        let mut bytes = [0; MAX_PRESET_NAME_LEN];
+        bytes[..name.len()].copy_from_slice(name);
+        Some(Self {
+            len: name.len() as u8,
+            bytes,
+        })
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+}
+
+/// Part of a sequence being uploaded, up to `MAX_SEQUENCE_CHUNK_LEN` bytes
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SequenceChunk {
+    len: u8,
+    bytes: [u8; MAX_SEQUENCE_CHUNK_LEN],
+}
+
+impl SequenceChunk {
+    /// Returns `None` if the chunk is too long
+    pub fn new(data: &[u8]) -> Option<Self> {
+        if data.len() > MAX_SEQUENCE_CHUNK_LEN {
+            return None;
+        }
+        let mut bytes = [0; MAX_SEQUENCE_CHUNK_LEN];
+        bytes[..data.len()].copy_from_slice(data);
+        Some(Self {
+            len: data.len() as u8,
+            bytes,
+        })
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Command {
+    GetVersion,
+    GetString {
+        string: u8,
+        param: StringParam,
+    },
+    SetString {
+        string: u8,
+        param: StringParam,
+        value: u32,
+    },
+    ListString {
+        string: u8,
+    },
+    GetNote {
+        note: u8,
+    },
+    /// Mapping is a string and harmonic, or `None` to unmap the note
+    SetNote {
+        note: u8,
+        mapping: Option<(u8, u8)>,
+    },
+    ListNotes,
+    GetGlobal {
+        param: GlobalParam,
+    },
+    SetGlobal {
+        param: GlobalParam,
+        value: u32,
+    },
+    ListGlobals,
+    Calibrate {
+        string: u8,
+    },
+    ListDiagnostics,
+    /// Release outputs held low by non-recoverable faults
+    ClearFaults,
+    SavePreset {
+        preset: u8,
+        name: PresetName,
+    },
+    LoadPreset {
+        preset: u8,
+    },
+    ListPresets,
+    DeletePreset {
+        preset: u8,
+    },
+    BeginSequence {
+        len: u32,
+    },
+    SequenceData {
+        data: SequenceChunk,
+    },
+    EndSequence,
+    PlaySequence,
+    StopSequence,
+}
+
+/// Why a message couldn't be decoded as a command or reply
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DecodeError {
+    /// Not one of our SysEx messages, which should be ignored
+    NotForUs,
+    /// One of our messages, but invalid. The command or reply byte is included so the error can
+    /// be reported back.
+    Invalid(u8, ErrorCode),
+}
+
+/// Split one of our messages into its command or reply byte and arguments
+fn unframe(message: &[u8]) -> Result<(u8, &[u8]), DecodeError> {
+    match message {
+        [SYSEX_START, SYSEX_MANUFACTURER_ID, id, args @ .., SYSEX_END] => Ok((*id, args)),
+        _ => Err(DecodeError::NotForUs),
+    }
+}
+
+fn frame(id: u8, args: &[&[u8]]) -> Vec<u8, MAX_MESSAGE_LEN> {
+    let mut message = Vec::new();
+    message
+        .extend_from_slice(&[SYSEX_START, SYSEX_MANUFACTURER_ID, id])
+        .unwrap();
+    for arg in args {
+        message.extend_from_slice(arg).unwrap();
+    }
+    message.push(SYSEX_END).unwrap();
+    message
+}
+
+impl Command {
+    /// Command byte, used to identify the command in acknowledgements and errors
+    pub fn id(&self) -> u8 {
+        match self {
+            Self::GetVersion => command::GET_VERSION,
+            Self::GetString { .. } => command::GET_STRING,
+            Self::SetString { .. } => command::SET_STRING,
+            Self::ListString { .. } => command::LIST_STRING,
+            Self::GetNote { .. } => command::GET_NOTE,
+            Self::SetNote { .. } => command::SET_NOTE,
+            Self::ListNotes => command::LIST_NOTES,
+            Self::GetGlobal { .. } => command::GET_GLOBAL,
+            Self::SetGlobal { .. } => command::SET_GLOBAL,
+            Self::ListGlobals => command::LIST_GLOBALS,
+            Self::Calibrate { .. } => command::CALIBRATE,
+            Self::ListDiagnostics => command::LIST_DIAGNOSTICS,
+            Self::ClearFaults => command::CLEAR_FAULTS,
+            Self::SavePreset { .. } => command::SAVE_PRESET,
+            Self::LoadPreset { .. } => command::LOAD_PRESET,
+            Self::ListPresets => command::LIST_PRESETS,
+            Self::DeletePreset { .. } => command::DELETE_PRESET,
+            Self::BeginSequence { .. } => command::BEGIN_SEQUENCE,
+            Self::SequenceData { .. } => command::SEQUENCE_DATA,
+            Self::EndSequence => command::END_SEQUENCE,
+            Self::PlaySequence => command::PLAY_SEQUENCE,
+            Self::StopSequence => command::STOP_SEQUENCE,
+        }
+    }
+
+    /// Whether the command is acknowledged when it succeeds. Get commands are answered by their
+    /// value instead.
+    pub fn is_acknowledged(&self) -> bool {
+        !matches!(
+            self,
+            Self::GetVersion
+                | Self::GetString { .. }
+                | Self::GetNote { .. }
+                | Self::GetGlobal { .. }
+        )
+    }
+
+    /// Encode as a complete SysEx message. String, note and harmonic numbers must fit in 7 bits.
+    pub fn encode(&self) -> Vec<u8, MAX_MESSAGE_LEN> {
+        let id = self.id();
+        match *self {
+            Self::GetVersion
+            | Self::ListNotes
+            | Self::ListGlobals
+            | Self::ListDiagnostics
+            | Self::ClearFaults
+            | Self::ListPresets
+            | Self::EndSequence
+            | Self::PlaySequence
+            | Self::StopSequence => frame(id, &[]),
+            Self::GetString { string, param } => frame(id, &[&[string, param as u8]]),
+            Self::SetString {
+                string,
+                param,
+                value,
+            } => frame(id, &[&[string, param as u8], &encode_value(value)]),
+            Self::ListString { string } => frame(id, &[&[string]]),
+            Self::GetNote { note } => frame(id, &[&[note]]),
+            Self::SetNote { note, mapping } => {
+                let (string, harmonic) = mapping.unwrap_or((UNMAPPED, 0));
+                frame(id, &[&[note, string, harmonic]])
+            }
+            Self::GetGlobal { param } => frame(id, &[&[param as u8]]),
+            Self::SetGlobal { param, value } => frame(id, &[&[param as u8], &encode_value(value)]),
+            Self::Calibrate { string } => frame(id, &[&[string]]),
+            Self::SavePreset { preset, name } => frame(id, &[&[preset], name.as_bytes()]),
+            Self::LoadPreset { preset } | Self::DeletePreset { preset } => frame(id, &[&[preset]]),
+            Self::BeginSequence { len } => frame(id, &[&encode_value(len)]),
+            Self::SequenceData { data } => frame(id, &[&pack(data.as_bytes())]),
+        }
+    }
+
+    /// Decode a complete SysEx message, including the start and end bytes
+    pub fn decode(message: &[u8]) -> Result<Self, DecodeError> {
+        let (id, args) = unframe(message)?;
+        let error = |code| DecodeError::Invalid(id, code);
+
+        let string_param =
+            |param| StringParam::from_id(param).ok_or(error(ErrorCode::InvalidParam));
+        let global_param =
+            |param| GlobalParam::from_id(param).ok_or(error(ErrorCode::InvalidParam));
+        let value = |bytes: &[u8]| decode_value(bytes).ok_or(error(ErrorCode::InvalidValue));
+
+        Ok(match (id, args) {
+            (command::GET_VERSION, []) => Self::GetVersion,
+            (command::GET_STRING, &[string, param]) => Self::GetString {
+                string,
+                param: string_param(param)?,
+            },
+            (command::SET_STRING, &[string, param, ref v @ ..]) if v.len() == 5 => {
+                Self::SetString {
+                    string,
+                    param: string_param(param)?,
+                    value: value(v)?,
+                }
+            }
+            (command::LIST_STRING, &[string]) => Self::ListString { string },
+            (command::GET_NOTE, &[note]) => Self::GetNote { note },
+            (command::SET_NOTE, &[note, UNMAPPED, _]) => Self::SetNote {
+                note,
+                mapping: None,
+            },
+            (command::SET_NOTE, &[note, string, harmonic]) => Self::SetNote {
+                note,
+                mapping: Some((string, harmonic)),
+            },
+            (command::LIST_NOTES, []) => Self::ListNotes,
+            (command::GET_GLOBAL, &[param]) => Self::GetGlobal {
+                param: global_param(param)?,
+            },
+            (command::SET_GLOBAL, &[param, ref v @ ..]) if v.len() == 5 => Self::SetGlobal {
+                param: global_param(param)?,
+                value: value(v)?,
+            },
+            (command::LIST_GLOBALS, []) => Self::ListGlobals,
+            (command::CALIBRATE, &[string]) => Self::Calibrate { string },
+            (command::LIST_DIAGNOSTICS, []) => Self::ListDiagnostics,
+            (command::CLEAR_FAULTS, []) => Self::ClearFaults,
+            (command::SAVE_PRESET, &[preset, ref name @ ..]) => Self::SavePreset {
+                preset,
+                name: PresetName::new(name).ok_or(error(ErrorCode::Malformed))?,
+            },
+            (command::LOAD_PRESET, &[preset]) => Self::LoadPreset { preset },
+            (command::LIST_PRESETS, []) => Self::ListPresets,
+            (command::DELETE_PRESET, &[preset]) => Self::DeletePreset { preset },
+            (command::BEGIN_SEQUENCE, v) if v.len() == 5 => Self::BeginSequence { len: value(v)? },
+            (command::SEQUENCE_DATA, packed) => Self::SequenceData {
+                data: unpack(packed)
+                    .as_deref()
+                    .and_then(SequenceChunk::new)
+                    .ok_or(error(ErrorCode::Malformed))?,
+            },
+            (command::END_SEQUENCE, []) => Self::EndSequence,
+            (command::PLAY_SEQUENCE, []) => Self::PlaySequence,
+            (command::STOP_SEQUENCE, []) => Self::StopSequence,
+            (
+                command::GET_VERSION
+                | command::GET_STRING
+                | command::SET_STRING
+                | command::LIST_STRING
+                | command::GET_NOTE
+                | command::SET_NOTE
+                | command::LIST_NOTES
+                | command::GET_GLOBAL
+                | command::SET_GLOBAL
+                | command::LIST_GLOBALS
+                | command::CALIBRATE
+                | command::LIST_DIAGNOSTICS
+                | command::CLEAR_FAULTS
+                | command::SAVE_PRESET
+                | command::LOAD_PRESET
+                | command::LIST_PRESETS
+                | command::DELETE_PRESET
+                | command::BEGIN_SEQUENCE
+                | command::END_SEQUENCE
+                | command::PLAY_SEQUENCE
+                | command::STOP_SEQUENCE,
+                _,
+            ) => return Err(error(ErrorCode::Malformed)),
+            _ => return Err(error(ErrorCode::UnknownCommand)),
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Reply {
+    Ack {
+        command: u8,
+    },
+    Error {
+        command: u8,
+        code: ErrorCode,
+    },
+    Version(u8),
+    StringValue {
+        string: u8,
+        param: StringParam,
+        value: u32,
+    },
+    NoteValue {
+        note: u8,
+        mapping: Option<(u8, u8)>,
+    },
+    GlobalValue {
+        param: GlobalParam,
+        value: u32,
+    },
+    DiagnosticValue {
+        param: DiagnosticParam,
+        index: u8,
+        value: u32,
+    },
+    PresetValue {
+        preset: u8,
+        name: PresetName,
+    },
+}
+
+impl Reply {
+    /// Encode as a complete SysEx message
+    pub fn encode(&self) -> Vec<u8, MAX_MESSAGE_LEN> {
+        match *self {
+            Self::Ack { command } => frame(reply::ACK, &[&[command]]),
+            Self::Error { command, code } => frame(reply::ERROR, &[&[command, code as u8]]),
+            Self::Version(version) => frame(reply::VERSION, &[&[version]]),
+            Self::StringValue {
+                string,
+                param,
+                value,
+            } => frame(
+                reply::STRING_VALUE,
+                &[&[string, param as u8], &encode_value(value)],
+            ),
+            Self::NoteValue { note, mapping } => {
+                let (string, harmonic) = mapping.unwrap_or((UNMAPPED, 0));
+                frame(reply::NOTE_VALUE, &[&[note, string, harmonic]])
+            }
+            Self::GlobalValue { param, value } => {
+                frame(reply::GLOBAL_VALUE, &[&[param as u8], &encode_value(value)])
+            }
+            Self::DiagnosticValue {
+                param,
+                index,
+                value,
+            } => frame(
+                reply::DIAGNOSTIC_VALUE,
+                &[&[param as u8, index], &encode_value(value)],
+            ),
+            Self::PresetValue { preset, name } => {
+                frame(reply::PRESET_VALUE, &[&[preset], name.as_bytes()])
+            }
+        }
+    }
+
+    /// Decode a complete SysEx message, including the start and end bytes. Panic reports are
+    /// decoded by `decode_panic_report()` instead.
+    pub fn decode(message: &[u8]) -> Result<Self, DecodeError> {
+        let (id, args) = unframe(message)?;
+        let error = |code| DecodeError::Invalid(id, code);
+
+        let string_param =
+            |param| StringParam::from_id(param).ok_or(error(ErrorCode::InvalidParam));
+        let global_param =
+            |param| GlobalParam::from_id(param).ok_or(error(ErrorCode::InvalidParam));
+        let value = |bytes: &[u8]| decode_value(bytes).ok_or(error(ErrorCode::InvalidValue));
+
+        Ok(match (id, args) {
+            (reply::ACK, &[command]) => Self::Ack { command },
+            (reply::ERROR, &[command, code]) => Self::Error {
+                command,
+                code: ErrorCode::from_id(code).ok_or(error(ErrorCode::InvalidValue))?,
+            },
+            (reply::VERSION, &[version]) => Self::Version(version),
+            (reply::STRING_VALUE, &[string, param, ref v @ ..]) if v.len() == 5 => {
+                Self::StringValue {
+                    string,
+                    param: string_param(param)?,
+                    value: value(v)?,
+                }
+            }
+            (reply::NOTE_VALUE, &[note, UNMAPPED, _]) => Self::NoteValue {
+                note,
+                mapping: None,
+            },
+            (reply::NOTE_VALUE, &[note, string, harmonic]) => Self::NoteValue {
+                note,
+                mapping: Some((string, harmonic)),
+            },
+            (reply::GLOBAL_VALUE, &[param, ref v @ ..]) if v.len() == 5 => Self::GlobalValue {
+                param: global_param(param)?,
+                value: value(v)?,
+            },
+            (reply::DIAGNOSTIC_VALUE, &[param, index, ref v @ ..]) if v.len() == 5 => {
+                Self::DiagnosticValue {
+                    param: DiagnosticParam::from_id(param).ok_or(error(ErrorCode::InvalidParam))?,
+                    index,
+                    value: value(v)?,
+                }
+            }
+            (reply::PRESET_VALUE, &[preset, ref name @ ..]) => Self::PresetValue {
+                preset,
+                name: PresetName::new(name).ok_or(error(ErrorCode::Malformed))?,
+            },
+            (
+                reply::ACK
+                | reply::ERROR
+                | reply::VERSION
+                | reply::STRING_VALUE
+                | reply::NOTE_VALUE
+                | reply::GLOBAL_VALUE
+                | reply::DIAGNOSTIC_VALUE
+                | reply::PRESET_VALUE,
+                _,
+            ) => return Err(error(ErrorCode::Malformed)),
+            _ => return Err(error(ErrorCode::UnknownCommand)),
+        })
+    }
+}
+
+/// Status sent by the device without being asked. Reports of each string are rate limited, so
+/// states that only last briefly may not be reported.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Report {
+    State {
+        string: u8,
+        state: StringState,
+    },
+    /// The measured period and the period the string is tuned to afterwards, in nanoseconds. The
+    /// sample is zero if there was an error.
+    Sample {
+        string: u8,
+        result: SampleResult,
+        sample: u32,
+        period: u32,
+    },
+    Diagnostic {
+        param: DiagnosticParam,
+        index: u8,
+        value: u32,
+    },
+}
+
+impl Report {
+    /// Encode as a complete SysEx message
+    pub fn encode(&self) -> Vec<u8, MAX_MESSAGE_LEN> {
+        match *self {
+            Self::State { string, state } => frame(report::STATE, &[&[string, state as u8]]),
+            Self::Sample {
+                string,
+                result,
+                sample,
+                period,
+            } => frame(
+                report::SAMPLE,
+                &[
+                    &[string, result as u8],
+                    &encode_value(sample),
+                    &encode_value(period),
+                ],
+            ),
+            Self::Diagnostic {
+                param,
+                index,
+                value,
+            } => frame(
+                report::DIAGNOSTIC,
+                &[&[param as u8, index], &encode_value(value)],
+            ),
+        }
+    }
+
+    /// Decode a complete SysEx message, including the start and end bytes. Replies decode as
+    /// `UnknownCommand` errors.
+    pub fn decode(message: &[u8]) -> Result<Self, DecodeError> {
+        let (id, args) = unframe(message)?;
+        let error = |code| DecodeError::Invalid(id, code);
+        let value = |bytes: &[u8]| decode_value(bytes).ok_or(error(ErrorCode::InvalidValue));
+
+        Ok(match (id, args) {
+            (report::STATE, &[string, state]) => Self::State {
+                string,
+                state: StringState::from_id(state).ok_or(error(ErrorCode::InvalidValue))?,
+            },
+            (report::SAMPLE, &[string, result, ref v @ ..]) if v.len() == 10 => Self::Sample {
+                string,
+                result: SampleResult::from_id(result).ok_or(error(ErrorCode::InvalidValue))?,
+                sample: value(&v[..5])?,
+                period: value(&v[5..])?,
+            },
+            (report::DIAGNOSTIC, &[param, index, ref v @ ..]) if v.len() == 5 => Self::Diagnostic {
+                param: DiagnosticParam::from_id(param).ok_or(error(ErrorCode::InvalidParam))?,
+                index,
+                value: value(v)?,
+            },
+            (report::STATE | report::SAMPLE | report::DIAGNOSTIC, _) => {
+                return Err(error(ErrorCode::Malformed))
+            }
+            _ => return Err(error(ErrorCode::UnknownCommand)),
+        })
+    }
+}
+
+/// Report of a panic that happened before the device was last reset
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PanicReport<'a> {
+    /// Line and column the panic happened at, or zero if they aren't known
+    pub line: u32,
+    pub column: u32,
+    /// Panic message, starting with the name of the file the panic happened in
+    pub text: &'a [u8],
+}
+
+/// Encode a panic report, truncating the text if it is too long. SysEx data bytes must be 7-bit,
+/// which ASCII already is.
+pub fn encode_panic_report(report: &PanicReport) -> Vec<u8, MAX_PANIC_REPORT_LEN> {
+    let text = &report.text[..report.text.len().min(MAX_PANIC_TEXT_LEN)];
+    let mut message = Vec::new();
+    message
+        .extend_from_slice(&[SYSEX_START, SYSEX_MANUFACTURER_ID, reply::PANIC_REPORT])
+        .unwrap();
+    message
+        .extend_from_slice(&encode_value(report.line))
+        .unwrap();
+    message
+        .extend_from_slice(&encode_value(report.column))
+        .unwrap();
+    for &c in text {
+        message.push(c & 0x7f).unwrap();
+    }
+    message.push(SYSEX_END).unwrap();
+    message
+}
+
+/// Decode a panic report, or return `None` if the message isn't one
+pub fn decode_panic_report(message: &[u8]) -> Option<PanicReport<'_>> {
+    match unframe(message) {
+        Ok((reply::PANIC_REPORT, args)) if args.len() >= 10 => Some(PanicReport {
+            line: decode_value(&args[..5])?,
+            column: decode_value(&args[5..10])?,
+            text: &args[10..],
+        }),
+        _ => None,
+    }
+}
+
+fn encode_value(value: u32) -> [u8; 5] {
+    let mut bytes = [0; 5];
+    for (i, b) in bytes.iter_mut().enumerate() {
+        *b = (value >> (7 * i)) as u8 & 0x7f;
+    }
+    bytes
+}
+
+fn decode_value(bytes: &[u8]) -> Option<u32> {
+    // The last byte only has room for the top 4 bits
+    if bytes.len() != 5 || bytes[4] > 0xf {
+        return None;
+    }
+    Some(
+        bytes
+            .iter()
+            .enumerate()
+            .fold(0, |value, (i, &b)| value | (b as u32) << (7 * i)),
+    )
+}
+
+/// Number of data bytes needed to send `len` 8-bit bytes
+const fn packed_len(len: usize) -> usize {
+    len + len.div_ceil(7)
+}
+
+/// Split 8-bit bytes into groups of 7, each sent as a byte holding their top bits followed by
+/// their lower 7 bits
+fn pack(bytes: &[u8]) -> Vec<u8, { packed_len(MAX_SEQUENCE_CHUNK_LEN) }> {
+    let mut packed = Vec::new();
+    for group in bytes.chunks(7) {
+        let top_bits = group
+            .iter()
+            .enumerate()
+            .fold(0, |bits, (i, &b)| bits | (b >> 7) << i);
+        packed.push(top_bits).unwrap();
+        for &b in group {
+            packed.push(b & 0x7f).unwrap();
+        }
+    }
+    packed
+}
+
+fn unpack(packed: &[u8]) -> Option<Vec<u8, MAX_SEQUENCE_CHUNK_LEN>> {
+    let mut bytes = Vec::new();
+    for group in packed.chunks(8) {
+        let (&top_bits, group) = group.split_first()?;
+        if group.is_empty() {
+            return None;
+        }
+        for (i, &b) in group.iter().enumerate() {
+            bytes.push(b | (top_bits >> i & 1) << 7).ok()?;
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn commands() -> impl Iterator<Item = Command> {
+        let fixed = [
+            Command::GetVersion,
+            Command::ListString { string: 7 },
+            Command::GetNote { note: 127 },
+            Command::SetNote {
+                note: 67,
+                mapping: Some((0, 1)),
+            },
+            Command::SetNote {
+                note: 0,
+                mapping: None,
+            },
+            Command::ListNotes,
+            Command::ListGlobals,
+            Command::Calibrate { string: 1 },
+            Command::ListDiagnostics,
+            Command::ClearFaults,
+            Command::SavePreset {
+                preset: 15,
+                name: PresetName::new(b"Verse 2").unwrap(),
+            },
+            Command::SavePreset {
+                preset: 0,
+                name: PresetName::new(&[b'x'; MAX_PRESET_NAME_LEN]).unwrap(),
+            },
+            Command::SavePreset {
+                preset: 1,
+                name: PresetName::new(b"").unwrap(),
+            },
+            Command::LoadPreset { preset: 3 },
+            Command::ListPresets,
+            Command::DeletePreset { preset: 127 },
+            Command::BeginSequence { len: 32 * 1024 },
+            Command::SequenceData {
+                data: SequenceChunk::new(b"MThd\0\0\0\x06\0\x01\0\x02\x01\xe0").unwrap(),
+            },
+            Command::SequenceData {
+                data: SequenceChunk::new(&[0xff; MAX_SEQUENCE_CHUNK_LEN]).unwrap(),
+            },
+            Command::SequenceData {
+                data: SequenceChunk::new(&[]).unwrap(),
+            },
+            Command::EndSequence,
+            Command::PlaySequence,
+            Command::StopSequence,
+        ];
+        let string = StringParam::ALL.iter().flat_map(|&param| {
+            [
+                Command::GetString { string: 3, param },
+                Command::SetString {
+                    string: 3,
+                    param,
+                    value: 0,
+                },
+                Command::SetString {
+                    string: 3,
+                    param,
+                    value: u32::MAX,
+                },
+            ]
+        });
+        let global = GlobalParam::ALL.iter().flat_map(|&param| {
+            [
+                Command::GetGlobal { param },
+                Command::SetGlobal {
+                    param,
+                    value: 0x12345678,
+                },
+            ]
+        });
+        fixed.into_iter().chain(string).chain(global)
+    }
+
+    fn replies() -> impl Iterator<Item = Reply> {
+        let fixed = [
+            Reply::Ack { command: 0x11 },
+            Reply::Version(PROTOCOL_VERSION),
+            Reply::NoteValue {
+                note: 79,
+                mapping: Some((7, 3)),
+            },
+            Reply::NoteValue {
+                note: 1,
+                mapping: None,
+            },
+            Reply::PresetValue {
+                preset: 2,
+                name: PresetName::new(b"Chorus").unwrap(),
+            },
+        ];
+        let errors = [
+            ErrorCode::UnknownCommand,
+            ErrorCode::Malformed,
+            ErrorCode::InvalidString,
+            ErrorCode::InvalidParam,
+            ErrorCode::InvalidValue,
+            ErrorCode::Unsupported,
+            ErrorCode::Busy,
+            ErrorCode::InvalidPreset,
+            ErrorCode::StorageFailed,
+            ErrorCode::InvalidSequence,
+        ]
+        .into_iter()
+        .map(|code| Reply::Error {
+            command: 0x21,
+            code,
+        });
+        let string = StringParam::ALL.iter().map(|&param| Reply::StringValue {
+            string: 5,
+            param,
+            value: 1_000_000,
+        });
+        let global = GlobalParam::ALL.iter().map(|&param| Reply::GlobalValue {
+            param,
+            value: u32::MAX,
+        });
+        let diagnostic = DiagnosticParam::ALL
+            .iter()
+            .map(|&param| Reply::DiagnosticValue {
+                param,
+                index: 2,
+                value: 42_000,
+            });
+        fixed
+            .into_iter()
+            .chain(errors)
+            .chain(string)
+            .chain(global)
+            .chain(diagnostic)
+    }
+
+    fn reports() -> impl Iterator<Item = Report> {
+        let state = StringState::ALL
+            .iter()
+            .map(|&state| Report::State { string: 6, state });
+        let sample = SampleResult::ALL.iter().map(|&result| Report::Sample {
+            string: 1,
+            result,
+            sample: 2_251_000,
+            period: u32::MAX,
+        });
+        let diagnostic = DiagnosticParam::ALL
+            .iter()
+            .map(|&param| Report::Diagnostic {
+                param,
+                index: 7,
+                value: 3,
+            });
+        state.chain(sample).chain(diagnostic)
+    }
+
+    fn assert_valid_sysex(message: &[u8]) {
+        assert_eq!(message.first(), Some(&SYSEX_START));
+        assert_eq!(message.last(), Some(&SYSEX_END));
+        for &b in &message[1..message.len() - 1] {
+            assert!(b < 0x80, "{:x?} contains a status byte", message);
+        }
+    }
+
+    #[test]
+    fn command_round_trip() {
+        for command in commands() {
+            let message = command.encode();
+            assert_valid_sysex(&message);
+            assert_eq!(Command::decode(&message), Ok(command));
+        }
+    }
+
+    #[test]
+    fn reply_round_trip() {
+        for reply in replies() {
+            let message = reply.encode();
+            assert_valid_sysex(&message);
+            assert_eq!(Reply::decode(&message), Ok(reply));
+        }
+    }
+
+    #[test]
+    fn report_round_trip() {
+        for report in reports() {
+            let message = report.encode();
+            assert_valid_sysex(&message);
+            assert_eq!(Report::decode(&message), Ok(report));
+            assert!(Reply::decode(&message).is_err());
+        }
+    }
+
+    #[test]
+    fn replies_are_not_reports() {
+        for reply in replies() {
+            assert!(Report::decode(&reply.encode()).is_err());
+        }
+    }
+
+    #[test]
+    fn param_names() {
+        for &param in StringParam::ALL {
+            assert_eq!(StringParam::from_name(param.name()), Some(param));
+        }
+        for &param in GlobalParam::ALL {
+            assert_eq!(GlobalParam::from_name(param.name()), Some(param));
+        }
+        for &param in DiagnosticParam::ALL {
+            assert_eq!(DiagnosticParam::from_name(param.name()), Some(param));
+        }
+        for &state in StringState::ALL {
+            assert_eq!(StringState::from_name(state.name()), Some(state));
+        }
+        for &result in SampleResult::ALL {
+            assert_eq!(SampleResult::from_name(result.name()), Some(result));
+        }
+        assert_eq!(StringParam::from_name("bogus"), None);
+    }
+
+    #[test]
+    fn value_round_trip() {
+        for value in [
+            0,
+            1,
+            0x7f,
+            0x80,
+            0x3fff,
+            0x4000,
+            0x0fff_ffff,
+            0x1000_0000,
+            u32::MAX,
+        ] {
+            assert_eq!(decode_value(&encode_value(value)), Some(value));
+        }
+    }
+
+    #[test]
+    fn value_overflow() {
+        assert_eq!(decode_value(&[0x7f, 0x7f, 0x7f, 0x7f, 0x10]), None);
+        assert_eq!(decode_value(&[0, 0, 0, 0]), None);
+    }
+
+    #[test]
+    fn other_manufacturer() {
+        let message = [SYSEX_START, 0x41, 0x10, 0x00, SYSEX_END];
+        assert_eq!(Command::decode(&message), Err(DecodeError::NotForUs));
+        assert_eq!(Reply::decode(&message), Err(DecodeError::NotForUs));
+        assert_eq!(
+            Command::decode(&[SYSEX_START, SYSEX_MANUFACTURER_ID, SYSEX_END]),
+            Err(DecodeError::NotForUs)
+        );
+    }
+
+    #[test]
+    fn unknown_command() {
+        let message = [SYSEX_START, SYSEX_MANUFACTURER_ID, 0x7e, SYSEX_END];
+        assert_eq!(
+            Command::decode(&message),
+            Err(DecodeError::Invalid(0x7e, ErrorCode::UnknownCommand))
+        );
+    }
+
+    #[test]
+    fn malformed_command() {
+        let mut message = Command::GetString {
+            string: 0,
+            param: StringParam::Period,
+        }
+        .encode();
+        message.insert(4, 0).unwrap();
+        assert_eq!(
+            Command::decode(&message),
+            Err(DecodeError::Invalid(
+                command::GET_STRING,
+                ErrorCode::Malformed
+            ))
+        );
+    }
+
+    #[test]
+    fn invalid_param() {
+        let message = [
+            SYSEX_START,
+            SYSEX_MANUFACTURER_ID,
+            command::GET_STRING,
+            0,
+            0x7f,
+            SYSEX_END,
+        ];
+        assert_eq!(
+            Command::decode(&message),
+            Err(DecodeError::Invalid(
+                command::GET_STRING,
+                ErrorCode::InvalidParam
+            ))
+        );
+    }
+
+    #[test]
+    fn invalid_preset_name() {
+        assert_eq!(PresetName::new(&[b'x'; MAX_PRESET_NAME_LEN + 1]), None);
+        assert_eq!(PresetName::new(b"caf\xe9"), None);
+        let message = [
+            SYSEX_START,
+            SYSEX_MANUFACTURER_ID,
+            command::SAVE_PRESET,
+            0,
+            SYSEX_END,
+            SYSEX_END,
+        ];
+        assert_eq!(
+            Command::decode(&message),
+            Err(DecodeError::Invalid(
+                command::SAVE_PRESET,
+                ErrorCode::Malformed
+            ))
+        );
+    }
+
+    #[test]
+    fn pack_round_trip() {
+        let mut bytes = [0; 256];
+        for (i, b) in bytes.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        assert_eq!(unpack(&pack(&[])).as_deref(), Some(&[][..]));
+        for len in 1..=MAX_SEQUENCE_CHUNK_LEN {
+            for data in bytes.windows(len) {
+                let packed = pack(data);
+                assert_eq!(packed.len(), packed_len(len));
+                assert!(packed.iter().all(|&b| b < 0x80));
+                assert_eq!(unpack(&packed).as_deref(), Some(data));
+            }
+        }
+        // Top bits without any bytes after them
+        assert_eq!(unpack(&[0, 1, 2, 3, 4, 5, 6, 7, 0]), None);
+    }
+
+    #[test]
+    fn sequence_chunk_too_long() {
+        assert_eq!(SequenceChunk::new(&[0; MAX_SEQUENCE_CHUNK_LEN + 1]), None);
+        let mut message = [0; MAX_MESSAGE_LEN + 2];
+        message[..3].copy_from_slice(&[SYSEX_START, SYSEX_MANUFACTURER_ID, command::SEQUENCE_DATA]);
+        message[MAX_MESSAGE_LEN + 1] = SYSEX_END;
+        assert_eq!(
+            Command::decode(&message),
+            Err(DecodeError::Invalid(
+                command::SEQUENCE_DATA,
+                ErrorCode::Malformed
+            ))
+        );
+    }
+
+    #[test]
+    fn panic_report_round_trip() {
+        let report = PanicReport {
+            line: 1234,
+            column: 56,
+            text: b"src/main.rs: attempt to add with overflow",
+        };
+        let message = encode_panic_report(&report);
+        assert_valid_sysex(&message);
+        assert_eq!(decode_panic_report(&message), Some(report));
+        assert_eq!(
+            Reply::decode(&message),
+            Err(DecodeError::Invalid(
+                reply::PANIC_REPORT,
+                ErrorCode::UnknownCommand
+            ))
+        );
+    }
+
+    #[test]
+    fn panic_report_truncated() {
+        let message = encode_panic_report(&PanicReport {
+            line: u32::MAX,
+            column: u32::MAX,
+            text: &[b'a'; 200],
+        });
+        assert_eq!(message.len(), MAX_PANIC_REPORT_LEN);
+        let report = decode_panic_report(&message).unwrap();
+        assert_eq!(report.line, u32::MAX);
+        assert_eq!(report.text.len(), MAX_PANIC_TEXT_LEN);
+    }
+}
@@ -0,0 +1,218 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// Prescaler and period planning for the firmware's PWM DACs, which is kept here so it can be
+// tested on the host. Frequencies are in hertz.
+
+/// Prescaler dividers supported by the TCC
+const DIVIDERS: [u16; 8] = [1, 2, 4, 8, 16, 64, 256, 1024];
+
+/// PWM steps are written to the TCC as single bytes by DMA
+const MAX_TOP: u32 = u8::MAX as u32;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// Sample rate or resolution is zero
+    InvalidRequest,
+    /// No prescaler and period combination provides the requested resolution at this sample rate
+    Unachievable,
+    /// The closest achievable sample rate is further from the requested rate than the tolerance
+    OutOfTolerance { achieved: u32, error_ppm: i32 },
+    /// The plan was made for a different clock frequency than the one supplied
+    ClockMismatch { planned: u32, actual: u32 },
+}
+
+/// Prescaler and period configuration of a PWM DAC, chosen to be as close as possible to a
+/// requested sample rate.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Plan {
+    clock_freq: u32,
+    requested: u32,
+    divider: u16,
+    top: u8,
+}
+
+impl Plan {
+    /// Search all prescaler and period values for the configuration closest to `sample_rate`
+    /// with at least `resolution` PWM steps. When several are equally close, the one with the
+    /// most steps is chosen. The achieved rate must be within `tolerance_ppm` of the request.
+    pub const fn new(
+        clock_freq: u32,
+        sample_rate: u32,
+        resolution: u8,
+        tolerance_ppm: u32,
+    ) -> Result<Self, Error> {
+        if sample_rate == 0 || resolution == 0 {
+            return Err(Error::InvalidRequest);
+        }
+
+        let mut best: Option<Self> = None;
+        let mut best_error = u64::MAX;
+
+        let mut i = 0;
+        while i < DIVIDERS.len() {
+            let divider = DIVIDERS[i];
+            i += 1;
+
+            // Dual-slope PWM counts up to TOP and back down again for every sample
+            let counts = clock_freq as u64 / divider as u64;
+            let top = (counts + sample_rate as u64) / (2 * sample_rate as u64);
+            if top < resolution as u64 || top > MAX_TOP as u64 {
+                continue;
+            }
+
+            let plan = Self {
+                clock_freq,
+                requested: sample_rate,
+                divider,
+                top: top as u8,
+            };
+            let error = plan.sample_rate().abs_diff(sample_rate) as u64;
+            // Dividers are in increasing order, so ties go to the larger period
+            if error < best_error {
+                best = Some(plan);
+                best_error = error;
+            }
+        }
+
+        match best {
+            Some(plan) => {
+                let error_ppm = plan.error_ppm();
+                if error_ppm.unsigned_abs() > tolerance_ppm {
+                    Err(Error::OutOfTolerance {
+                        achieved: plan.sample_rate(),
+                        error_ppm,
+                    })
+                } else {
+                    Ok(plan)
+                }
+            }
+            None => Err(Error::Unachievable),
+        }
+    }
+
+    /// Same as `new()`, but panics if no suitable configuration exists. When used to initialize
+    /// a constant, this rejects impossible configurations at compile time.
+    pub const fn new_const(
+        clock_freq: u32,
+        sample_rate: u32,
+        resolution: u8,
+        tolerance_ppm: u32,
+    ) -> Self {
+        match Self::new(clock_freq, sample_rate, resolution, tolerance_ppm) {
+            Ok(plan) => plan,
+            Err(Error::InvalidRequest) => panic!("Sample rate and resolution must be non-zero"),
+            Err(Error::Unachievable) => panic!("No PWM configuration provides this resolution"),
+            Err(_) => panic!("No PWM configuration is within tolerance of this sample rate"),
+        }
+    }
+
+    /// Check that the TCC is clocked at the frequency the plan was made for
+    pub fn check_clock(&self, clock_freq: u32) -> Result<(), Error> {
+        if clock_freq == self.clock_freq {
+            Ok(())
+        } else {
+            Err(Error::ClockMismatch {
+                planned: self.clock_freq,
+                actual: clock_freq,
+            })
+        }
+    }
+
+    pub const fn sample_rate(&self) -> u32 {
+        self.clock_freq / self.divider as u32 / (2 * self.top as u32)
+    }
+
+    /// Difference between the achieved and requested sample rate, in parts per million
+    pub const fn error_ppm(&self) -> i32 {
+        ((self.sample_rate() as i64 - self.requested as i64) * 1_000_000 / self.requested as i64)
+            as i32
+    }
+
+    pub const fn divider(&self) -> u16 {
+        self.divider
+    }
+
+    /// Number of PWM steps, which is the value of the PER register
+    pub const fn top(&self) -> u8 {
+        self.top
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLOCK: u32 = 48_000_000;
+
+    #[test]
+    fn exact() {
+        let plan = Plan::new(CLOCK, 25_000, 240, 0).unwrap();
+        assert_eq!(plan.divider(), 4);
+        assert_eq!(plan.top(), 240);
+        assert_eq!(plan.sample_rate(), 25_000);
+        assert_eq!(plan.error_ppm(), 0);
+        assert_eq!(Plan::new_const(CLOCK, 25_000, 240, 0), plan);
+    }
+
+    #[test]
+    fn unachievable() {
+        // 240 steps fits exactly, and the next smaller prescaler needs twice as many
+        assert_eq!(
+            Plan::new(CLOCK, 25_000, 241, 1_000),
+            Err(Error::Unachievable)
+        );
+        // Too slow for even the largest prescaler
+        assert_eq!(Plan::new(CLOCK, 10, 1, 1_000), Err(Error::Unachievable));
+    }
+
+    #[test]
+    fn tolerance() {
+        // The closest rate is 27027 Hz, exactly 1000 ppm off
+        let plan = Plan::new(CLOCK, 27_000, 100, 1_000).unwrap();
+        assert_eq!(plan.sample_rate(), 27_027);
+        assert_eq!(plan.error_ppm(), 1_000);
+        assert_eq!(
+            Plan::new(CLOCK, 27_000, 100, 999),
+            Err(Error::OutOfTolerance {
+                achieved: 27_027,
+                error_ppm: 1_000
+            })
+        );
+    }
+
+    #[test]
+    fn ties_prefer_more_steps() {
+        // Dividing by 4 with 222 steps and by 8 with 111 steps both give 27027 Hz
+        let plan = Plan::new(CLOCK, 27_000, 100, 1_000).unwrap();
+        assert_eq!(plan.divider(), 4);
+        assert_eq!(plan.top(), 222);
+    }
+
+    #[test]
+    fn invalid_request() {
+        assert_eq!(Plan::new(CLOCK, 0, 240, 1_000), Err(Error::InvalidRequest));
+        assert_eq!(
+            Plan::new(CLOCK, 25_000, 0, 1_000),
+            Err(Error::InvalidRequest)
+        );
+    }
+
+    #[test]
+    fn clock_mismatch() {
+        let plan = Plan::new(CLOCK, 25_000, 240, 0).unwrap();
+        assert_eq!(plan.check_clock(CLOCK), Ok(()));
+        assert_eq!(
+            plan.check_clock(8_000_000),
+            Err(Error::ClockMismatch {
+                planned: CLOCK,
+                actual: 8_000_000
+            })
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn const_rejects_unachievable() {
+        Plan::new_const(CLOCK, 25_000, 241, 1_000);
+    }
+}
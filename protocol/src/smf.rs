@@ -0,0 +1,536 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+use heapless::Vec;
+
+/// Most tracks a format 1 file can have and still be played
+pub const MAX_TRACKS: usize = 16;
+
+/// Tempo until the first tempo change, 120 beats per minute
+const DEFAULT_TEMPO_US: u32 = 500_000;
+
+const HEADER_CHUNK: &[u8] = b"MThd";
+const TRACK_CHUNK: &[u8] = b"MTrk";
+
+/// Status bytes of events that aren't channel messages
+const SYSEX: u8 = 0xf0;
+const SYSEX_ESCAPE: u8 = 0xf7;
+const META: u8 = 0xff;
+
+/// Meta event types
+const META_END_OF_TRACK: u8 = 0x2f;
+const META_TEMPO: u8 = 0x51;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// Doesn't start with a header chunk
+    NotMidi,
+    /// Format 2, or more tracks than can be played at once
+    Unsupported,
+    /// A chunk or event is cut off or doesn't make sense
+    Malformed,
+}
+
+/// How the delta times of events are counted
+#[derive(Clone, Copy)]
+enum Division {
+    /// Ticks per quarter note, which last as long as the tempo says
+    Metrical(u32),
+    /// Fixed length ticks, given as a number of microseconds and how many ticks they span
+    Timecode { us: u32, ticks: u32 },
+}
+
+impl Division {
+    fn from_header(division: u16) -> Result<Self, Error> {
+        if division & 0x8000 == 0 {
+            return match division {
+                0 => Err(Error::Malformed),
+                ticks => Ok(Self::Metrical(ticks as u32)),
+            };
+        }
+
+        // The upper byte is the negative SMPTE frame rate and the lower byte the ticks per frame
+        let frames = ((division >> 8) as i8).unsigned_abs() as u32;
+        let ticks = (division & 0xff) as u32;
+        match frames {
+            _ if ticks == 0 => Err(Error::Malformed),
+            24 | 25 | 30 => Ok(Self::Timecode {
+                us: 1_000_000,
+                ticks: frames * ticks,
+            }),
+            // 29.97 frames per second, drop frame
+            29 => Ok(Self::Timecode {
+                us: 1_001_000,
+                ticks: 30 * ticks,
+            }),
+            _ => Err(Error::Malformed),
+        }
+    }
+}
+
+/// Type and contents of a chunk
+type Chunk<'a> = (&'a [u8], &'a [u8]);
+
+/// Split off the next chunk
+fn next_chunk<'a>(data: &mut &'a [u8]) -> Result<Option<Chunk<'a>>, Error> {
+    if data.is_empty() {
+        return Ok(None);
+    }
+    if data.len() < 8 {
+        return Err(Error::Malformed);
+    }
+    let (header, rest) = data.split_at(8);
+    let len = u32::from_be_bytes(header[4..].try_into().unwrap()) as usize;
+    if rest.len() < len {
+        return Err(Error::Malformed);
+    }
+    let (contents, rest) = rest.split_at(len);
+    *data = rest;
+    Ok(Some((&header[..4], contents)))
+}
+
+#[derive(Clone, Copy)]
+enum TrackEvent {
+    /// Channel message, padded with zeros if it has only one data byte
+    Channel([u8; 3]),
+    /// Microseconds per quarter note
+    Tempo(u32),
+    EndOfTrack,
+    /// SysEx and meta events that don't affect playback
+    Ignored,
+}
+
+/// Reads the events of a track chunk in order
+struct Track<'a> {
+    data: &'a [u8],
+    pos: usize,
+    running_status: Option<u8>,
+}
+
+impl<'a> Track<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            pos: 0,
+            running_status: None,
+        }
+    }
+
+    fn byte(&mut self) -> Result<u8, Error> {
+        let byte = *self.data.get(self.pos).ok_or(Error::Malformed)?;
+        self.pos += 1;
+        Ok(byte)
+    }
+
+    fn bytes(&mut self, len: u32) -> Result<&'a [u8], Error> {
+        let bytes = self
+            .data
+            .get(self.pos..)
+            .and_then(|rest| rest.get(..len as usize))
+            .ok_or(Error::Malformed)?;
+        self.pos += bytes.len();
+        Ok(bytes)
+    }
+
+    /// Variable length quantity, which has 7 bits in each byte, most significant first, and
+    /// the top bit set on all but the last byte
+    fn var_len(&mut self) -> Result<u32, Error> {
+        let mut value = 0;
+        for _ in 0..4 {
+            let byte = self.byte()?;
+            value = value << 7 | (byte & 0x7f) as u32;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(Error::Malformed)
+    }
+
+    fn channel_message(&mut self, status: u8) -> Result<TrackEvent, Error> {
+        let mut message = [status, 0, 0];
+        let len = match status {
+            0xc0..=0xdf => 1,
+            _ => 2,
+        };
+        for byte in &mut message[1..=len] {
+            *byte = self.byte()?;
+            if *byte & 0x80 != 0 {
+                return Err(Error::Malformed);
+            }
+        }
+        Ok(TrackEvent::Channel(message))
+    }
+
+    /// Read the next event and the ticks since the one before it, or `None` once the track has
+    /// ended
+    fn next(&mut self) -> Result<Option<(u32, TrackEvent)>, Error> {
+        // Tracks without an end of track event end with their chunk
+        if self.pos == self.data.len() {
+            return Ok(None);
+        }
+
+        let delta = self.var_len()?;
+        let status = self.byte()?;
+        let event = match status {
+            // SysEx and meta events cancel running status
+            SYSEX | SYSEX_ESCAPE => {
+                self.running_status = None;
+                let len = self.var_len()?;
+                self.bytes(len)?;
+                TrackEvent::Ignored
+            }
+            META => {
+                self.running_status = None;
+                let kind = self.byte()?;
+                let len = self.var_len()?;
+                match (kind, self.bytes(len)?) {
+                    (META_END_OF_TRACK, _) => {
+                        self.pos = self.data.len();
+                        TrackEvent::EndOfTrack
+                    }
+                    (META_TEMPO, &[a, b, c]) => TrackEvent::Tempo(u32::from_be_bytes([0, a, b, c])),
+                    _ => TrackEvent::Ignored,
+                }
+            }
+            0x80..=0xef => {
+                self.running_status = Some(status);
+                self.channel_message(status)?
+            }
+            // System common and realtime messages can't appear in a file
+            0xf1..=0xfe => return Err(Error::Malformed),
+            // A data byte, which continues the last channel message's status
+            _ => {
+                let status = self.running_status.ok_or(Error::Malformed)?;
+                self.pos -= 1;
+                self.channel_message(status)?
+            }
+        };
+        Ok(Some((delta, event)))
+    }
+}
+
+/// Standard MIDI File of format 0 or 1
+#[derive(Clone)]
+pub struct Sequence<'a> {
+    tracks: Vec<&'a [u8], MAX_TRACKS>,
+    division: Division,
+}
+
+impl<'a> Sequence<'a> {
+    /// Find the tracks of a file, checking that every event in them can be read, so playing it
+    /// can't fail later
+    pub fn parse(mut data: &'a [u8]) -> Result<Self, Error> {
+        let header = match next_chunk(&mut data) {
+            Ok(Some((HEADER_CHUNK, header))) if header.len() >= 6 => header,
+            _ => return Err(Error::NotMidi),
+        };
+        let format = u16::from_be_bytes([header[0], header[1]]);
+        let num_tracks = u16::from_be_bytes([header[2], header[3]]) as usize;
+        let division = Division::from_header(u16::from_be_bytes([header[4], header[5]]))?;
+        if format > 1 || num_tracks > MAX_TRACKS {
+            return Err(Error::Unsupported);
+        }
+
+        let mut tracks = Vec::new();
+        while let Some((kind, chunk)) = next_chunk(&mut data)? {
+            // Unknown chunk types are skipped, as the standard asks
+            if kind == TRACK_CHUNK {
+                tracks.push(chunk).map_err(|_| Error::Malformed)?;
+            }
+        }
+        if tracks.len() != num_tracks {
+            return Err(Error::Malformed);
+        }
+
+        for &track in &tracks {
+            let mut track = Track::new(track);
+            while track.next()?.is_some() {}
+        }
+        Ok(Self { tracks, division })
+    }
+}
+
+/// Channel message and when to play it, in microseconds from the start of the sequence
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Event {
+    pub time_us: u64,
+    pub message: [u8; 3],
+}
+
+struct PlayerTrack<'a> {
+    track: Track<'a>,
+    /// Tick and contents of the next event on this track
+    next: Option<(u64, TrackEvent)>,
+}
+
+/// Merges the tracks of a sequence into a single stream of channel messages, timed according to
+/// the tempo changes along the way
+pub struct Player<'a> {
+    sequence: Sequence<'a>,
+    tracks: Vec<PlayerTrack<'a>, MAX_TRACKS>,
+    us_per_quarter: u32,
+    /// Tick and time of the last tempo change, which later times are counted from
+    tempo_tick: u64,
+    tempo_time_us: u64,
+    /// Time of the last event read
+    time_us: u64,
+}
+
+impl<'a> Player<'a> {
+    pub fn new(sequence: Sequence<'a>) -> Self {
+        let mut player = Self {
+            sequence,
+            tracks: Vec::new(),
+            us_per_quarter: DEFAULT_TEMPO_US,
+            tempo_tick: 0,
+            tempo_time_us: 0,
+            time_us: 0,
+        };
+        player.rewind();
+        player
+    }
+
+    /// Go back to the start of the sequence
+    pub fn rewind(&mut self) {
+        self.tracks.clear();
+        for &data in &self.sequence.tracks {
+            let mut track = Track::new(data);
+            let next = Self::read(&mut track, 0);
+            // There is room for every track, since they were counted when parsing
+            self.tracks.push(PlayerTrack { track, next }).ok();
+        }
+        self.us_per_quarter = DEFAULT_TEMPO_US;
+        self.tempo_tick = 0;
+        self.tempo_time_us = 0;
+        self.time_us = 0;
+    }
+
+    fn read(track: &mut Track<'a>, tick: u64) -> Option<(u64, TrackEvent)> {
+        // Every event was checked when parsing, so errors can't happen
+        let (delta, event) = track.next().ok().flatten()?;
+        Some((tick + delta as u64, event))
+    }
+
+    fn time_us(&self, tick: u64) -> u64 {
+        let (us, ticks) = match self.sequence.division {
+            Division::Metrical(ticks) => (self.us_per_quarter, ticks),
+            Division::Timecode { us, ticks } => (us, ticks),
+        };
+        self.tempo_time_us + (tick - self.tempo_tick) * us as u64 / ticks as u64
+    }
+
+    /// Time of the last event read. Once the sequence has ended, this is its length, including
+    /// any time before the end of the last track.
+    pub fn position_us(&self) -> u64 {
+        self.time_us
+    }
+}
+
+impl<'a> Iterator for Player<'a> {
+    type Item = Event;
+
+    /// Next channel message of any track, or `None` once every track has ended. Events at the
+    /// same time are played in track order.
+    fn next(&mut self) -> Option<Event> {
+        loop {
+            let track = self
+                .tracks
+                .iter_mut()
+                .filter(|track| track.next.is_some())
+                .min_by_key(|track| track.next.map_or(u64::MAX, |(tick, _)| tick))?;
+            let (tick, event) = track.next.take()?;
+            track.next = Self::read(&mut track.track, tick);
+
+            let time_us = self.time_us(tick);
+            self.time_us = time_us;
+            match event {
+                TrackEvent::Channel(message) => return Some(Event { time_us, message }),
+                TrackEvent::Tempo(us_per_quarter) => {
+                    self.tempo_tick = tick;
+                    self.tempo_time_us = time_us;
+                    self.us_per_quarter = us_per_quarter;
+                }
+                TrackEvent::EndOfTrack | TrackEvent::Ignored => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Assemble a file from its header fields and track contents
+    fn file(format: u16, division: u16, tracks: &[&[u8]]) -> Vec<u8, 256> {
+        let mut file = Vec::new();
+        file.extend_from_slice(HEADER_CHUNK).unwrap();
+        file.extend_from_slice(&6u32.to_be_bytes()).unwrap();
+        file.extend_from_slice(&format.to_be_bytes()).unwrap();
+        file.extend_from_slice(&(tracks.len() as u16).to_be_bytes())
+            .unwrap();
+        file.extend_from_slice(&division.to_be_bytes()).unwrap();
+        for track in tracks {
+            file.extend_from_slice(TRACK_CHUNK).unwrap();
+            file.extend_from_slice(&(track.len() as u32).to_be_bytes())
+                .unwrap();
+            file.extend_from_slice(track).unwrap();
+        }
+        file
+    }
+
+    fn events(data: &[u8]) -> Vec<Event, 16> {
+        Player::new(Sequence::parse(data).unwrap()).collect()
+    }
+
+    fn event(time_us: u64, message: [u8; 3]) -> Event {
+        Event { time_us, message }
+    }
+
+    #[test]
+    fn running_status() {
+        let data = file(
+            0,
+            96,
+            &[&[
+                0x00, 0x90, 0x3c, 0x40, // Note on
+                0x60, 0x3e, 0x40, // Another note on, with running status
+                0x60, 0xc0, 0x05, // Program change, which has one data byte
+                0x00, 0x07, // Running status again
+                0x00, 0xff, 0x2f, 0x00,
+            ]],
+        );
+        assert_eq!(
+            events(&data),
+            [
+                event(0, [0x90, 0x3c, 0x40]),
+                event(500_000, [0x90, 0x3e, 0x40]),
+                event(1_000_000, [0xc0, 0x05, 0x00]),
+                event(1_000_000, [0xc0, 0x07, 0x00]),
+            ]
+        );
+
+        // Meta events cancel running status
+        let data = file(
+            0,
+            96,
+            &[&[
+                0x00, 0x90, 0x3c, 0x40, 0x00, 0xff, 0x01, 0x00, 0x00, 0x3e, 0x40,
+            ]],
+        );
+        assert!(matches!(Sequence::parse(&data), Err(Error::Malformed)));
+    }
+
+    #[test]
+    fn smpte_division() {
+        // 25 frames per second of 40 ticks each, so a tick is a millisecond
+        let data = file(0, 0xe728, &[&[0x87, 0x68, 0x90, 0x3c, 0x40]]);
+        assert_eq!(events(&data), [event(1_000_000, [0x90, 0x3c, 0x40])]);
+
+        // 29.97 frames per second, so 30 frames last a little longer than a second
+        let data = file(0, 0xe302, &[&[0x3c, 0x90, 0x3c, 0x40]]);
+        assert_eq!(events(&data), [event(1_001_000, [0x90, 0x3c, 0x40])]);
+
+        // Frame rates that don't exist, including the one furthest from zero
+        for division in [0xe901, 0x8001, 0xff01, 0xe800] {
+            assert!(matches!(
+                Sequence::parse(&file(0, division, &[])),
+                Err(Error::Malformed)
+            ));
+        }
+    }
+
+    #[test]
+    fn truncated() {
+        let data = file(0, 96, &[&[0x00, 0x90, 0x3c, 0x40, 0x00, 0xff, 0x2f, 0x00]]);
+        assert!(Sequence::parse(&data).is_ok());
+
+        // Cut off in the middle of the track chunk and of its header
+        for len in [data.len() - 1, 14 + 4] {
+            assert!(matches!(
+                Sequence::parse(&data[..len]),
+                Err(Error::Malformed)
+            ));
+        }
+        // Cut off in the middle of the header chunk
+        assert!(matches!(Sequence::parse(&data[..12]), Err(Error::NotMidi)));
+
+        // The chunk is complete, but the event in it isn't
+        let data = file(0, 96, &[&[0x00, 0x90, 0x3c]]);
+        assert!(matches!(Sequence::parse(&data), Err(Error::Malformed)));
+        let data = file(0, 96, &[&[0x00, 0xff, 0x51, 0x03, 0x07]]);
+        assert!(matches!(Sequence::parse(&data), Err(Error::Malformed)));
+        // A variable length quantity that never ends
+        let data = file(0, 96, &[&[0x80, 0x80, 0x80, 0x80, 0x00]]);
+        assert!(matches!(Sequence::parse(&data), Err(Error::Malformed)));
+    }
+
+    #[test]
+    fn header() {
+        assert!(matches!(Sequence::parse(b"RIFF"), Err(Error::NotMidi)));
+        assert!(matches!(
+            Sequence::parse(&file(2, 96, &[])),
+            Err(Error::Unsupported)
+        ));
+        assert!(matches!(
+            Sequence::parse(&file(0, 0, &[])),
+            Err(Error::Malformed)
+        ));
+
+        // The header says there is a track, but there isn't
+        let mut data = file(0, 96, &[]);
+        data[11] = 1;
+        assert!(matches!(Sequence::parse(&data), Err(Error::Malformed)));
+    }
+
+    #[test]
+    fn tempo_changes() {
+        let data = file(
+            0,
+            96,
+            &[&[
+                0x00, 0x90, 0x3c, 0x40, //
+                // Double the tempo after the first quarter note
+                0x60, 0xff, 0x51, 0x03, 0x03, 0xd0, 0x90, //
+                0x60, 0x80, 0x3c, 0x00, //
+                0x00, 0xff, 0x2f, 0x00,
+            ]],
+        );
+        assert_eq!(
+            events(&data),
+            [
+                event(0, [0x90, 0x3c, 0x40]),
+                event(750_000, [0x80, 0x3c, 0x00])
+            ]
+        );
+
+        // Format 1 files keep the tempo map in the first track, which applies to all of them
+        let data = file(
+            1,
+            96,
+            &[
+                &[
+                    0x00, 0xff, 0x51, 0x03, 0x03, 0xd0, 0x90, 0x00, 0xff, 0x2f, 0x00,
+                ],
+                &[0x60, 0x90, 0x3c, 0x40, 0x81, 0x40, 0x80, 0x3c, 0x00],
+            ],
+        );
+        assert_eq!(
+            events(&data),
+            [
+                event(250_000, [0x90, 0x3c, 0x40]),
+                event(750_000, [0x80, 0x3c, 0x00])
+            ]
+        );
+    }
+
+    #[test]
+    fn rewind() {
+        let data = file(0, 96, &[&[0x60, 0x90, 0x3c, 0x40, 0x60, 0xff, 0x2f, 0x00]]);
+        let mut player = Player::new(Sequence::parse(&data).unwrap());
+        assert_eq!(player.next(), Some(event(500_000, [0x90, 0x3c, 0x40])));
+        assert_eq!(player.next(), None);
+        // The length includes the time before the end of the track
+        assert_eq!(player.position_us(), 1_000_000);
+
+        player.rewind();
+        assert_eq!(player.next(), Some(event(500_000, [0x90, 0x3c, 0x40])));
+    }
+}
@@ -0,0 +1,162 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+use crate::hal::clock;
+use crate::hal::time::{Hertz, Nanoseconds, U32Ext};
+
+use crate::dac::{Dac, DacDmaTrigger};
+use crate::pac;
+use core::ops::Deref;
+use pac::{DAC, PM, TC4, TC5};
+
+/// Driver for the on-chip 10-bit DAC, which outputs on pin A0 (PA02). The sample rate is set by
+/// the overflow of a TC, which is used to trigger DMA transfers into the DAC data register.
+pub struct AnalogDac<TC>
+where
+    TC: Deref<Target = pac::tc3::RegisterBlock>,
+{
+    dac: DAC,
+    tc: TC,
+    sample_period: Nanoseconds,
+}
+
+impl<TC> AnalogDac<TC>
+where
+    TC: Deref<Target = pac::tc3::RegisterBlock>,
+{
+    fn sync_dac(&self) {
+        while self.dac.status.read().syncbusy().bit() {}
+    }
+
+    fn sync_tc(&self) {
+        while self.tc.count16().status.read().syncbusy().bit() {}
+    }
+
+    fn init(&self, top: u16) {
+        // Disable DAC
+        self.sync_dac();
+        self.dac.ctrla.modify(|_, w| w.enable().clear_bit());
+        self.sync_dac();
+        // Reset DAC
+        self.dac.ctrla.write(|w| w.swrst().set_bit());
+        self.sync_dac();
+
+        // Use the analog supply as the reference so the full 0-3.3V range is available, and
+        // enable the external output
+        self.dac.ctrlb.write(|w| w.refsel().avcc().eoen().set_bit());
+
+        // Start at zero output
+        self.dac.data.write(|w| unsafe { w.data().bits(0) });
+
+        // Enable DAC
+        self.sync_dac();
+        self.dac.ctrla.modify(|_, w| w.enable().set_bit());
+        self.sync_dac();
+
+        // Disable TC
+        self.sync_tc();
+        self.tc
+            .count16_mut()
+            .ctrla
+            .modify(|_, w| w.enable().clear_bit());
+        self.sync_tc();
+        // Reset TC
+        self.tc.count16_mut().ctrla.write(|w| w.swrst().set_bit());
+        self.sync_tc();
+
+        // Overflow when the counter matches CC0, which sets the sample rate
+        self.tc
+            .count16_mut()
+            .ctrla
+            .write(|w| w.prescaler().div1().wavegen().mfrq().mode().count16());
+
+        self.sync_tc();
+        self.tc.count16_mut().cc[0].write(|w| unsafe { w.cc().bits(top) });
+
+        // Enable TC
+        self.sync_tc();
+        self.tc
+            .count16_mut()
+            .ctrla
+            .modify(|_, w| w.enable().set_bit());
+
+        // Start TC
+        self.sync_tc();
+        self.tc
+            .count16_mut()
+            .ctrlbset
+            .write(|w| w.cmd().retrigger());
+    }
+}
+
+impl<TC> Dac for AnalogDac<TC>
+where
+    Self: DacDmaTrigger,
+    TC: Deref<Target = pac::tc3::RegisterBlock>,
+{
+    type Sample = u16;
+
+    fn max_amplitude(&self) -> u32 {
+        // 10-bit resolution
+        (1 << 10) - 1
+    }
+
+    fn set_amplitude(&mut self, sample: Self::Sample) {
+        let max = self.max_amplitude() as u16;
+        self.sync_dac();
+        self.dac
+            .data
+            .write(|w| unsafe { w.data().bits(sample.min(max)) });
+    }
+
+    fn sample_period(&self) -> Nanoseconds {
+        self.sample_period
+    }
+
+    fn dma_ptr(&self) -> *mut Self::Sample {
+        self.dac.data.as_ptr().cast()
+    }
+}
+
+impl DacDmaTrigger for AnalogDac<TC4> {
+    const DMA_TRIGGER_SOURCE: samd_dma::TriggerSource = samd_dma::TriggerSource::Tc4Ovf;
+}
+
+impl DacDmaTrigger for AnalogDac<TC5> {
+    const DMA_TRIGGER_SOURCE: samd_dma::TriggerSource = samd_dma::TriggerSource::Tc5Ovf;
+}
+
+macro_rules! analog_dac {
+    ($(($TC:ident, $clock:ident, $apmask:ident, $apbits:ident),)+) => {
+        $(
+
+impl AnalogDac<$TC> {
+    pub fn new(
+        _dac_clock: &clock::DacClock,
+        tc_clock: &clock::$clock,
+        freq: impl Into<Hertz>,
+        dac: DAC,
+        tc: $TC,
+        pm: &mut PM,
+    ) -> Self {
+        // Power on DAC and TC
+        pm.apbcmask.modify(|_, w| w.dac_().set_bit());
+        pm.$apmask.modify(|_, w| w.$apbits().set_bit());
+
+        let top = (tc_clock.freq().0 / freq.into().0).clamp(1, u16::MAX as u32 + 1);
+        let sample_period = (tc_clock.freq().0 / top).hz().into();
+
+        let s = Self {
+            dac,
+            tc,
+            sample_period,
+        };
+        s.init((top - 1) as u16);
+        s
+    }
+}
+
+)+}}
+
+analog_dac! {
+    (TC4, Tc4Tc5Clock, apbcmask, tc4_),
+    (TC5, Tc4Tc5Clock, apbcmask, tc5_),
+}
@@ -3,14 +3,26 @@ use crate::hal::time::Nanoseconds;
 use num_traits::PrimInt;
 
 pub trait Dac: DacDmaTrigger {
-    type Amplitude: 'static + PrimInt;
-    const MAX_AMPLITUDE: Self::Amplitude;
+    /// Value written to the hardware for each sample
+    type Sample: 'static + PrimInt;
+    /// Number of low amplitude bits that are spread over successive samples using sigma-delta
+    /// modulation, rather than being output directly
+    const NOISE_SHAPING_BITS: u32 = 0;
 
-    fn set_amplitude(&mut self, amplitude: Self::Amplitude);
+    /// Maximum amplitude, which has `NOISE_SHAPING_BITS` more bits of resolution than a sample
+    fn max_amplitude(&self) -> u32;
+
+    /// Amplitude that produces no output. DACs with a non-zero value are bipolar, and can swing
+    /// `max_amplitude()` both above and below it.
+    fn zero(&self) -> u32 {
+        0
+    }
+
+    fn set_amplitude(&mut self, sample: Self::Sample);
 
     fn sample_period(&self) -> Nanoseconds;
 
-    fn dma_ptr(&self) -> *mut Self::Amplitude;
+    fn dma_ptr(&self) -> *mut Self::Sample;
 }
 
 pub trait DacDmaTrigger {
@@ -0,0 +1,54 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+use crate::hal::clock;
+use crate::pac;
+use pac::{EIC, PM};
+
+pub type Sense = pac::eic::config::SENSE0_A;
+
+/// External interrupt controller, used only to turn pin changes into events
+pub struct ExternalInterruptController {
+    eic: EIC,
+}
+
+impl ExternalInterruptController {
+    pub const NUM_CHANNELS: u8 = 16;
+
+    pub fn new(_clock: clock::EicClock, eic: EIC, pm: &PM) -> Self {
+        // Power on EIC
+        pm.apbamask.modify(|_, w| w.eic_().set_bit());
+
+        let s = Self { eic };
+
+        // Reset EIC
+        s.sync();
+        s.eic.ctrl.write(|w| w.swrst().set_bit());
+        s.sync();
+
+        // Enable EIC
+        s.eic.ctrl.write(|w| w.enable().set_bit());
+        s.sync();
+
+        s
+    }
+
+    fn sync(&self) {
+        while self.eic.status.read().syncbusy().bit() {}
+    }
+
+    /// Generate an event on the given EXTINT channel when the pin matches `sense`. The pin must be
+    /// in alternate function A.
+    pub fn enable_event(&self, channel: u8, sense: Sense) {
+        assert!(channel < Self::NUM_CHANNELS);
+
+        // Each CONFIG register holds the settings for 8 channels, 4 bits each
+        let config = &self.eic.config[channel as usize / 8];
+        let shift = (channel % 8) * 4;
+        config.modify(|r, w| unsafe {
+            w.bits((r.bits() & !(0xf << shift)) | ((u8::from(sense) as u32) << shift))
+        });
+
+        self.eic
+            .evctrl
+            .modify(|r, w| unsafe { w.bits(r.bits() | (1 << channel)) });
+    }
+}
@@ -10,6 +10,20 @@ pub enum User {
     DmacCh1 = 0x1,
     DmacCh2 = 0x2,
     DmacCh3 = 0x3,
+    Tcc0Ev0 = 0x4,
+    Tcc0Ev1 = 0x5,
+    Tcc0Mc0 = 0x6,
+    Tcc0Mc1 = 0x7,
+    Tcc0Mc2 = 0x8,
+    Tcc0Mc3 = 0x9,
+    Tcc1Ev0 = 0xa,
+    Tcc1Ev1 = 0xb,
+    Tcc1Mc0 = 0xc,
+    Tcc1Mc1 = 0xd,
+    Tcc2Ev0 = 0xe,
+    Tcc2Ev1 = 0xf,
+    Tcc2Mc0 = 0x10,
+    Tcc2Mc1 = 0x11,
     Tc3 = 0x12,
     Tc4 = 0x13,
     Tc5 = 0x14,
@@ -25,7 +39,22 @@ pub type Path = pac::evsys::channel::PATH_A;
 #[repr(u8)]
 pub enum EventGenerator {
     None = 0x0,
+    EicExtint0 = 0xc,
+    EicExtint1 = 0xd,
+    EicExtint2 = 0xe,
+    EicExtint3 = 0xf,
+    EicExtint4 = 0x10,
+    EicExtint5 = 0x11,
+    EicExtint6 = 0x12,
+    EicExtint7 = 0x13,
     EicExtint8 = 0x14,
+    EicExtint9 = 0x15,
+    EicExtint10 = 0x16,
+    EicExtint11 = 0x17,
+    EicExtint12 = 0x18,
+    EicExtint13 = 0x19,
+    EicExtint14 = 0x1a,
+    EicExtint15 = 0x1b,
     AcComp0 = 0x44,
     AcComp1 = 0x45,
     AcWin0 = 0x46,
@@ -10,41 +10,76 @@ use rtic::app;
 mod const_assert;
 
 mod ac;
+#[cfg_attr(not(feature = "analog-string"), allow(dead_code))]
+mod analog_dac;
 mod dac;
+mod eic;
 mod evsys;
+mod midi;
+mod nvm;
+mod panic;
 mod pwm_dac;
 mod string;
+mod watchdog;
+
+/// Number of strings on the instrument, which `for_each_string!` also assumes
+const NUM_STRINGS: u8 = 8;
 
 #[app(device = bsp::pac, dispatchers = [EVSYS, DAC])]
 mod app {
-    use hal::clock::GenericClockController;
+    use hal::clock::{ClockGenId, ClockSource, GenericClockController};
     use hal::gpio::v2 as gpio;
     use hal::prelude::*;
     use hal::rtc;
+    use hal::sercom::v2::{uart, Sercom0};
     use hal::usb::usb_device::bus::UsbBusAllocator;
-    use hal::usb::usb_device::device::{UsbDevice, UsbDeviceBuilder, UsbVidPid};
+    use hal::usb::usb_device::device::{UsbDevice, UsbDeviceBuilder, UsbDeviceState, UsbVidPid};
     use hal::usb::UsbBus;
-    use panic_halt as _;
+    use heapless::Vec;
     use seq_macro::seq;
     use usbd_midi::data::midi;
-    use usbd_midi::data::usb_midi::midi_packet_reader::MidiPacketBufferReader;
+    use usbd_midi::data::usb_midi::usb_midi_event_packet::UsbMidiEventPacket;
 
     use crate::ac;
+    #[cfg(feature = "analog-string")]
+    use crate::analog_dac;
     use crate::bsp;
+    use crate::dac;
+    use crate::eic;
     use crate::evsys;
     use crate::hal;
+    use crate::midi::report::Reported;
+    use crate::midi::smf;
+    use crate::midi::sysex;
+    use crate::midi::tuning;
+    use crate::midi::Generation;
     use crate::pac;
+    use crate::panic;
     use crate::pwm_dac;
     use crate::string;
+    use crate::watchdog;
+    use crate::NUM_STRINGS;
+
+    /// All PWM DACs are clocked from the 48 MHz GCLK0. 240 steps at 25 kHz fits exactly, and this
+    /// fails to compile if that ever stops being possible.
+    const PWM_DAC_PLAN: pwm_dac::Plan = pwm_dac::Plan::new_const(48_000_000, 25_000, 240, 1_000);
 
-    // macro_rules! uart_println {
-    //     ($uart:expr, $($arg:tt)*) => {{
-    //         use core::fmt::Write;
-    //         $uart.lock(|s| writeln!(s as &mut dyn $crate::hal::ehal::serial::Write<_, Error = _>, $($arg)*))
-    //     }}
-    // }
+    /// Whether the TCC0 strings are driven by H-bridges
+    #[cfg(not(feature = "bipolar-strings"))]
+    type Tcc0Mode = pwm_dac::Unipolar;
+    #[cfg(feature = "bipolar-strings")]
+    type Tcc0Mode = pwm_dac::Bipolar;
 
-    const NUM_STRINGS: u8 = 8;
+    /// Time both sides of an H-bridge leg are off when switching, in 48 MHz GCLK cycles. 500 ns
+    /// lets one side of the bridge turn off before the other turns on.
+    #[cfg(feature = "bipolar-strings")]
+    const TCC0_DEAD_TIME: u8 = 24;
+
+    /// External interrupt that the overcurrent comparator is wired to
+    #[cfg(not(feature = "bipolar-strings"))]
+    const FAULT_EXTINT: (u8, evsys::EventGenerator) = (5, evsys::EventGenerator::EicExtint5);
+    #[cfg(feature = "bipolar-strings")]
+    const FAULT_EXTINT: (u8, evsys::EventGenerator) = (15, evsys::EventGenerator::EicExtint15);
 
     macro_rules! for_each_string {
         ($($tts:tt)*) => { seq!(N in 0..8 { $($tts)* }); }
@@ -53,44 +88,76 @@ mod app {
     macro_rules! string_i_lock {
         ($cx:expr, $i:expr, $f:expr) => {
             for_each_string!(
-                $cx.shared.strings.lock(|strings| match $i {
-                    #(N => ($f)(&mut strings.N),)*
-                    _ => panic!("String out of range")
+                $cx.shared.strings.lock(|strings| {
+                    let r = match $i {
+                        #(N => ($f)(&mut strings.controllers.N),)*
+                        _ => panic!("String out of range")
+                    };
+                    strings.apply_power_budget();
+                    r
                 })
             )
         };
     }
 
-    for_each_string!(
+    /// How often the supervisor task checks that everything is making progress
+    const SUPERVISE_PERIOD_MS: u32 = 100;
+
+    static DMAC_HEARTBEAT: watchdog::Heartbeat = watchdog::Heartbeat::new();
+    static FILL_BUFFER_HEARTBEAT: watchdog::Heartbeat = watchdog::Heartbeat::new();
+
+    /// DAC that drives the last string
+    #[cfg(not(feature = "analog-string"))]
+    type LastStringDac = pwm_dac::Channel<pac::TCC2, 1>;
+    #[cfg(feature = "analog-string")]
+    type LastStringDac = analog_dac::AnalogDac<pac::TC4>;
+
+    /// Sample type of the DAC that drives the last string
+    type LastStringSample = <LastStringDac as dac::Dac>::Sample;
+
+    seq!(N in 0..7 {
         pub struct DmaResources (
             #(string::dac_driver::DmaResources<u8>,)*
+            string::dac_driver::DmaResources<LastStringSample>,
         );
 
         impl DmaResources {
             pub const fn new() -> Self {
                 Self(
-                    #(string::dac_driver::DmaResources::new(),)*
+                    #(string::dac_driver::DmaResources::<u8>::new(),)*
+                    string::dac_driver::DmaResources::<LastStringSample>::new(),
                 )
             }
         }
-    );
+    });
 
-    pub struct Strings(
-        string::Controller<string::DacDriver<pwm_dac::Channel<pac::TCC0, 0>>>,
-        string::Controller<string::DacDriver<pwm_dac::Channel<pac::TCC0, 1>>>,
-        string::Controller<string::DacDriver<pwm_dac::Channel<pac::TCC0, 2>>>,
-        string::Controller<string::DacDriver<pwm_dac::Channel<pac::TCC0, 3>>>,
+    pub struct Controllers(
+        string::Controller<string::DacDriver<pwm_dac::Channel<pac::TCC0, 0, Tcc0Mode>>>,
+        string::Controller<string::DacDriver<pwm_dac::Channel<pac::TCC0, 1, Tcc0Mode>>>,
+        string::Controller<string::DacDriver<pwm_dac::Channel<pac::TCC0, 2, Tcc0Mode>>>,
+        string::Controller<string::DacDriver<pwm_dac::Channel<pac::TCC0, 3, Tcc0Mode>>>,
         string::Controller<string::DacDriver<pwm_dac::Channel<pac::TCC1, 0>>>,
         string::Controller<string::DacDriver<pwm_dac::Channel<pac::TCC1, 1>>>,
         string::Controller<string::DacDriver<pwm_dac::Channel<pac::TCC2, 0>>>,
-        string::Controller<string::DacDriver<pwm_dac::Channel<pac::TCC2, 1>>>,
+        string::Controller<string::DacDriver<LastStringDac>>,
     );
 
+    pub struct Strings {
+        controllers: Controllers,
+        /// Maximum sum of the amplitudes of all strings, which is proportional to the total
+        /// current drawn from the supply
+        power_budget: u16,
+    }
+
     impl Strings {
+        /// Our supply can handle about half of the strings at full amplitude
+        const DEFAULT_POWER_BUDGET: u16 = 4 * u8::MAX as u16;
+
         pub fn new(
-            dac_tcc0: pwm_dac::PwmDac<pac::TCC0>,
+            dac_tcc0: pwm_dac::PwmDac<pac::TCC0, Tcc0Mode>,
             dac_tcc1: pwm_dac::PwmDac<pac::TCC1>,
             dac_tcc2: pwm_dac::PwmDac<pac::TCC2>,
+            #[cfg(feature = "analog-string")] last_string_dac: LastStringDac,
             _freq_meter: ac::FrequencyMeter<pac::TC3>,
             dma: &mut samd_dma::DMAController<samd_dma::storage::Storage8>,
             dma_resources: &'static mut DmaResources,
@@ -98,8 +165,10 @@ mod app {
             let dac_tcc0 = dac_tcc0.split();
             let dac_tcc1 = dac_tcc1.split();
             let dac_tcc2 = dac_tcc2.split();
+            #[cfg(not(feature = "analog-string"))]
+            let last_string_dac = dac_tcc2.1;
 
-            Self(
+            let controllers = Controllers(
                 string::Controller::new(
                     string::DacDriver::new(
                         dac_tcc0.0,
@@ -186,7 +255,7 @@ mod app {
                 ),
                 string::Controller::new(
                     string::DacDriver::new(
-                        dac_tcc2.1,
+                        last_string_dac,
                         dma.take_channel::<samd_dma::consts::CH7>().unwrap(),
                         &mut dma_resources.7,
                     ),
@@ -196,20 +265,419 @@ mod app {
                         ..string::Config::default()
                     },
                 ),
-            )
+            );
+
+            Self {
+                controllers,
+                power_budget: Self::DEFAULT_POWER_BUDGET,
+            }
+        }
+
+        /// Whether any coil is being driven
+        pub fn is_driving(&self) -> bool {
+            let mut driving = false;
+            for_each_string!(
+                #(driving |= matches!(
+                    self.controllers.N.phase(),
+                    string::Phase::Attack | string::Phase::Sustain | string::Phase::Release
+                );)*
+            );
+            driving
+        }
+
+        pub fn mute_all(&mut self) {
+            for_each_string!(
+                #(self.controllers.N.mute();)*
+            );
+        }
+
+        /// Release a set of strings, given as a bitmask, returning when each string needs its
+        /// next envelope update
+        fn release(&mut self, strings: u8) -> [Option<rtc::Instant>; NUM_STRINGS as usize] {
+            let mut next = [None; NUM_STRINGS as usize];
+            for_each_string!(
+                #(if strings & 1 << N != 0 {
+                    next[N] = self.controllers.N.off(127);
+                })*
+            );
+            self.apply_power_budget();
+            next
+        }
+
+        pub fn set_target_periods(
+            &mut self,
+            periods: &[Option<hal::time::Nanoseconds>; NUM_STRINGS as usize],
+        ) {
+            for_each_string!(
+                #(self.controllers.N.set_target_period(periods[N]);)*
+            );
+        }
+
+        pub fn configs(&self) -> [string::Config; NUM_STRINGS as usize] {
+            let mut configs = [string::Config::default(); NUM_STRINGS as usize];
+            for_each_string!(
+                #(configs[N] = *self.controllers.N.config();)*
+            );
+            configs
+        }
+
+        /// Switch every string to a new configuration, except for its period, which tracks the
+        /// physical string. Strings that are playing ramp to their new amplitude over `ramp`.
+        pub fn set_configs(
+            &mut self,
+            configs: &[string::Config; NUM_STRINGS as usize],
+            ramp: rtc::Duration,
+        ) {
+            for_each_string!(
+                #(
+                    let config = string::Config {
+                        period: self.controllers.N.config().period,
+                        ..configs[N]
+                    };
+                    self.controllers.N.ramp_config(config, ramp);
+                )*
+            );
+            self.apply_power_budget();
+        }
+
+        pub fn power_budget(&self) -> u16 {
+            self.power_budget
+        }
+
+        pub fn set_power_budget(&mut self, budget: u16) {
+            self.power_budget = budget;
+            self.apply_power_budget();
+        }
+
+        /// Scale all strings by the same factor if their total amplitude exceeds the power
+        /// budget, which keeps the relative dynamics between them intact
+        fn apply_power_budget(&mut self) {
+            for_each_string!(
+                let total = 0 #(+ self.controllers.N.commanded_amplitude() as u32)*;
+                let scale = if total > self.power_budget as u32 {
+                    (self.power_budget as u32 * u8::MAX as u32 / total) as u8
+                } else {
+                    u8::MAX
+                };
+                #(self.controllers.N.set_power_scale(scale);)*
+            );
+        }
+    }
+
+    /// Faults reported by the TCCs since boot
+    #[derive(Default)]
+    pub struct FaultLog {
+        pub count: [u16; 3],
+        /// Last fault of each TCC since faults were cleared
+        pub last: [Option<pwm_dac::Fault>; 3],
+    }
+
+    impl FaultLog {
+        fn record(&mut self, tcc: u8, fault: pwm_dac::Fault) {
+            self.count[tcc as usize] = self.count[tcc as usize].saturating_add(1);
+            self.last[tcc as usize] = Some(fault);
+        }
+    }
+
+    /// Handles to the fault state of every TCC, for clearing faults on request
+    pub struct FaultMonitors {
+        tcc0: pwm_dac::FaultMonitor<pac::TCC0>,
+        tcc1: pwm_dac::FaultMonitor<pac::TCC1>,
+        tcc2: pwm_dac::FaultMonitor<pac::TCC2>,
+    }
+
+    impl FaultMonitors {
+        /// Release outputs held low by non-recoverable faults, returning whether all of them
+        /// were released
+        fn clear(&self) -> bool {
+            self.tcc0.clear();
+            self.tcc1.clear();
+            self.tcc2.clear();
+            !(self.tcc0.is_latched() || self.tcc1.is_latched() || self.tcc2.is_latched())
+        }
+    }
+
+    /// Note offs that couldn't be queued, which are coalesced per string so none are lost
+    pub struct PendingNoteOffs {
+        /// When the latest note off that still has to be applied was received
+        pending: [Option<rtc::Instant>; NUM_STRINGS as usize],
+        /// Note ons received before this were superseded by a note off that skipped the queue
+        superseded_before: [Option<rtc::Instant>; NUM_STRINGS as usize],
+    }
+
+    impl PendingNoteOffs {
+        pub const fn new() -> Self {
+            Self {
+                pending: [None; NUM_STRINGS as usize],
+                superseded_before: [None; NUM_STRINGS as usize],
+            }
+        }
+
+        fn push(&mut self, i: u8, received: rtc::Instant) {
+            self.pending[i as usize] = Some(received);
+            self.superseded_before[i as usize] = Some(received);
+        }
+
+        fn take(&mut self, i: u8) -> bool {
+            self.pending[i as usize].take().is_some()
+        }
+
+        /// Whether a note on that was waiting in the queue has already been turned off. The note
+        /// off skipped the queue, so it may be processed before the note on.
+        fn is_superseded(&self, i: u8, received: rtc::Instant) -> bool {
+            matches!(self.superseded_before[i as usize], Some(t) if received <= t)
+        }
+    }
+
+    /// Counts of events that couldn't be queued because a task queue was full
+    #[derive(Default)]
+    pub struct EventStats {
+        pub dropped_midi: u16,
+        /// Note offs that were applied outside the queue instead
+        pub deferred_note_offs: u16,
+        /// Envelope updates and arpeggio steps that couldn't be scheduled
+        pub dropped_updates: u16,
+        /// Bytes from the DIN MIDI input that were corrupted or lost
+        pub serial_errors: u16,
+        /// Messages that couldn't be relayed because an output was full
+        pub dropped_thru: u16,
+    }
+
+    /// Settings that apply to the whole instrument rather than a single string
+    #[derive(Default)]
+    pub struct Settings {
+        pub channel_mode: crate::midi::ChannelMode,
+        pub note_map: crate::midi::NoteMap,
+        pub tuning: tuning::Tuning,
+        /// How often to report status to the host, if at all
+        pub report_interval: Option<rtc::Duration>,
+    }
+
+    /// Values last reported to the host, so only changes are sent
+    pub struct Reports {
+        states: [Reported<string::Phase>; NUM_STRINGS as usize],
+        forced_releases: [Reported<u16>; NUM_STRINGS as usize],
+        thermal_limiting: [Reported<bool>; NUM_STRINGS as usize],
+        fault_counts: [Reported<u16>; 3],
+        last_faults: [Reported<u8>; 3],
+        event_stats: [Reported<u16>; 5],
+    }
+
+    impl Reports {
+        pub const fn new() -> Self {
+            Self {
+                states: [Reported::new(); NUM_STRINGS as usize],
+                forced_releases: [Reported::new(); NUM_STRINGS as usize],
+                thermal_limiting: [Reported::new(); NUM_STRINGS as usize],
+                fault_counts: [Reported::new(); 3],
+                last_faults: [Reported::new(); 3],
+                event_stats: [Reported::new(); 5],
+            }
+        }
+    }
+
+    /// RTC ticks in a second, for converting the times of sequence events without overflowing
+    const RTC_TICKS_PER_SEC: u64 = rtc::Duration::secs(1).ticks() as u64;
+
+    /// Instant that a sequence time falls on, given an instant and the sequence time at it
+    fn sequence_instant(anchor: (rtc::Instant, u64), time_us: u64) -> rtc::Instant {
+        let (instant, anchor_us) = anchor;
+        let ticks = time_us.saturating_sub(anchor_us) * RTC_TICKS_PER_SEC / 1_000_000;
+        instant + rtc::Duration::from_ticks(ticks as u32)
+    }
+
+    /// Ways to control playback of the sequence, from MIDI realtime messages or the button
+    #[derive(Clone, Copy)]
+    pub enum Transport {
+        /// Play from the beginning
+        Start,
+        /// Play from where playback stopped
+        Continue,
+        Stop,
+        /// Stop if playing, otherwise play from the beginning
+        Toggle,
+    }
+
+    /// Shortest pass of a looping sequence. Without it, a sequence with every event at the start
+    /// would loop forever without waiting for anything.
+    const MIN_LOOP_US: u64 = 10_000;
+
+    /// Playback of the uploaded or built in sequence. Events are scheduled one at a time on the
+    /// RTC, counted from an anchor that only moves when playback starts or loops, so rounding
+    /// doesn't add up over a long sequence.
+    pub struct Playback {
+        player: Option<smf::Player<'static>>,
+        /// Next event, waiting for its time to come
+        pending: Option<smf::Event>,
+        /// Instant and sequence time that events are scheduled from, while playing
+        anchor: Option<(rtc::Instant, u64)>,
+        /// Sequence time where playback stopped, so it can continue from there
+        stopped_us: u64,
+        /// Whether to start over when the sequence ends
+        pub looping: bool,
+        /// Advanced whenever playback starts or stops
+        generation: Generation,
+    }
+
+    impl Playback {
+        pub const fn new() -> Self {
+            Self {
+                player: None,
+                pending: None,
+                anchor: None,
+                stopped_us: 0,
+                looping: false,
+                generation: Generation::new(),
+            }
+        }
+
+        pub fn is_playing(&self) -> bool {
+            self.anchor.is_some()
+        }
+
+        /// Load the sequence and play it from the beginning. Returns the generation and due time
+        /// of the first event, if there is one.
+        fn start(
+            &mut self,
+            now: rtc::Instant,
+        ) -> Result<Option<(Generation, rtc::Instant)>, sysex::ErrorCode> {
+            let data = crate::midi::sequence::current().ok_or(sysex::ErrorCode::InvalidSequence)?;
+            let sequence =
+                smf::Sequence::parse(data).map_err(|_| sysex::ErrorCode::InvalidSequence)?;
+            self.unload();
+            self.player = Some(smf::Player::new(sequence));
+            Ok(self.resume(now))
+        }
+
+        /// Play from where playback stopped. Returns the generation and due time of the next
+        /// event, or `None` if nothing is left to play or it is already playing.
+        fn resume(&mut self, now: rtc::Instant) -> Option<(Generation, rtc::Instant)> {
+            if self.player.is_none() || self.is_playing() {
+                return None;
+            }
+            let generation = self.generation.advance();
+            let anchor = (now, self.stopped_us);
+            self.anchor = Some(anchor);
+            let t = match self.pending {
+                Some(event) => sequence_instant(anchor, event.time_us),
+                None => self.advance()?,
+            };
+            Some((generation, t))
+        }
+
+        /// Returns whether it was playing
+        fn stop(&mut self, now: rtc::Instant) -> bool {
+            let (anchor, anchor_us) = match self.anchor.take() {
+                Some(anchor) => anchor,
+                None => return false,
+            };
+            let elapsed = now
+                .checked_duration_since(anchor)
+                .map_or(0, |elapsed| elapsed.ticks() as u64);
+            self.stopped_us = anchor_us + elapsed * 1_000_000 / RTC_TICKS_PER_SEC;
+            self.generation.advance();
+            true
+        }
+
+        /// Forget the sequence, which has to be stopped first
+        fn unload(&mut self) {
+            self.player = None;
+            self.pending = None;
+            self.stopped_us = 0;
+        }
+
+        /// Read the next event, starting over at the end if looping. Returns when it is due, or
+        /// `None` once the sequence has ended.
+        fn advance(&mut self) -> Option<rtc::Instant> {
+            let player = self.player.as_mut()?;
+            let mut anchor = self.anchor?;
+            let mut event = player.next();
+            if event.is_none() && self.looping {
+                // The next pass starts when this one ends, which includes any time left after
+                // the last note
+                let length = player.position_us().max(MIN_LOOP_US);
+                anchor = (sequence_instant(anchor, length), 0);
+                player.rewind();
+                event = player.next();
+            }
+
+            match event {
+                Some(event) => {
+                    self.anchor = Some(anchor);
+                    self.pending = Some(event);
+                    Some(sequence_instant(anchor, event.time_us))
+                }
+                None => {
+                    self.anchor = None;
+                    self.unload();
+                    None
+                }
+            }
         }
     }
 
+    /// Longest SysEx message received, either our own or a MIDI Tuning Standard message
+    const MAX_SYSEX_RX_LEN: usize = if sysex::MAX_MESSAGE_LEN > tuning::MAX_MESSAGE_LEN {
+        sysex::MAX_MESSAGE_LEN
+    } else {
+        tuning::MAX_MESSAGE_LEN
+    };
+
+    type SysExRx = crate::midi::usb::SysExReceiver<MAX_SYSEX_RX_LEN>;
+
+    type MidiTx = crate::midi::thru::Output;
+
+    /// Standard MIDI baud rate
+    const DIN_BAUD_RATE: u32 = 31_250;
+
+    type DinTxPin = gpio::Pin<gpio::PA06, gpio::AlternateD>;
+
+    type DinUart =
+        uart::Uart<uart::Config<uart::Pads<Sercom0, bsp::UartRx, DinTxPin>>, uart::Duplex>;
+
+    /// Starts and stops the sequence, connecting D2 to ground when pressed
+    type ButtonPin = gpio::Pin<gpio::PA14, gpio::PullUpInput>;
+
+    /// How often the button is checked, which is also long enough to ignore it bouncing
+    const BUTTON_POLL_MS: u32 = 20;
+
     #[shared]
     struct Shared {
-        // uart_tx: uart::Uart<uart::Config<uart::Pads<Sercom0, NoneT, bsp::UartTx>>, uart::Tx>,
         strings: Strings,
+        fault_log: FaultLog,
+        pending_note_offs: PendingNoteOffs,
+        event_stats: EventStats,
+        settings: Settings,
+        sustain_pedal: crate::midi::control::SustainPedal,
+        midi_tx: crate::midi::thru::Output,
+        playback: Playback,
+        looper: crate::midi::looper::Looper,
+        arpeggiator: crate::midi::arp::Arpeggiator,
     }
 
     #[local]
     struct Local {
         usb_device: UsbDevice<'static, UsbBus>,
         usb_midi: usbd_midi::midi_device::MidiClass<'static, UsbBus>,
+        sysex_rx: SysExRx,
+        din_uart: DinUart,
+        din_parser: crate::midi::serial::Parser,
+        din_sysex_rx: SysExRx,
+        usb_relay: crate::midi::thru::Relay,
+        din_relay: crate::midi::thru::Relay,
+        reports: Reports,
+        parameter_numbers: crate::midi::control::ParameterNumbers,
+        nvm: crate::nvm::Nvm,
+        sequence_upload: Option<crate::midi::sequence::Upload>,
+        button: ButtonPin,
+        button_pressed: bool,
+        /// Report of a panic before the last reset, which is sent once the host is connected
+        panic_record: Option<panic::PanicRecord>,
+        watchdog: watchdog::Watchdog,
+        tcc0_faults: pwm_dac::FaultMonitor<pac::TCC0>,
+        tcc1_faults: pwm_dac::FaultMonitor<pac::TCC1>,
+        tcc2_faults: pwm_dac::FaultMonitor<pac::TCC2>,
+        fault_monitors: FaultMonitors,
     }
 
     #[monotonic(binds = RTC, default = true)]
@@ -223,30 +691,40 @@ mod app {
     fn init(cx: init::Context) -> (Shared, Local, init::Monotonics) {
         let mut peripherals: pac::Peripherals = cx.device;
 
+        let panic_record = panic::take_record();
+
         let mut clocks = GenericClockController::with_internal_32kosc(
             peripherals.GCLK,
             &mut peripherals.PM,
             &mut peripherals.SYSCTRL,
             &mut peripherals.NVMCTRL,
         );
+        let nvm = crate::nvm::Nvm::new(peripherals.NVMCTRL);
         let gclk0 = clocks.gclk0();
         let gclk1 = clocks.gclk1();
 
+        // 1.024 kHz for the watchdog
+        let gclk2 = clocks
+            .configure_gclk_divider_and_source(ClockGenId::GCLK2, 32, ClockSource::OSCULP32K, false)
+            .unwrap();
+
         let rtc_clock = clocks.rtc(&gclk1).unwrap();
         let rtc = rtc::Rtc::count32_mode(peripherals.RTC, rtc_clock.freq(), &mut peripherals.PM);
 
         let pins = bsp::Pins::new(peripherals.PORT);
 
-        // let uart_tx = {
-        //     let clock = &clocks.sercom0_core(&gclk0).unwrap();
-        //     let pads = uart::Pads::default().tx(pins.d1);
-        //     uart::Config::new(&peripherals.PM, peripherals.SERCOM0, pads, clock.freq())
-        //         .baud(
-        //             115200.hz(),
-        //             uart::BaudMode::Fractional(uart::Oversampling::Bits16),
-        //         )
-        //         .enable()
-        // };
+        // DIN MIDI input on D0 and output on A5. D1, the usual TX pin, drives string 4.
+        let mut din_uart = {
+            let clock = &clocks.sercom0_core(&gclk0).unwrap();
+            let pads = uart::Pads::default().rx(pins.d0).tx(pins.a5);
+            uart::Config::new(&peripherals.PM, peripherals.SERCOM0, pads, clock.freq())
+                .baud(
+                    DIN_BAUD_RATE.hz(),
+                    uart::BaudMode::Fractional(uart::Oversampling::Bits16),
+                )
+                .enable()
+        };
+        din_uart.enable_interrupts(uart::Flags::RXC);
 
         *cx.local.usb_allocator = Some(bsp::usb_allocator(
             peripherals.USB,
@@ -274,24 +752,37 @@ mod app {
         let tcc0_tcc1_clock = clocks.tcc0_tcc1(&gclk0).unwrap();
         let tcc2_tc3_clock = clocks.tcc2_tc3(&gclk0).unwrap();
 
-        let dac_tcc0 = pwm_dac::PwmDac::<pac::TCC0>::new(
+        #[cfg(not(feature = "bipolar-strings"))]
+        let mut dac_tcc0 = pwm_dac::PwmDac::<pac::TCC0>::new(
             &tcc0_tcc1_clock,
-            25.khz(),
+            PWM_DAC_PLAN,
             peripherals.TCC0,
             &mut peripherals.PM,
-        );
-        let dac_tcc1 = pwm_dac::PwmDac::<pac::TCC1>::new(
+        )
+        .unwrap();
+        #[cfg(feature = "bipolar-strings")]
+        let mut dac_tcc0 = pwm_dac::PwmDac::new_bipolar(
+            &tcc0_tcc1_clock,
+            PWM_DAC_PLAN,
+            TCC0_DEAD_TIME,
+            peripherals.TCC0,
+            &mut peripherals.PM,
+        )
+        .unwrap();
+        let mut dac_tcc1 = pwm_dac::PwmDac::<pac::TCC1>::new(
             &tcc0_tcc1_clock,
-            25.khz(),
+            PWM_DAC_PLAN,
             peripherals.TCC1,
             &mut peripherals.PM,
-        );
-        let dac_tcc2 = pwm_dac::PwmDac::<pac::TCC2>::new(
+        )
+        .unwrap();
+        let mut dac_tcc2 = pwm_dac::PwmDac::<pac::TCC2>::new(
             &tcc2_tc3_clock,
-            25.khz(),
+            PWM_DAC_PLAN,
             peripherals.TCC2,
             &mut peripherals.PM,
-        );
+        )
+        .unwrap();
 
         let _string_0_pin: gpio::Pin<_, gpio::AlternateE> = pins.d4.into_mode();
         let _string_1_pin: gpio::Pin<_, gpio::AlternateE> = pins.d3.into_mode();
@@ -300,12 +791,48 @@ mod app {
         let _string_4_pin: gpio::Pin<_, gpio::AlternateE> = pins.d1.into_mode();
         let _string_5_pin: gpio::Pin<_, gpio::AlternateE> = pins.d9.into_mode();
         let _string_6_pin: gpio::Pin<_, gpio::AlternateE> = pins.d11.into_mode();
+        #[cfg(not(feature = "analog-string"))]
         let _string_7_pin: gpio::Pin<_, gpio::AlternateE> = pins.d13.into_mode();
+        #[cfg(feature = "analog-string")]
+        let _string_7_pin: gpio::Pin<_, gpio::AlternateB> = pins.a0.into_mode();
+
+        #[cfg(feature = "analog-string")]
+        let analog_dac = analog_dac::AnalogDac::<pac::TC4>::new(
+            &clocks.dac(&gclk0).unwrap(),
+            &clocks.tc4_tc5(&gclk0).unwrap(),
+            PWM_DAC_PLAN.sample_rate().hz(),
+            peripherals.DAC,
+            peripherals.TC4,
+            &mut peripherals.PM,
+        );
 
         let _ac_pos_pin: gpio::Pin<_, gpio::AlternateB> = pins.a3.into_mode();
         let _ac_neg_pin: gpio::Pin<_, gpio::AlternateB> = pins.a4.into_mode();
         let _ac_comp_pin: gpio::Pin<_, gpio::AlternateH> = pins.miso.into_mode();
 
+        // Low sides of the TCC0 H-bridges, on WO[4] to WO[7]
+        #[cfg(feature = "bipolar-strings")]
+        let _string_low_pins: (
+            gpio::Pin<_, gpio::AlternateF>,
+            gpio::Pin<_, gpio::AlternateF>,
+            gpio::Pin<_, gpio::AlternateF>,
+            gpio::Pin<_, gpio::AlternateF>,
+        ) = (
+            pins.sda.into_mode(),
+            pins.scl.into_mode(),
+            pins.d6.into_mode(),
+            pins.d7.into_mode(),
+        );
+
+        // Output of the external overcurrent comparator, on EXTINT5, or EXTINT15 if D7 is
+        // driving a bridge
+        #[cfg(not(feature = "bipolar-strings"))]
+        let _fault_pin: gpio::Pin<_, gpio::AlternateA> = pins.d7.into_mode();
+        #[cfg(feature = "bipolar-strings")]
+        let _fault_pin: gpio::Pin<_, gpio::AlternateA> = pins.d5.into_mode();
+
+        let button: ButtonPin = pins.d2.into_mode();
+
         let evsys = evsys::EventSystem::new(peripherals.EVSYS, &peripherals.PM).split();
 
         let _ac = ac::AnalogComparator::new(
@@ -322,156 +849,1821 @@ mod app {
         evsys_ac_channel.user(evsys::User::Tc3);
         evsys_ac_channel.config(evsys::Path::ASYNCHRONOUS, evsys::EventGenerator::AcComp0);
 
-        let strings = Strings::new(
+        // Route overcurrent faults to all TCCs asynchronously, so the outputs are shut off even
+        // if the CPU is stuck
+        let eic = eic::ExternalInterruptController::new(
+            clocks.eic(&gclk0).unwrap(),
+            peripherals.EIC,
+            &peripherals.PM,
+        );
+        eic.enable_event(FAULT_EXTINT.0, eic::Sense::HIGH);
+
+        let evsys_fault_channel = evsys.1;
+        evsys_fault_channel.user(evsys::User::Tcc0Ev1);
+        evsys_fault_channel.user(evsys::User::Tcc1Ev1);
+        evsys_fault_channel.user(evsys::User::Tcc2Ev1);
+        evsys_fault_channel.config(evsys::Path::ASYNCHRONOUS, FAULT_EXTINT.1);
+
+        dac_tcc0.enable_fault(pwm_dac::Fault::NonRecoverable);
+        dac_tcc1.enable_fault(pwm_dac::Fault::NonRecoverable);
+        dac_tcc2.enable_fault(pwm_dac::Fault::NonRecoverable);
+        let tcc0_faults = dac_tcc0.fault_monitor();
+        let tcc1_faults = dac_tcc1.fault_monitor();
+        let tcc2_faults = dac_tcc2.fault_monitor();
+        let fault_monitors = FaultMonitors {
+            tcc0: dac_tcc0.fault_monitor(),
+            tcc1: dac_tcc1.fault_monitor(),
+            tcc2: dac_tcc2.fault_monitor(),
+        };
+
+        let mut strings = Strings::new(
             dac_tcc0,
             dac_tcc1,
             dac_tcc2,
+            #[cfg(feature = "analog-string")]
+            analog_dac,
             freq,
             &mut dma,
             cx.local.dma_resources,
         );
+        let settings = Settings::default();
+        strings.set_target_periods(&settings.tuning.string_periods(&settings.note_map));
+
+        // Muting happens on the early warning after 1 s without progress, and the reset a second
+        // later
+        let mut watchdog =
+            watchdog::Watchdog::new(clocks.wdt(&gclk2).unwrap(), peripherals.WDT);
+        watchdog.start(watchdog::Period::CYC2048, watchdog::EarlyWarningOffset::CYC1024);
+        supervise::spawn().unwrap();
+        poll_button::spawn().unwrap();
 
         (
-            Shared { strings },
+            Shared {
+                strings,
+                fault_log: FaultLog::default(),
+                pending_note_offs: PendingNoteOffs::new(),
+                event_stats: EventStats::default(),
+                settings,
+                sustain_pedal: crate::midi::control::SustainPedal::new(),
+                midi_tx: crate::midi::thru::Output::new(),
+                playback: Playback::new(),
+                looper: crate::midi::looper::Looper::new(),
+                arpeggiator: crate::midi::arp::Arpeggiator::new(),
+            },
             Local {
                 usb_device,
                 usb_midi,
+                sysex_rx: SysExRx::new(),
+                din_uart,
+                din_parser: crate::midi::serial::Parser::new(0),
+                din_sysex_rx: SysExRx::new(),
+                usb_relay: crate::midi::thru::Relay::usb_to_din(),
+                din_relay: crate::midi::thru::Relay::din_to_usb(),
+                reports: Reports::new(),
+                parameter_numbers: crate::midi::control::ParameterNumbers::new(),
+                nvm,
+                sequence_upload: None,
+                button,
+                button_pressed: false,
+                panic_record,
+                watchdog,
+                tcc0_faults,
+                tcc1_faults,
+                tcc2_faults,
+                fault_monitors,
             },
             init::Monotonics(rtc),
         )
     }
 
+    /// Schedule an envelope update. If the queue is full, the update is only delayed, because
+    /// the supervisor regularly updates every string anyway.
+    fn schedule_update(
+        event_stats: &mut impl rtic::Mutex<T = EventStats>,
+        t: rtc::Instant,
+        i: u8,
+        harmonic: u8,
+    ) {
+        if update_string::spawn_at(t, i, harmonic).is_err() {
+            event_stats.lock(|stats| {
+                stats.dropped_updates = stats.dropped_updates.wrapping_add(1)
+            });
+        }
+    }
+
     #[task(
-        shared = [strings],
+        shared = [strings, event_stats],
         capacity = 8
     )]
     fn update_string(mut cx: update_string::Context, i: u8, harmonic: u8) {
-        string_i_lock!(cx, i, |string: &mut string::Controller<_>| {
-            if let Some(t) = string.update() {
-                update_string::spawn_at(t, i, harmonic).ok();
-            }
-        });
+        let mut next = None;
+        string_i_lock!(cx, i, |string: &mut string::Controller<_>| next = string.update());
+        if let Some(t) = next {
+            schedule_update(&mut cx.shared.event_stats, t, i, harmonic);
+        }
     }
 
     fn msg_to_note(msg: &midi::message::Message) -> Option<midi::notes::Note> {
         match msg {
             midi::message::Message::NoteOn(_, note, _) => Some(*note),
             midi::message::Message::NoteOff(_, note, _) => Some(*note),
+            midi::message::Message::PolyphonicAftertouch(_, note, _) => Some(*note),
             _ => None,
         }
     }
 
-    fn note_to_string(note: midi::notes::Note) -> Option<(u8, u8)> {
-        use midi::notes::Note::*;
-        match note.into() {
-            G4 => Some((0, 1)),
-            A4 => Some((1, 1)),
-            B4 => Some((2, 1)),
-            C5 => Some((3, 1)),
-            D5 => Some((4, 1)),
-            E5 => Some((5, 1)),
-            F5 => Some((6, 1)),
-            G5 => Some((7, 1)),
-            A5 => Some((1, 2)),
-            B5 => Some((2, 2)),
-            C6 => Some((3, 2)),
-            D6 => Some((4, 2)),
-            E6 => Some((5, 2)),
-            F6 => Some((6, 2)),
-            G6 => Some((7, 2)),
-            A6 => Some((1, 3)),
-            B6 => Some((2, 3)),
-            C7 => Some((3, 3)),
-            D7 => Some((4, 3)),
-            E7 => Some((5, 3)),
-            F7 => Some((6, 3)),
-            G7 => Some((7, 3)),
-            _ => None,
+    fn msg_channel(msg: &midi::message::Message) -> Option<u8> {
+        use midi::message::Message::*;
+        match msg {
+            NoteOff(channel, ..)
+            | NoteOn(channel, ..)
+            | PolyphonicAftertouch(channel, ..)
+            | ProgramChange(channel, ..)
+            | ChannelAftertouch(channel, ..)
+            | PitchWheelChange(channel, ..)
+            | ControlChange(channel, ..) => Some(*channel as u8),
         }
     }
 
-    #[task(
-        shared = [strings],
-        capacity = 16
-    )]
-    fn handle_midi(mut cx: handle_midi::Context, msg: midi::message::Message) {
-        if let Some((i, harmonic)) = msg_to_note(&msg).and_then(note_to_string) {
-            string_i_lock!(cx, i, |string: &mut string::Controller<_>| {
-                if let Some(t) = match msg {
-                    midi::message::Message::NoteOn(_, _, velocity) => {
-                        string.on(velocity.into(), harmonic)
+    /// Map a note message to a string and harmonic, if the string listens on its channel
+    fn msg_to_string(msg: &midi::message::Message, settings: &Settings) -> Option<(u8, u8)> {
+        let channel = msg_channel(msg)?;
+        msg_to_note(msg).and_then(|note| note_to_string(channel, note as u8, settings))
+    }
+
+    /// Map a note on a channel to a string and harmonic, if the string listens on the channel
+    fn note_to_string(channel: u8, note: u8, settings: &Settings) -> Option<(u8, u8)> {
+        settings
+            .note_map
+            .get(note)
+            .filter(|&(i, _)| settings.channel_mode.accepts_note(channel, i))
+    }
+
+    /// Many controllers send a note on with zero velocity instead of a note off
+    fn is_note_off(msg: &midi::message::Message) -> bool {
+        match msg {
+            midi::message::Message::NoteOff(..) => true,
+            midi::message::Message::NoteOn(_, _, velocity) => u8::from(*velocity) == 0,
+            _ => false,
+        }
+    }
+
+    /// Strings that a message turns off, as a bitmask. Besides note offs, this includes the
+    /// channel mode messages, which all imply all notes off.
+    fn note_off_strings(msg: &midi::message::Message, settings: &Settings) -> u8 {
+        use crate::midi::control;
+        match msg {
+            midi::message::Message::ControlChange(channel, function, _) => {
+                match u8::from(function.0) {
+                    control::ALL_SOUND_OFF | control::ALL_NOTES_OFF..=control::POLY_MODE_ON => {
+                        settings.channel_mode.strings(*channel as u8)
                     }
-                    midi::message::Message::NoteOff(_, _, _velocity) => string.off(127),
-                    _ => None,
-                } {
-                    update_string::spawn_at(t, i, harmonic).ok();
+                    _ => 0,
                 }
-            });
+            }
+            msg if is_note_off(msg) => msg_to_string(msg, settings).map_or(0, |(i, _)| 1 << i),
+            _ => 0,
         }
     }
 
-    #[task(
-        shared = [strings],
-        capacity = 8
-    )]
-    fn fill_buffer(
-        mut cx: fill_buffer::Context,
-        string: u8,
-        buffer: string::dac_driver::FillableBuffer<u8>,
+    /// Indices of the strings in a bitmask
+    fn string_indices(strings: u8) -> impl Iterator<Item = u8> {
+        (0..NUM_STRINGS).filter(move |i| strings & (1 << i) != 0)
+    }
+
+    /// Release the notes playing on a set of strings, given as a bitmask
+    fn release_strings(
+        strings: &mut impl rtic::Mutex<T = Strings>,
+        event_stats: &mut impl rtic::Mutex<T = EventStats>,
+        released: u8,
     ) {
-        let buffer = buffer.fill();
+        let next = strings.lock(|strings| strings.release(released));
+        schedule_releases(event_stats, next);
+    }
 
-        string_i_lock!(cx, string, |string: &mut string::Controller<
-            string::DacDriver<_>,
-        >| {
-            // https://github.com/rust-lang/rust/issues/42574
-            let buffer = buffer;
-            string.driver_mut().submit(buffer)
+    /// Release the string an arpeggiated note is playing on, unless the sustain pedal holds it
+    fn release_arp_note(
+        strings: &mut impl rtic::Mutex<T = Strings>,
+        sustain_pedal: &mut impl rtic::Mutex<T = crate::midi::control::SustainPedal>,
+        settings: &mut impl rtic::Mutex<T = Settings>,
+        event_stats: &mut impl rtic::Mutex<T = EventStats>,
+        note: crate::midi::arp::Note,
+    ) {
+        let mapping = settings.lock(|settings| note_to_string(note.channel, note.note, settings));
+        let released = mapping.map_or(0, |(i, _)| {
+            sustain_pedal.lock(|pedal| pedal.note_off(1 << i))
         });
+        release_strings(strings, event_stats, released);
     }
 
-    #[task(
-        binds = DMAC,
-        shared = [strings],
-        priority = 3
-    )]
-    fn dmac_interrupt(mut cx: dmac_interrupt::Context) {
-        for i in 0..NUM_STRINGS {
-            let mut buffer = None;
+    /// Stop the arpeggiator and release the note it left sounding, which may be on a string
+    /// outside the ones being turned off
+    fn stop_arpeggiator(cx: &mut handle_midi::Context) {
+        if let Some(note) = cx.shared.arpeggiator.lock(|arp| arp.stop()) {
+            release_arp_note(
+                &mut cx.shared.strings,
+                &mut cx.shared.sustain_pedal,
+                &mut cx.shared.settings,
+                &mut cx.shared.event_stats,
+                note,
+            );
+        }
+    }
 
-            string_i_lock!(cx, i, |string: &mut string::Controller<
-                string::DacDriver<_>,
-            >| buffer = string.driver_mut().request());
-            if let Some(buffer) = buffer {
-                fill_buffer::spawn(i, buffer).ok();
+    /// Set the pressure applied to a set of strings, given as a bitmask
+    fn set_pressure(cx: &mut handle_midi::Context, strings: u8, pressure: u8) {
+        for i in string_indices(strings) {
+            string_i_lock!(cx, i, |string: &mut string::Controller<_>| {
+                string.set_pressure(pressure)
+            });
+        }
+    }
+
+    /// Set a controller used for bowing on a set of strings, given as a bitmask
+    fn set_bowing_controller(
+        cx: &mut handle_midi::Context,
+        strings: u8,
+        controller: string::BowingController,
+        value: u8,
+    ) {
+        for i in string_indices(strings) {
+            string_i_lock!(cx, i, |string: &mut string::Controller<_>| {
+                string.set_bowing_controller(controller, value)
+            });
+        }
+    }
+
+    /// Handle a control change affecting a set of strings, given as a bitmask
+    fn handle_control_change(cx: &mut handle_midi::Context, strings: u8, control: u8, value: u8) {
+        use crate::midi::control;
+        match control {
+            control::BREATH_CONTROLLER => {
+                set_bowing_controller(cx, strings, string::BowingController::Breath, value)
+            }
+            control::EXPRESSION_CONTROLLER => {
+                set_bowing_controller(cx, strings, string::BowingController::Expression, value)
+            }
+            control::SUSTAIN_PEDAL if value >= 64 => {
+                cx.shared.sustain_pedal.lock(|pedal| pedal.press(strings))
+            }
+            // Resetting all controllers releases the pressure, returns the bowing controllers to
+            // their defaults and lifts the sustain pedal
+            control::SUSTAIN_PEDAL | control::RESET_ALL_CONTROLLERS => {
+                if control == control::RESET_ALL_CONTROLLERS {
+                    set_pressure(cx, strings, 0);
+                    set_bowing_controller(cx, strings, string::BowingController::Breath, 0);
+                    set_bowing_controller(cx, strings, string::BowingController::Expression, 127);
+                }
+                let released = cx.shared.sustain_pedal.lock(|pedal| pedal.release(strings));
+                release_strings(&mut cx.shared.strings, &mut cx.shared.event_stats, released);
             }
+            control::LOOPER_RECORD..=control::LOOPER_CLEAR if value >= 64 && strings != 0 => {
+                handle_looper(cx, control)
+            }
+            control::ALL_SOUND_OFF => {
+                if strings != 0 {
+                    stop_arpeggiator(cx);
+                }
+                cx.shared.sustain_pedal.lock(|pedal| pedal.mute(strings));
+                for i in string_indices(strings) {
+                    string_i_lock!(cx, i, |string: &mut string::Controller<_>| string.mute());
+                }
+            }
+            // Omni and mono/poly mode changes are only honored for their implied all notes off,
+            // since the channel mode is configured over SysEx instead. The arpeggiator lets go of
+            // its notes too, in case a note off never arrived, unless the channel has no strings.
+            control::ALL_NOTES_OFF..=control::POLY_MODE_ON => {
+                if strings != 0 {
+                    stop_arpeggiator(cx);
+                }
+                let released = cx.shared.sustain_pedal.lock(|pedal| pedal.note_off(strings));
+                release_strings(&mut cx.shared.strings, &mut cx.shared.event_stats, released);
+            }
+            _ => {}
         }
     }
 
-    #[task(binds = TC3, shared = [strings])]
-    fn freq_interrupt(mut cx: freq_interrupt::Context) {
-        cx.shared
-            .strings
-            .lock(|strings| strings.1.sample_frequency());
+    /// Point the frequency measurements of each string at the period the notes mapped to it are
+    /// tuned to
+    fn update_target_periods(
+        strings: &mut impl rtic::Mutex<T = Strings>,
+        settings: &mut impl rtic::Mutex<T = Settings>,
+    ) {
+        let periods = settings.lock(|settings| settings.tuning.string_periods(&settings.note_map));
+        strings.lock(|strings| strings.set_target_periods(&periods));
     }
 
-    #[task(binds = USB, local = [usb_device, usb_midi], priority = 2)]
-    fn usb_interrupt(cx: usb_interrupt::Context) {
-        let usb_device: &mut UsbDevice<UsbBus> = cx.local.usb_device;
-        let usb_midi: &mut usbd_midi::midi_device::MidiClass<_> = cx.local.usb_midi;
+    /// Handle data entry for a registered parameter
+    fn handle_registered_parameter(cx: &mut handle_midi::Context, parameter: u16, value: u16) {
+        let retuned = cx.shared.settings.lock(|settings| match parameter {
+            tuning::MASTER_FINE_TUNING => {
+                settings.tuning.set_master_fine(value);
+                true
+            }
+            // Only the MSB is used
+            tuning::MASTER_COARSE_TUNING => {
+                settings.tuning.set_master_coarse((value >> 7) as u8);
+                true
+            }
+            _ => false,
+        });
+        if retuned {
+            update_target_periods(&mut cx.shared.strings, &mut cx.shared.settings);
+        }
+    }
 
-        if !usb_device.poll(&mut [usb_midi]) {
-            return;
+    /// How long strings that are playing take to reach their amplitude in a new preset
+    const PRESET_RAMP_MS: u32 = 200;
+
+    /// Switch to a stored preset. Notes that are playing carry on with their new configuration,
+    /// unless the note map or channel mode changes. Their note offs might not reach them anymore
+    /// then, so they are released.
+    fn load_preset(
+        strings: &mut impl rtic::Mutex<T = Strings>,
+        settings: &mut impl rtic::Mutex<T = Settings>,
+        sustain_pedal: &mut impl rtic::Mutex<T = crate::midi::control::SustainPedal>,
+        event_stats: &mut impl rtic::Mutex<T = EventStats>,
+        index: u8,
+    ) -> Result<(), sysex::ErrorCode> {
+        let preset = crate::midi::preset::Preset::read(index)?;
+
+        let remapped = settings.lock(|settings| {
+            let remapped = settings.note_map != preset.note_map
+                || settings.channel_mode != preset.channel_mode;
+            settings.note_map = preset.note_map;
+            settings.channel_mode = preset.channel_mode;
+            remapped
+        });
+
+        if remapped {
+            update_target_periods(strings, settings);
         }
 
-        let mut buffer = [0; 64];
-        if let Ok(size) = usb_midi.read(&mut buffer) {
-            let buffer_reader = MidiPacketBufferReader::new(&buffer, size);
-            for packet in buffer_reader.into_iter() {
-                if let Ok(packet) = packet {
-                    handle_midi::spawn(packet.message).ok();
-                }
+        let next = strings.lock(|strings| {
+            strings.set_configs(&preset.configs, rtc::Duration::millis(PRESET_RAMP_MS));
+            if remapped {
+                strings.release(u8::MAX)
+            } else {
+                [None; NUM_STRINGS as usize]
+            }
+        });
+        if remapped {
+            sustain_pedal.lock(|pedal| pedal.mute(u8::MAX));
+        }
+        schedule_releases(event_stats, next);
+        Ok(())
+    }
+
+    /// Schedule the envelope updates of strings that were released all at once
+    fn schedule_releases(
+        event_stats: &mut impl rtic::Mutex<T = EventStats>,
+        next: [Option<rtc::Instant>; NUM_STRINGS as usize],
+    ) {
+        for (i, t) in (0..NUM_STRINGS).zip(next) {
+            if let Some(t) = t {
+                // Harmonic doesn't matter when releasing
+                schedule_update(event_stats, t, i, 1);
             }
         }
     }
+
+    /// Start the task that plays the sequence, if playback just started
+    fn schedule_playback(started: Option<(Generation, rtc::Instant)>) {
+        if let Some((generation, t)) = started {
+            play_sequence::spawn_at(t, generation).ok();
+        }
+    }
+
+    /// Release every string and mute the sustain pedal, for when playback stops and might have
+    /// left notes playing
+    fn release_all_strings(
+        strings: &mut impl rtic::Mutex<T = Strings>,
+        sustain_pedal: &mut impl rtic::Mutex<T = crate::midi::control::SustainPedal>,
+        event_stats: &mut impl rtic::Mutex<T = EventStats>,
+    ) {
+        release_remapped_strings(strings, sustain_pedal, event_stats, u8::MAX);
+    }
+
+    /// Release a set of strings, given as a bitmask, and mute the sustain pedal on them, for when
+    /// the note offs of the notes playing on them would no longer reach them
+    fn release_remapped_strings(
+        strings: &mut impl rtic::Mutex<T = Strings>,
+        sustain_pedal: &mut impl rtic::Mutex<T = crate::midi::control::SustainPedal>,
+        event_stats: &mut impl rtic::Mutex<T = EventStats>,
+        released: u8,
+    ) {
+        let next = strings.lock(|strings| strings.release(released));
+        sustain_pedal.lock(|pedal| pedal.mute(released));
+        schedule_releases(event_stats, next);
+    }
+
+    /// Stop playing the sequence, releasing every string in case it left notes playing
+    fn stop_sequence(
+        playback: &mut impl rtic::Mutex<T = Playback>,
+        strings: &mut impl rtic::Mutex<T = Strings>,
+        sustain_pedal: &mut impl rtic::Mutex<T = crate::midi::control::SustainPedal>,
+        event_stats: &mut impl rtic::Mutex<T = EventStats>,
+    ) {
+        if playback.lock(|playback| playback.stop(monotonics::now())) {
+            release_all_strings(strings, sustain_pedal, event_stats);
+        }
+    }
+
+    /// Play the sequence from the beginning, stopping it first if it is already playing
+    fn start_sequence(
+        playback: &mut impl rtic::Mutex<T = Playback>,
+        strings: &mut impl rtic::Mutex<T = Strings>,
+        sustain_pedal: &mut impl rtic::Mutex<T = crate::midi::control::SustainPedal>,
+        event_stats: &mut impl rtic::Mutex<T = EventStats>,
+    ) -> Result<(), sysex::ErrorCode> {
+        stop_sequence(playback, strings, sustain_pedal, event_stats);
+        let started = playback.lock(|playback| playback.start(monotonics::now()))?;
+        schedule_playback(started);
+        Ok(())
+    }
+
+    /// Play the events of the sequence that are due, then schedule the next one. Events are
+    /// handled like MIDI input, so they follow the note map and channel mode.
+    #[task(
+        shared = [playback, strings, sustain_pedal, pending_note_offs, event_stats, settings],
+        capacity = 4
+    )]
+    fn play_sequence(mut cx: play_sequence::Context, generation: Generation) {
+        loop {
+            let due = cx.shared.playback.lock(|playback| {
+                if !playback.generation.is_current(generation) {
+                    return None;
+                }
+                let event = playback.pending.take()?;
+                Some((event, playback.advance()))
+            });
+            let (event, next) = match due {
+                Some(due) => due,
+                None => return,
+            };
+
+            let now = monotonics::now();
+            dispatch_event(
+                event.message,
+                now,
+                &mut cx.shared.pending_note_offs,
+                &mut cx.shared.event_stats,
+                &mut cx.shared.settings,
+            );
+
+            match next {
+                // Events at the same time, like the notes of a chord, are played together
+                Some(t) if t <= now => {}
+                Some(t) => {
+                    if play_sequence::spawn_at(t, generation).is_err() {
+                        stop_sequence(
+                            &mut cx.shared.playback,
+                            &mut cx.shared.strings,
+                            &mut cx.shared.sustain_pedal,
+                            &mut cx.shared.event_stats,
+                        );
+                    }
+                    return;
+                }
+                None => return,
+            }
+        }
+    }
+
+    #[task(
+        shared = [playback, strings, sustain_pedal, event_stats],
+        capacity = 2
+    )]
+    fn control_playback(mut cx: control_playback::Context, transport: Transport) {
+        let playing = cx.shared.playback.lock(|playback| playback.is_playing());
+        match transport {
+            Transport::Stop => stop_sequence(
+                &mut cx.shared.playback,
+                &mut cx.shared.strings,
+                &mut cx.shared.sustain_pedal,
+                &mut cx.shared.event_stats,
+            ),
+            Transport::Toggle if playing => stop_sequence(
+                &mut cx.shared.playback,
+                &mut cx.shared.strings,
+                &mut cx.shared.sustain_pedal,
+                &mut cx.shared.event_stats,
+            ),
+            Transport::Start | Transport::Toggle => {
+                // Ignored if there is no sequence
+                start_sequence(
+                    &mut cx.shared.playback,
+                    &mut cx.shared.strings,
+                    &mut cx.shared.sustain_pedal,
+                    &mut cx.shared.event_stats,
+                )
+                .ok();
+            }
+            Transport::Continue => {
+                let started = cx
+                    .shared
+                    .playback
+                    .lock(|playback| playback.resume(monotonics::now()));
+                schedule_playback(started);
+            }
+        }
+    }
+
+    /// Handle a press of one of the looper's buttons
+    fn handle_looper(cx: &mut handle_midi::Context, control: u8) {
+        use crate::midi::looper::Change;
+        let (change, generation) = cx.shared.looper.lock(|looper| {
+            let change = looper.control(control, monotonics::now());
+            (change, looper.generation())
+        });
+        match change {
+            Change::Started => {
+                play_loop::spawn(generation).ok();
+            }
+            Change::Stopped => release_all_strings(
+                &mut cx.shared.strings,
+                &mut cx.shared.sustain_pedal,
+                &mut cx.shared.event_stats,
+            ),
+            Change::None => {}
+        }
+    }
+
+    /// Play the events of the loop that are due, then schedule the next one. Like the sequence,
+    /// events are handled like MIDI input.
+    #[task(
+        shared = [looper, strings, sustain_pedal, pending_note_offs, event_stats, settings],
+        capacity = 4
+    )]
+    fn play_loop(mut cx: play_loop::Context, generation: Generation) {
+        loop {
+            let now = monotonics::now();
+            let due = cx.shared.looper.lock(|looper| {
+                if !looper.generation().is_current(generation) {
+                    return Err(None);
+                }
+                match looper.next_due() {
+                    Some(t) if t <= now => Ok(looper.take_due()),
+                    t => Err(t),
+                }
+            });
+            match due {
+                Ok(Some(message)) => dispatch_event(
+                    message,
+                    now,
+                    &mut cx.shared.pending_note_offs,
+                    &mut cx.shared.event_stats,
+                    &mut cx.shared.settings,
+                ),
+                // The end of a pass, so the next one starts
+                Ok(None) => {}
+                Err(Some(t)) => {
+                    if play_loop::spawn_at(t, generation).is_err() {
+                        cx.shared.looper.lock(|looper| looper.stop());
+                        release_all_strings(
+                            &mut cx.shared.strings,
+                            &mut cx.shared.sustain_pedal,
+                            &mut cx.shared.event_stats,
+                        );
+                    }
+                    return;
+                }
+                Err(None) => return,
+            }
+        }
+    }
+
+    /// Toggle playback when the button is pressed
+    #[task(local = [button, button_pressed])]
+    fn poll_button(cx: poll_button::Context) {
+        let pressed = cx.local.button.is_low().unwrap();
+        if pressed && !*cx.local.button_pressed {
+            control_playback::spawn(Transport::Toggle).ok();
+        }
+        *cx.local.button_pressed = pressed;
+
+        poll_button::spawn_after(rtc::Duration::millis(BUTTON_POLL_MS)).ok();
+    }
+
+    #[task(
+        local = [parameter_numbers],
+        shared = [
+            strings,
+            pending_note_offs,
+            event_stats,
+            settings,
+            sustain_pedal,
+            looper,
+            arpeggiator,
+        ],
+        capacity = 16
+    )]
+    fn handle_midi(
+        mut cx: handle_midi::Context,
+        msg: midi::message::Message,
+        received: rtc::Instant,
+    ) {
+        if let midi::message::Message::ControlChange(channel, function, value) = msg {
+            let strings = cx
+                .shared
+                .settings
+                .lock(|settings| settings.channel_mode.strings(channel as u8));
+            let (control, value) = (u8::from(function.0), u8::from(value));
+            // Registered parameters are tracked on every channel, but only channels with strings
+            // listening can change them
+            let entry = cx
+                .local
+                .parameter_numbers
+                .control_change(channel as u8, control, value);
+            if let Some((parameter, value)) = entry.filter(|_| strings != 0) {
+                handle_registered_parameter(&mut cx, parameter, value);
+            }
+            handle_control_change(&mut cx, strings, control, value);
+            return;
+        }
+
+        if let midi::message::Message::ProgramChange(channel, program) = msg {
+            let accepted = cx
+                .shared
+                .settings
+                .lock(|settings| settings.channel_mode.strings(channel as u8) != 0);
+            if accepted {
+                // Programs without a stored preset are ignored
+                load_preset(
+                    &mut cx.shared.strings,
+                    &mut cx.shared.settings,
+                    &mut cx.shared.sustain_pedal,
+                    &mut cx.shared.event_stats,
+                    program.into(),
+                )
+                .ok();
+            }
+            return;
+        }
+
+        if let midi::message::Message::ChannelAftertouch(channel, pressure) = msg {
+            let strings = cx
+                .shared
+                .settings
+                .lock(|settings| settings.channel_mode.strings(channel as u8));
+            set_pressure(&mut cx, strings, pressure.into());
+            return;
+        }
+
+        if route_note(&mut cx, &msg, received) {
+            return;
+        }
+
+        let mapping = cx.shared.settings.lock(|settings| msg_to_string(&msg, settings));
+        if let Some((i, harmonic)) = mapping {
+            if is_note_off(&msg) {
+                let released = cx.shared.sustain_pedal.lock(|pedal| pedal.note_off(1 << i));
+                release_strings(&mut cx.shared.strings, &mut cx.shared.event_stats, released);
+                return;
+            }
+
+            if let midi::message::Message::PolyphonicAftertouch(_, _, pressure) = msg {
+                set_pressure(&mut cx, 1 << i, pressure.into());
+                return;
+            }
+
+            let superseded = cx
+                .shared
+                .pending_note_offs
+                .lock(|pending| pending.is_superseded(i, received));
+            if superseded {
+                return;
+            }
+            cx.shared.sustain_pedal.lock(|pedal| pedal.note_on(i));
+
+            let mut next = None;
+            string_i_lock!(cx, i, |string: &mut string::Controller<_>| {
+                next = match msg {
+                    midi::message::Message::NoteOn(_, _, velocity) => {
+                        string.on(velocity.into(), harmonic)
+                    }
+                    _ => None,
+                };
+            });
+            if let Some(t) = next {
+                schedule_update(&mut cx.shared.event_stats, t, i, harmonic);
+            }
+        }
+    }
+
+    /// Pass a note on or off through the arpeggiator, which sits in front of the note map.
+    /// Returns whether the arpeggiator took care of the note.
+    fn route_note(
+        cx: &mut handle_midi::Context,
+        msg: &midi::message::Message,
+        received: rtc::Instant,
+    ) -> bool {
+        use crate::midi::arp::{Note, Route};
+        let note = match *msg {
+            midi::message::Message::NoteOn(channel, note, velocity)
+            | midi::message::Message::NoteOff(channel, note, velocity) => Note {
+                channel: channel as u8,
+                note: note as u8,
+                velocity: velocity.into(),
+            },
+            _ => return false,
+        };
+
+        let route = cx.shared.arpeggiator.lock(|arp| {
+            if is_note_off(msg) {
+                arp.note_off(note.channel, note.note)
+            } else {
+                arp.note_on(note, received)
+            }
+        });
+        match route {
+            Route::Through => return false,
+            Route::Held => {}
+            // If the queue is full, the arpeggio stalls until all of its notes are released
+            Route::Start(t, generation) => {
+                arp_step::spawn_at(t, generation).ok();
+            }
+            Route::Release(note) => {
+                let mapping = cx
+                    .shared
+                    .settings
+                    .lock(|settings| note_to_string(note.channel, note.note, settings));
+                if let Some((i, _)) = mapping {
+                    let released = cx.shared.sustain_pedal.lock(|pedal| pedal.note_off(1 << i));
+                    release_strings(&mut cx.shared.strings, &mut cx.shared.event_stats, released);
+                }
+            }
+        }
+        true
+    }
+
+    /// Play the next step of an arpeggio or strum. Its notes are mapped to strings like notes
+    /// from MIDI input.
+    #[task(
+        shared = [arpeggiator, strings, sustain_pedal, event_stats, settings],
+        capacity = 4
+    )]
+    fn arp_step(mut cx: arp_step::Context, generation: Generation) {
+        let step = cx
+            .shared
+            .arpeggiator
+            .lock(|arp| arp.generation().is_current(generation).then(|| arp.step()));
+        let step = match step {
+            Some(step) => step,
+            None => return,
+        };
+
+        if let Some(note) = step.off {
+            release_arp_note(
+                &mut cx.shared.strings,
+                &mut cx.shared.sustain_pedal,
+                &mut cx.shared.settings,
+                &mut cx.shared.event_stats,
+                note,
+            );
+        }
+
+        if let Some(note) = step.on {
+            let mapping = cx
+                .shared
+                .settings
+                .lock(|settings| note_to_string(note.channel, note.note, settings));
+            if let Some((i, harmonic)) = mapping {
+                cx.shared.sustain_pedal.lock(|pedal| pedal.note_on(i));
+                let mut next = None;
+                string_i_lock!(cx, i, |string: &mut string::Controller<_>| {
+                    next = string.on(note.velocity, harmonic)
+                });
+                if let Some(t) = next {
+                    schedule_update(&mut cx.shared.event_stats, t, i, harmonic);
+                }
+            }
+        }
+
+        if let Some(t) = step.next {
+            if arp_step::spawn_at(t, generation).is_err() {
+                // The arpeggio can't go on, so don't leave its note sounding
+                cx.shared
+                    .event_stats
+                    .lock(|stats| stats.dropped_updates = stats.dropped_updates.wrapping_add(1));
+                if let Some(note) = cx.shared.arpeggiator.lock(|arp| arp.stop()) {
+                    release_arp_note(
+                        &mut cx.shared.strings,
+                        &mut cx.shared.sustain_pedal,
+                        &mut cx.shared.settings,
+                        &mut cx.shared.event_stats,
+                        note,
+                    );
+                }
+            }
+        }
+    }
+
+    /// Apply note offs that couldn't be queued to handle_midi
+    #[task(
+        shared = [strings, pending_note_offs, event_stats, sustain_pedal],
+        capacity = 1
+    )]
+    fn process_note_offs(mut cx: process_note_offs::Context) {
+        for i in 0..NUM_STRINGS {
+            if !cx.shared.pending_note_offs.lock(|pending| pending.take(i)) {
+                continue;
+            }
+            if cx.shared.sustain_pedal.lock(|pedal| pedal.note_off(1 << i)) == 0 {
+                continue;
+            }
+
+            let mut next = None;
+            string_i_lock!(cx, i, |string: &mut string::Controller<_>| next = string.off(127));
+            if let Some(t) = next {
+                // Harmonic doesn't matter when releasing
+                schedule_update(&mut cx.shared.event_stats, t, i, 1);
+            }
+        }
+    }
+
+    #[task(
+        shared = [strings],
+        capacity = 8
+    )]
+    fn fill_buffer(
+        mut cx: fill_buffer::Context,
+        string: u8,
+        buffer: string::dac_driver::FillableBuffer<u8>,
+    ) {
+        let buffer = buffer.fill();
+
+        string_i_lock!(cx, string, |string: &mut string::Controller<
+            string::DacDriver<_>,
+        >| {
+            // https://github.com/rust-lang/rust/issues/42574
+            let buffer = buffer;
+            string.driver_mut().submit(buffer);
+            // Buffers are requested at a steady rate, so this is a convenient place to keep the
+            // thermal model, amplitude ramps and pressure smoothing up to date
+            string.update_thermal();
+            string.update_ramp();
+            string.update_pressure();
+        });
+
+        FILL_BUFFER_HEARTBEAT.beat();
+    }
+
+    #[task(
+        binds = DMAC,
+        shared = [strings],
+        priority = 3
+    )]
+    fn dmac_interrupt(mut cx: dmac_interrupt::Context) {
+        for i in 0..NUM_STRINGS {
+            let mut buffer = None;
+
+            string_i_lock!(cx, i, |string: &mut string::Controller<
+                string::DacDriver<_>,
+            >| buffer = string.driver_mut().request());
+            if let Some(buffer) = buffer {
+                fill_buffer::spawn(i, buffer).ok();
+            }
+        }
+
+        DMAC_HEARTBEAT.beat();
+    }
+
+    /// Feeds the watchdog as long as DMA, buffer filling and this task, which runs at the same
+    /// priority as the envelope updates, are all making progress.
+    #[task(local = [watchdog], shared = [strings, event_stats])]
+    fn supervise(mut cx: supervise::Context) {
+        // Check both, so neither heartbeat is left over for the next period
+        let dmac_alive = DMAC_HEARTBEAT.check();
+        let fill_buffer_alive = FILL_BUFFER_HEARTBEAT.check();
+        if dmac_alive && fill_buffer_alive {
+            cx.local.watchdog.feed();
+        }
+
+        // Catch up on envelope updates that couldn't be scheduled and note offs that couldn't be
+        // processed. Updates that aren't due yet are ignored.
+        for i in 0..NUM_STRINGS {
+            let mut next = None;
+            string_i_lock!(cx, i, |string: &mut string::Controller<_>| next = string.update());
+            if let Some(t) = next {
+                schedule_update(&mut cx.shared.event_stats, t, i, 1);
+            }
+        }
+        process_note_offs::spawn().ok();
+
+        supervise::spawn_after(rtc::Duration::millis(SUPERVISE_PERIOD_MS)).ok();
+    }
+
+    /// Something has stopped making progress and the watchdog is about to reset the device, so
+    /// make sure nothing is left driving a coil. Whatever is stuck might be the task that fills
+    /// the sample buffers, so DMA would keep replaying the last ones if only the drivers were
+    /// muted; stop the outputs in hardware instead.
+    #[task(binds = WDT, shared = [strings], priority = 4)]
+    fn watchdog_warning(mut cx: watchdog_warning::Context) {
+        watchdog::Watchdog::clear_early_warning();
+        panic::silence();
+        cx.shared.strings.lock(|strings| strings.mute_all());
+    }
+
+    #[task(binds = TCC0, local = [tcc0_faults], shared = [fault_log], priority = 3)]
+    fn tcc0_fault(mut cx: tcc0_fault::Context) {
+        if let Some(fault) = cx.local.tcc0_faults.on_interrupt() {
+            cx.shared.fault_log.lock(|log| log.record(0, fault));
+        }
+    }
+
+    #[task(binds = TCC1, local = [tcc1_faults], shared = [fault_log], priority = 3)]
+    fn tcc1_fault(mut cx: tcc1_fault::Context) {
+        if let Some(fault) = cx.local.tcc1_faults.on_interrupt() {
+            cx.shared.fault_log.lock(|log| log.record(1, fault));
+        }
+    }
+
+    #[task(binds = TCC2, local = [tcc2_faults], shared = [fault_log], priority = 3)]
+    fn tcc2_fault(mut cx: tcc2_fault::Context) {
+        if let Some(fault) = cx.local.tcc2_faults.on_interrupt() {
+            cx.shared.fault_log.lock(|log| log.record(2, fault));
+        }
+    }
+
+    #[task(binds = TC3, shared = [strings])]
+    fn freq_interrupt(mut cx: freq_interrupt::Context) {
+        cx.shared
+            .strings
+            .lock(|strings| strings.controllers.1.sample_frequency());
+    }
+
+    /// Make sure note offs that couldn't be queued still get applied, given the strings they
+    /// turn off as a bitmask
+    fn queue_note_offs(
+        pending_note_offs: &mut impl rtic::Mutex<T = PendingNoteOffs>,
+        strings: u8,
+        received: rtc::Instant,
+    ) {
+        if strings == 0 {
+            return;
+        }
+        pending_note_offs.lock(|pending| {
+            for i in string_indices(strings) {
+                pending.push(i, received);
+            }
+        });
+        process_note_offs::spawn().ok();
+    }
+
+    /// Pass a packet from any MIDI input on to the task that handles it
+    fn dispatch_packet(
+        packet: crate::midi::usb::Packet,
+        received: rtc::Instant,
+        sysex_rx: &mut SysExRx,
+        looper: &mut impl rtic::Mutex<T = crate::midi::looper::Looper>,
+        arpeggiator: &mut impl rtic::Mutex<T = crate::midi::arp::Arpeggiator>,
+        pending_note_offs: &mut impl rtic::Mutex<T = PendingNoteOffs>,
+        event_stats: &mut impl rtic::Mutex<T = EventStats>,
+        settings: &mut impl rtic::Mutex<T = Settings>,
+    ) {
+        if crate::midi::usb::is_sysex(&packet) {
+            if let Some(message) = sysex_rx.push(&packet) {
+                handle_sysex::spawn(message).ok();
+            }
+            return;
+        }
+
+        if let Some(status) = crate::midi::usb::realtime_status(&packet) {
+            use crate::midi::usb::realtime;
+            let transport = match status {
+                realtime::CLOCK => {
+                    if let Some(generation) = arpeggiator.lock(|arp| arp.clock()) {
+                        arp_step::spawn(generation).ok();
+                    }
+                    return;
+                }
+                realtime::START => Transport::Start,
+                realtime::CONTINUE => Transport::Continue,
+                realtime::STOP => Transport::Stop,
+                _ => return,
+            };
+            control_playback::spawn(transport).ok();
+            return;
+        }
+
+        looper.lock(|looper| looper.record([packet[1], packet[2], packet[3]], received));
+        if let Ok(packet) = UsbMidiEventPacket::try_from(&packet[..]) {
+            dispatch_message(
+                packet.message,
+                received,
+                pending_note_offs,
+                event_stats,
+                settings,
+            );
+        }
+    }
+
+    /// Pass a channel message played back from the sequence or the looper on to the task that
+    /// handles it, like one from a MIDI input
+    fn dispatch_event(
+        message: [u8; 3],
+        received: rtc::Instant,
+        pending_note_offs: &mut impl rtic::Mutex<T = PendingNoteOffs>,
+        event_stats: &mut impl rtic::Mutex<T = EventStats>,
+        settings: &mut impl rtic::Mutex<T = Settings>,
+    ) {
+        let [status, data1, data2] = message;
+        let packet = [status >> 4, status, data1, data2];
+        if let Ok(packet) = UsbMidiEventPacket::try_from(&packet[..]) {
+            dispatch_message(
+                packet.message,
+                received,
+                pending_note_offs,
+                event_stats,
+                settings,
+            );
+        }
+    }
+
+    /// Pass a channel message from a MIDI input or the sequence on to the task that handles it
+    fn dispatch_message(
+        msg: midi::message::Message,
+        received: rtc::Instant,
+        pending_note_offs: &mut impl rtic::Mutex<T = PendingNoteOffs>,
+        event_stats: &mut impl rtic::Mutex<T = EventStats>,
+        settings: &mut impl rtic::Mutex<T = Settings>,
+    ) {
+        if let Err((msg, received)) = handle_midi::spawn(msg, received) {
+            let strings = settings.lock(|settings| note_off_strings(&msg, settings));
+            event_stats.lock(|stats| {
+                if strings != 0 {
+                    stats.deferred_note_offs = stats.deferred_note_offs.wrapping_add(1);
+                } else {
+                    stats.dropped_midi = stats.dropped_midi.wrapping_add(1);
+                }
+            });
+            queue_note_offs(pending_note_offs, strings, received);
+        }
+    }
+
+    /// Queue a SysEx message to be sent to the host, and out of the DIN port if enabled. Returns
+    /// whether the message was queued for the host.
+    fn send_sysex(midi_tx: &mut impl rtic::Mutex<T = MidiTx>, message: &[u8]) -> bool {
+        let queued = midi_tx.lock(|tx| tx.send_sysex(message));
+        rtic::pend(pac::Interrupt::USB);
+        rtic::pend(pac::Interrupt::SERCOM0);
+        queued
+    }
+
+    /// Relay a packet received on one port to the other, if the `thru` settings allow it
+    fn relay_packet(
+        relay: &mut crate::midi::thru::Relay,
+        packet: &crate::midi::usb::Packet,
+        midi_tx: &mut impl rtic::Mutex<T = MidiTx>,
+        event_stats: &mut impl rtic::Mutex<T = EventStats>,
+    ) {
+        if !midi_tx.lock(|tx| relay.push(tx, packet)) {
+            event_stats.lock(|stats| stats.dropped_thru = stats.dropped_thru.wrapping_add(1));
+        }
+    }
+
+    fn send_reply(midi_tx: &mut impl rtic::Mutex<T = MidiTx>, reply: sysex::Reply) {
+        send_sysex(midi_tx, &reply.encode());
+    }
+
+    fn send_report(
+        midi_tx: &mut impl rtic::Mutex<T = MidiTx>,
+        report: sysex::Report,
+    ) -> bool {
+        send_sysex(midi_tx, &report.encode())
+    }
+
+    fn send_diagnostic(
+        midi_tx: &mut impl rtic::Mutex<T = MidiTx>,
+        param: sysex::DiagnosticParam,
+        index: u8,
+        value: u32,
+    ) {
+        send_reply(
+            midi_tx,
+            sysex::Reply::DiagnosticValue {
+                param,
+                index,
+                value,
+            },
+        );
+    }
+
+    fn check_string(string: u8) -> Result<(), sysex::ErrorCode> {
+        if string < NUM_STRINGS {
+            Ok(())
+        } else {
+            Err(sysex::ErrorCode::InvalidString)
+        }
+    }
+
+    fn execute_sysex(
+        cx: &mut handle_sysex::Context,
+        command: sysex::Command,
+    ) -> Result<(), sysex::ErrorCode> {
+        use crate::midi::config;
+        use sysex::{Command, DiagnosticParam, ErrorCode, GlobalParam, Reply, StringParam};
+
+        match command {
+            Command::GetVersion => send_reply(
+                &mut cx.shared.midi_tx,
+                Reply::Version(sysex::PROTOCOL_VERSION),
+            ),
+            Command::GetString { string, param } => {
+                check_string(string)?;
+                let mut value = 0;
+                string_i_lock!(cx, string, |s: &mut string::Controller<_>| {
+                    value = config::string_param(s.config(), param)
+                });
+                send_reply(
+                    &mut cx.shared.midi_tx,
+                    Reply::StringValue {
+                        string,
+                        param,
+                        value,
+                    },
+                );
+            }
+            Command::SetString {
+                string,
+                param,
+                value,
+            } => {
+                check_string(string)?;
+                if param == StringParam::Period {
+                    // Every harmonic the string plays has to stay drivable at the new period
+                    let harmonic = cx
+                        .shared
+                        .settings
+                        .lock(|settings| settings.note_map.highest_harmonic(string));
+                    config::check_drive_period(value, harmonic)?;
+                }
+                let mut result = Ok(());
+                string_i_lock!(cx, string, |s: &mut string::Controller<_>| {
+                    result = s.update_config(|c| config::set_string_param(c, param, value))
+                });
+                result?;
+            }
+            Command::ListString { string } => {
+                check_string(string)?;
+                for &param in StringParam::ALL {
+                    execute_sysex(cx, Command::GetString { string, param })?;
+                }
+            }
+            Command::GetNote { note } => {
+                let mapping = cx.shared.settings.lock(|settings| settings.note_map.get(note));
+                send_reply(
+                    &mut cx.shared.midi_tx,
+                    Reply::NoteValue { note, mapping },
+                );
+            }
+            Command::SetNote { note, mapping } => {
+                if let Some((string, harmonic)) = mapping {
+                    check_string(string)?;
+                    let mut period = 0;
+                    string_i_lock!(cx, string, |s: &mut string::Controller<_>| {
+                        period = s.config().period.0
+                    });
+                    config::check_drive_period(period, harmonic)?;
+                }
+                let previous = cx.shared.settings.lock(|settings| {
+                    let previous = settings.note_map.get(note);
+                    settings.note_map.set(note, mapping);
+                    previous
+                });
+                update_target_periods(&mut cx.shared.strings, &mut cx.shared.settings);
+                if previous != mapping {
+                    let remapped = [previous, mapping]
+                        .iter()
+                        .flatten()
+                        .fold(0, |strings, &(i, _)| strings | 1 << i);
+                    release_remapped_strings(
+                        &mut cx.shared.strings,
+                        &mut cx.shared.sustain_pedal,
+                        &mut cx.shared.event_stats,
+                        remapped,
+                    );
+                }
+            }
+            Command::ListNotes => {
+                let note_map = cx.shared.settings.lock(|settings| settings.note_map.clone());
+                for (note, mapping) in note_map.iter() {
+                    send_reply(
+                        &mut cx.shared.midi_tx,
+                        Reply::NoteValue {
+                            note,
+                            mapping: Some(mapping),
+                        },
+                    );
+                }
+            }
+            Command::GetGlobal { param } => {
+                let value = match param {
+                    GlobalParam::ChannelMode => cx
+                        .shared
+                        .settings
+                        .lock(|settings| config::channel_mode_value(settings.channel_mode)),
+                    GlobalParam::PowerBudget => cx
+                        .shared
+                        .strings
+                        .lock(|strings| strings.power_budget() as u32),
+                    GlobalParam::ReportInterval => cx
+                        .shared
+                        .settings
+                        .lock(|settings| config::report_interval_value(settings.report_interval)),
+                    GlobalParam::Thru => cx.shared.midi_tx.lock(|tx| tx.routes as u32),
+                    GlobalParam::ThruFilter => cx.shared.midi_tx.lock(|tx| tx.filter as u32),
+                    GlobalParam::SequenceLoop => {
+                        cx.shared.playback.lock(|playback| playback.looping as u32)
+                    }
+                    GlobalParam::ArpMode => cx
+                        .shared
+                        .arpeggiator
+                        .lock(|arp| config::arp_mode_value(arp.mode())),
+                    GlobalParam::ArpInterval => {
+                        cx.shared.arpeggiator.lock(|arp| arp.interval.to_millis())
+                    }
+                    GlobalParam::ArpClock => {
+                        cx.shared.arpeggiator.lock(|arp| arp.clock_pulses as u32)
+                    }
+                    GlobalParam::StrumSpacing => cx
+                        .shared
+                        .arpeggiator
+                        .lock(|arp| arp.strum_spacing.to_millis()),
+                };
+                send_reply(
+                    &mut cx.shared.midi_tx,
+                    Reply::GlobalValue { param, value },
+                );
+            }
+            Command::SetGlobal { param, value } => match param {
+                GlobalParam::ChannelMode => {
+                    let mode = config::channel_mode_from_value(value)?;
+                    let remapped = cx.shared.settings.lock(|settings| {
+                        let remapped = settings.channel_mode != mode;
+                        settings.channel_mode = mode;
+                        remapped
+                    });
+                    if remapped {
+                        release_all_strings(
+                            &mut cx.shared.strings,
+                            &mut cx.shared.sustain_pedal,
+                            &mut cx.shared.event_stats,
+                        );
+                    }
+                }
+                GlobalParam::PowerBudget => {
+                    let budget = u16::try_from(value).map_err(|_| ErrorCode::InvalidValue)?;
+                    cx.shared
+                        .strings
+                        .lock(|strings| strings.set_power_budget(budget));
+                }
+                GlobalParam::ReportInterval => {
+                    let interval = config::report_interval_from_value(value)?;
+                    cx.shared
+                        .settings
+                        .lock(|settings| settings.report_interval = interval);
+                    if interval.is_some() {
+                        // Fails if reports are already scheduled, which then pick up the new
+                        // interval
+                        report_status::spawn().ok();
+                    }
+                }
+                GlobalParam::Thru => {
+                    let routes = config::bits_from_value(value, sysex::thru::ALL)?;
+                    cx.shared.midi_tx.lock(|tx| tx.routes = routes);
+                }
+                GlobalParam::ThruFilter => {
+                    let filter = config::bits_from_value(value, sysex::thru_filter::ALL)?;
+                    cx.shared.midi_tx.lock(|tx| tx.filter = filter);
+                }
+                GlobalParam::SequenceLoop => {
+                    let looping = config::bits_from_value(value, 1)? != 0;
+                    cx.shared
+                        .playback
+                        .lock(|playback| playback.looping = looping);
+                }
+                GlobalParam::ArpMode => {
+                    let mode = config::arp_mode_from_value(value)?;
+                    // Notes the arpeggiator was playing would never be turned off otherwise
+                    if cx.shared.arpeggiator.lock(|arp| arp.set_mode(mode)) {
+                        release_all_strings(
+                            &mut cx.shared.strings,
+                            &mut cx.shared.sustain_pedal,
+                            &mut cx.shared.event_stats,
+                        );
+                    }
+                }
+                GlobalParam::ArpInterval => {
+                    let interval = config::arp_interval_from_value(value)?;
+                    cx.shared.arpeggiator.lock(|arp| arp.interval = interval);
+                }
+                GlobalParam::ArpClock => {
+                    let pulses = u8::try_from(value).map_err(|_| ErrorCode::InvalidValue)?;
+                    cx.shared.arpeggiator.lock(|arp| arp.clock_pulses = pulses);
+                }
+                GlobalParam::StrumSpacing => {
+                    let spacing = config::strum_spacing_from_value(value)?;
+                    cx.shared
+                        .arpeggiator
+                        .lock(|arp| arp.strum_spacing = spacing);
+                }
+            },
+            Command::ListGlobals => {
+                for &param in GlobalParam::ALL {
+                    execute_sysex(cx, Command::GetGlobal { param })?;
+                }
+            }
+            Command::Calibrate { string } => {
+                check_string(string)?;
+                let mut result = Err(string::CalibrationError::Busy);
+                string_i_lock!(cx, string, |s: &mut string::Controller<_>| {
+                    result = s.calibrate()
+                });
+                let t = result.map_err(|e| match e {
+                    string::CalibrationError::NoFrequencyMeter => ErrorCode::Unsupported,
+                    string::CalibrationError::Busy => ErrorCode::Busy,
+                })?;
+                schedule_update(&mut cx.shared.event_stats, t, string, 1);
+            }
+            Command::ListDiagnostics => {
+                let (fault_counts, last_faults) =
+                    cx.shared.fault_log.lock(|log| (log.count, log.last));
+                for (tcc, (&count, &last)) in fault_counts.iter().zip(&last_faults).enumerate() {
+                    send_diagnostic(
+                        &mut cx.shared.midi_tx,
+                        DiagnosticParam::FaultCount,
+                        tcc as u8,
+                        count as u32,
+                    );
+                    send_diagnostic(
+                        &mut cx.shared.midi_tx,
+                        DiagnosticParam::LastFault,
+                        tcc as u8,
+                        crate::midi::report::fault(last) as u32,
+                    );
+                }
+
+                let stats = cx.shared.event_stats.lock(|stats| {
+                    [
+                        (DiagnosticParam::DroppedMidi, stats.dropped_midi),
+                        (DiagnosticParam::DeferredNoteOffs, stats.deferred_note_offs),
+                        (DiagnosticParam::DroppedUpdates, stats.dropped_updates),
+                        (DiagnosticParam::SerialErrors, stats.serial_errors),
+                        (DiagnosticParam::DroppedThru, stats.dropped_thru),
+                    ]
+                });
+                for (param, count) in stats {
+                    send_diagnostic(&mut cx.shared.midi_tx, param, 0, count as u32);
+                }
+
+                for i in 0..NUM_STRINGS {
+                    let mut state = (0, 0, None, false);
+                    string_i_lock!(cx, i, |s: &mut string::Controller<_>| {
+                        let thermal = s.thermal_state();
+                        state = (
+                            thermal.temperature,
+                            s.forced_releases(),
+                            s.target_period(),
+                            thermal.is_limiting(),
+                        )
+                    });
+                    let (temperature, forced_releases, target_period, limiting) = state;
+                    send_diagnostic(
+                        &mut cx.shared.midi_tx,
+                        DiagnosticParam::Temperature,
+                        i,
+                        temperature,
+                    );
+                    send_diagnostic(
+                        &mut cx.shared.midi_tx,
+                        DiagnosticParam::ForcedReleases,
+                        i,
+                        forced_releases as u32,
+                    );
+                    send_diagnostic(
+                        &mut cx.shared.midi_tx,
+                        DiagnosticParam::TargetPeriod,
+                        i,
+                        target_period.map_or(0, |p| p.0),
+                    );
+                    send_diagnostic(
+                        &mut cx.shared.midi_tx,
+                        DiagnosticParam::ThermalLimiting,
+                        i,
+                        limiting as u32,
+                    );
+                }
+            }
+            Command::ClearFaults => {
+                let fault_monitors: &FaultMonitors = cx.local.fault_monitors;
+                cx.shared.fault_log.lock(|log| {
+                    // The outputs of a TCC stay low while its fault input is active, so the
+                    // record is kept to show which one
+                    if fault_monitors.clear() {
+                        log.last = [None; 3];
+                        Ok(())
+                    } else {
+                        Err(ErrorCode::Busy)
+                    }
+                })?;
+            }
+            Command::SavePreset { preset, name } => {
+                check_idle(cx)?;
+                let configs = cx.shared.strings.lock(|strings| strings.configs());
+                let (note_map, channel_mode) = cx
+                    .shared
+                    .settings
+                    .lock(|settings| (settings.note_map.clone(), settings.channel_mode));
+                crate::midi::preset::Preset {
+                    name,
+                    configs,
+                    note_map,
+                    channel_mode,
+                }
+                .write(cx.local.nvm, preset)?;
+            }
+            Command::LoadPreset { preset } => load_preset(
+                &mut cx.shared.strings,
+                &mut cx.shared.settings,
+                &mut cx.shared.sustain_pedal,
+                &mut cx.shared.event_stats,
+                preset,
+            )?,
+            Command::ListPresets => {
+                for preset in 0..crate::midi::preset::NUM_PRESETS {
+                    if let Some(name) = crate::midi::preset::name(preset) {
+                        send_reply(
+                            &mut cx.shared.midi_tx,
+                            Reply::PresetValue { preset, name },
+                        );
+                    }
+                }
+            }
+            Command::DeletePreset { preset } => {
+                check_idle(cx)?;
+                crate::midi::preset::Preset::delete(cx.local.nvm, preset)?
+            }
+            Command::BeginSequence { len } => {
+                // The sequence being played is about to be overwritten, and nothing can keep
+                // playing while the flash is erased
+                stop_sequence(
+                    &mut cx.shared.playback,
+                    &mut cx.shared.strings,
+                    &mut cx.shared.sustain_pedal,
+                    &mut cx.shared.event_stats,
+                );
+                cx.shared.playback.lock(|playback| playback.unload());
+                cx.shared.looper.lock(|looper| looper.stop());
+                cx.shared.arpeggiator.lock(|arp| arp.stop());
+                cx.shared.strings.lock(|strings| strings.mute_all());
+                cx.shared.sustain_pedal.lock(|pedal| pedal.mute(u8::MAX));
+                check_idle(cx)?;
+                *cx.local.sequence_upload = None;
+                *cx.local.sequence_upload = Some(crate::midi::sequence::Upload::begin(
+                    cx.local.nvm,
+                    len as usize,
+                )?);
+            }
+            Command::SequenceData { data } => {
+                check_idle(cx)?;
+                let upload = cx
+                    .local
+                    .sequence_upload
+                    .as_mut()
+                    .ok_or(ErrorCode::InvalidSequence)?;
+                let result = upload.push(cx.local.nvm, data.as_bytes());
+                if result.is_err() {
+                    *cx.local.sequence_upload = None;
+                }
+                result?;
+            }
+            Command::EndSequence => {
+                check_idle(cx)?;
+                cx.local
+                    .sequence_upload
+                    .take()
+                    .ok_or(ErrorCode::InvalidSequence)?
+                    .finish(cx.local.nvm)?
+            }
+            Command::PlaySequence => start_sequence(
+                &mut cx.shared.playback,
+                &mut cx.shared.strings,
+                &mut cx.shared.sustain_pedal,
+                &mut cx.shared.event_stats,
+            )?,
+            Command::StopSequence => stop_sequence(
+                &mut cx.shared.playback,
+                &mut cx.shared.strings,
+                &mut cx.shared.sustain_pedal,
+                &mut cx.shared.event_stats,
+            ),
+        }
+        Ok(())
+    }
+
+    /// Flash operations stall the CPU for milliseconds at a time, which would starve the strings
+    /// of samples and drop MIDI input, so they are refused while anything is playing
+    fn check_idle(cx: &mut handle_sysex::Context) -> Result<(), sysex::ErrorCode> {
+        let playing = cx.shared.playback.lock(|playback| playback.is_playing())
+            || cx.shared.looper.lock(|looper| looper.is_playing())
+            || cx.shared.arpeggiator.lock(|arp| arp.is_active());
+        if playing || cx.shared.strings.lock(|strings| strings.is_driving()) {
+            Err(sysex::ErrorCode::Busy)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Apply a MIDI Tuning Standard message. Scale/octave tuning only applies if it includes a
+    /// channel that strings are listening on.
+    fn handle_tuning(cx: &mut handle_sysex::Context, message: &[u8]) {
+        let retuned = cx.shared.settings.lock(|settings| {
+            let channels = (0..16)
+                .filter(|&channel| settings.channel_mode.strings(channel) != 0)
+                .fold(0, |channels, channel| channels | 1 << channel);
+            settings.tuning.handle_sysex(message, channels)
+        });
+        if retuned {
+            update_target_periods(&mut cx.shared.strings, &mut cx.shared.settings);
+        }
+    }
+
+    #[task(
+        local = [nvm, sequence_upload, fault_monitors],
+        shared = [
+            strings,
+            settings,
+            midi_tx,
+            fault_log,
+            event_stats,
+            sustain_pedal,
+            playback,
+            looper,
+            arpeggiator,
+        ],
+        capacity = 2
+    )]
+    fn handle_sysex(mut cx: handle_sysex::Context, message: Vec<u8, MAX_SYSEX_RX_LEN>) {
+        let command = match sysex::Command::decode(&message) {
+            Ok(command) => command,
+            Err(sysex::DecodeError::NotForUs) => {
+                handle_tuning(&mut cx, &message);
+                return;
+            }
+            Err(sysex::DecodeError::Invalid(command, code)) => {
+                send_reply(
+                    &mut cx.shared.midi_tx,
+                    sysex::Reply::Error { command, code },
+                );
+                return;
+            }
+        };
+
+        let reply = match execute_sysex(&mut cx, command) {
+            Ok(()) if command.is_acknowledged() => sysex::Reply::Ack {
+                command: command.id(),
+            },
+            Ok(()) => return,
+            Err(code) => sysex::Reply::Error {
+                command: command.id(),
+                code,
+            },
+        };
+        send_reply(&mut cx.shared.midi_tx, reply);
+    }
+
+    /// Send the state of each string, new frequency measurements and changed error counters to
+    /// the host. Runs once per report interval while reporting is enabled.
+    #[task(
+        local = [reports],
+        shared = [strings, fault_log, event_stats, settings, midi_tx],
+        capacity = 1
+    )]
+    fn report_status(mut cx: report_status::Context) {
+        use crate::midi::report;
+        use sysex::{DiagnosticParam, Report};
+
+        let reports: &mut Reports = cx.local.reports;
+        let interval = match cx.shared.settings.lock(|settings| settings.report_interval) {
+            Some(interval) => interval,
+            None => {
+                // Report everything again once reporting is re-enabled
+                *reports = Reports::new();
+                return;
+            }
+        };
+
+        let diagnostic = |param, index, value: u16| Report::Diagnostic {
+            param,
+            index,
+            value: value as u32,
+        };
+
+        for i in 0..NUM_STRINGS {
+            let mut status = (string::Phase::Off, None, 0.ns(), 0, false);
+            string_i_lock!(cx, i, |s: &mut string::Controller<_>| {
+                status = (
+                    s.phase(),
+                    s.take_sample(),
+                    s.config().period,
+                    s.forced_releases(),
+                    s.thermal_state().is_limiting(),
+                )
+            });
+            let (phase, sample, period, forced_releases, limiting) = status;
+
+            let tx = &mut cx.shared.midi_tx;
+            reports.states[i as usize].update(phase, |phase| {
+                send_report(
+                    tx,
+                    Report::State {
+                        string: i,
+                        state: report::string_state(phase),
+                    },
+                )
+            });
+            if let Some(sample) = sample {
+                send_report(tx, report::sample_report(i, sample, period));
+            }
+            reports.forced_releases[i as usize].update(forced_releases, |count| {
+                send_report(tx, diagnostic(DiagnosticParam::ForcedReleases, i, count))
+            });
+            reports.thermal_limiting[i as usize].update(limiting, |limiting| {
+                send_report(
+                    tx,
+                    diagnostic(DiagnosticParam::ThermalLimiting, i, limiting as u16),
+                )
+            });
+        }
+
+        let (fault_counts, last_faults) = cx.shared.fault_log.lock(|log| (log.count, log.last));
+        for (tcc, (&count, &last)) in fault_counts.iter().zip(&last_faults).enumerate() {
+            let tx = &mut cx.shared.midi_tx;
+            reports.fault_counts[tcc].update(count, |count| {
+                send_report(
+                    tx,
+                    diagnostic(DiagnosticParam::FaultCount, tcc as u8, count),
+                )
+            });
+            reports.last_faults[tcc].update(report::fault(last), |fault| {
+                send_report(
+                    tx,
+                    diagnostic(DiagnosticParam::LastFault, tcc as u8, fault as u16),
+                )
+            });
+        }
+
+        let stats = cx.shared.event_stats.lock(|stats| {
+            [
+                (DiagnosticParam::DroppedMidi, stats.dropped_midi),
+                (DiagnosticParam::DeferredNoteOffs, stats.deferred_note_offs),
+                (DiagnosticParam::DroppedUpdates, stats.dropped_updates),
+                (DiagnosticParam::SerialErrors, stats.serial_errors),
+                (DiagnosticParam::DroppedThru, stats.dropped_thru),
+            ]
+        });
+        for (reported, (param, count)) in reports.event_stats.iter_mut().zip(stats) {
+            reported.update(count, |count| {
+                send_report(&mut cx.shared.midi_tx, diagnostic(param, 0, count))
+            });
+        }
+
+        report_status::spawn_after(interval).ok();
+    }
+
+    #[task(
+        binds = USB,
+        local = [usb_device, usb_midi, sysex_rx, usb_relay, panic_record],
+        shared = [pending_note_offs, event_stats, settings, midi_tx, looper, arpeggiator],
+        priority = 2
+    )]
+    fn usb_interrupt(mut cx: usb_interrupt::Context) {
+        let usb_device: &mut UsbDevice<UsbBus> = cx.local.usb_device;
+        let usb_midi: &mut usbd_midi::midi_device::MidiClass<_> = cx.local.usb_midi;
+
+        let ready = usb_device.poll(&mut [usb_midi]);
+
+        if usb_device.state() == UsbDeviceState::Configured {
+            if let Some(record) = cx.local.panic_record.as_ref() {
+                let report = sysex::PanicReport {
+                    line: record.line(),
+                    column: record.column(),
+                    text: record.message(),
+                };
+                // Kept until it fits in the queue, since it is only sent once
+                if send_sysex(&mut cx.shared.midi_tx, &sysex::encode_panic_report(&report)) {
+                    *cx.local.panic_record = None;
+                }
+            }
+        }
+
+        // Send as many packets as the endpoint will accept; the rest are sent when the next
+        // transfer completes
+        cx.shared.midi_tx.lock(|midi_tx| {
+            while let Some(packet) = midi_tx.usb.front() {
+                if usb_midi.send_bytes(*packet).is_err() {
+                    break;
+                }
+                midi_tx.usb.pop_front();
+            }
+        });
+
+        if !ready {
+            return;
+        }
+
+        let mut buffer = [0; 64];
+        if let Ok(size) = usb_midi.read(&mut buffer) {
+            let now = monotonics::now();
+            for (i, packet) in buffer[..size].chunks_exact(4).enumerate() {
+                let packet = packet.try_into().unwrap();
+                // Packets in a transfer all arrive at once, so stamp each a tick after the one
+                // before. Otherwise a note on would look superseded by a note off that came
+                // before it in the same transfer, and be dropped.
+                let received = now + rtc::Duration::from_ticks(i as u32);
+                relay_packet(
+                    cx.local.usb_relay,
+                    &packet,
+                    &mut cx.shared.midi_tx,
+                    &mut cx.shared.event_stats,
+                );
+                dispatch_packet(
+                    packet,
+                    received,
+                    cx.local.sysex_rx,
+                    &mut cx.shared.looper,
+                    &mut cx.shared.arpeggiator,
+                    &mut cx.shared.pending_note_offs,
+                    &mut cx.shared.event_stats,
+                    &mut cx.shared.settings,
+                );
+            }
+            rtic::pend(pac::Interrupt::SERCOM0);
+        }
+    }
+
+    #[task(
+        binds = SERCOM0,
+        local = [din_uart, din_parser, din_sysex_rx, din_relay],
+        shared = [pending_note_offs, event_stats, settings, midi_tx, looper, arpeggiator],
+        priority = 2
+    )]
+    fn din_interrupt(mut cx: din_interrupt::Context) {
+        let uart: &mut DinUart = cx.local.din_uart;
+        loop {
+            let byte = match uart.read() {
+                Ok(byte) => byte,
+                Err(nb::Error::WouldBlock) => break,
+                Err(nb::Error::Other(_)) => {
+                    // The byte was corrupted or lost. Whatever message it belonged to is
+                    // resynchronized by the next status byte.
+                    uart.clear_status(
+                        uart::Status::BUFOVF | uart::Status::FERR | uart::Status::PERR,
+                    );
+                    cx.shared.event_stats.lock(|stats| {
+                        stats.serial_errors = stats.serial_errors.wrapping_add(1)
+                    });
+                    continue;
+                }
+            };
+
+            if let Some(packet) = cx.local.din_parser.push(byte) {
+                relay_packet(
+                    cx.local.din_relay,
+                    &packet,
+                    &mut cx.shared.midi_tx,
+                    &mut cx.shared.event_stats,
+                );
+                rtic::pend(pac::Interrupt::USB);
+                dispatch_packet(
+                    packet,
+                    monotonics::now(),
+                    cx.local.din_sysex_rx,
+                    &mut cx.shared.looper,
+                    &mut cx.shared.arpeggiator,
+                    &mut cx.shared.pending_note_offs,
+                    &mut cx.shared.event_stats,
+                    &mut cx.shared.settings,
+                );
+            }
+        }
+
+        // Send as many bytes as the transmitter will take, and have it interrupt again when it
+        // can take more
+        cx.shared.midi_tx.lock(|midi_tx| {
+            while let Some(&byte) = midi_tx.din.front() {
+                if uart.write(byte).is_err() {
+                    break;
+                }
+                midi_tx.din.pop_front();
+            }
+            if midi_tx.din.is_empty() {
+                uart.disable_interrupts(uart::Flags::DRE);
+            } else {
+                uart.enable_interrupts(uart::Flags::DRE);
+            }
+        });
+    }
 }
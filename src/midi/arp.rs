@@ -0,0 +1,304 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+use heapless::Vec;
+
+use crate::hal::rtc::{Duration, Instant};
+
+use super::Generation;
+
+/// Most notes that can be held or waiting to be strummed. Notes after that are ignored.
+pub const MAX_HELD: usize = 16;
+
+/// How held notes are played
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    /// Notes are played as they arrive
+    Off,
+    /// One note at a time, from the lowest to the highest
+    Up,
+    Down,
+    /// Up and then back down, without repeating the highest and lowest notes
+    UpDown,
+    Random,
+    /// One note at a time, in the order they were played
+    AsPlayed,
+    /// Each chord is spread out from the lowest note to the highest
+    StrumUp,
+    StrumDown,
+}
+
+impl Mode {
+    fn is_strum(self) -> bool {
+        matches!(self, Self::StrumUp | Self::StrumDown)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Note {
+    pub channel: u8,
+    pub note: u8,
+    pub velocity: u8,
+}
+
+impl Note {
+    fn is(&self, channel: u8, note: u8) -> bool {
+        self.channel == channel && self.note == note
+    }
+}
+
+/// What to do with a note message after the arpeggiator has seen it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Route {
+    /// Played as usual
+    Through,
+    /// Taken by the arpeggiator, which plays it later
+    Held,
+    /// Schedule the first step for the instant, with the generation the step has to match
+    Start(Instant, Generation),
+    /// Turn off a note the arpeggiator played
+    Release(Note),
+}
+
+/// Notes to play on a step, and when the next step is due if it's timed by the RTC
+#[derive(Default)]
+pub struct Step {
+    pub off: Option<Note>,
+    pub on: Option<Note>,
+    pub next: Option<Instant>,
+}
+
+/// Sits in front of the note map, taking the notes of held chords and playing them back one
+/// at a time. Arpeggios step at a fixed interval or every few MIDI clock pulses, and strums
+/// play each note of a chord a fixed spacing after the one before.
+pub struct Arpeggiator {
+    mode: Mode,
+    /// Time between arpeggiated notes, unless following the MIDI clock
+    pub interval: Duration,
+    /// MIDI clock pulses between arpeggiated notes, or 0 to use `interval`
+    pub clock_pulses: u8,
+    /// Time between the notes of a strum
+    pub strum_spacing: Duration,
+    /// Notes being arpeggiated in the order they were played, or the notes of a strum that are
+    /// still to be played, in the order they will be
+    held: Vec<Note, MAX_HELD>,
+    /// Note the arpeggio is playing
+    sounding: Option<Note>,
+    /// Steps since the arpeggio started
+    position: u32,
+    /// When the step that is due was scheduled for
+    next_at: Option<Instant>,
+    /// Clock pulses since the last step
+    pulses: u8,
+    random: u32,
+    /// Advanced whenever stepping starts or stops
+    generation: Generation,
+}
+
+impl Arpeggiator {
+    pub const fn new() -> Self {
+        Self {
+            mode: Mode::Off,
+            interval: Duration::millis(125),
+            clock_pulses: 0,
+            strum_spacing: Duration::millis(20),
+            held: Vec::new(),
+            sounding: None,
+            position: 0,
+            next_at: None,
+            pulses: 0,
+            random: 0x2545_f491,
+            generation: Generation::new(),
+        }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Switch to another mode, forgetting any held notes. Returns whether notes the
+    /// arpeggiator played might still be sounding.
+    pub fn set_mode(&mut self, mode: Mode) -> bool {
+        let active = self.next_at.is_some() || self.sounding.is_some();
+        self.mode = mode;
+        self.stop();
+        active
+    }
+
+    /// Whether the arpeggiator has notes to play, or left one sounding
+    pub fn is_active(&self) -> bool {
+        !self.held.is_empty() || self.next_at.is_some() || self.sounding.is_some()
+    }
+
+    /// Stop stepping and forget the held notes, returning the note the arpeggio left sounding
+    pub fn stop(&mut self) -> Option<Note> {
+        self.held.clear();
+        self.next_at = None;
+        self.generation.advance();
+        self.sounding.take()
+    }
+
+    /// Handle a note on from a MIDI input
+    pub fn note_on(&mut self, note: Note, now: Instant) -> Route {
+        if self.mode == Mode::Off {
+            return Route::Through;
+        }
+        if self
+            .held
+            .iter()
+            .any(|held| held.is(note.channel, note.note))
+        {
+            return Route::Held;
+        }
+
+        if self.mode.is_strum() {
+            let up = self.mode == Mode::StrumUp;
+            let i = self
+                .held
+                .iter()
+                .position(|held| {
+                    if up {
+                        note.note < held.note
+                    } else {
+                        note.note > held.note
+                    }
+                })
+                .unwrap_or(self.held.len());
+            if self.held.insert(i, note).is_err() {
+                return Route::Held;
+            }
+            if self.next_at.is_some() {
+                return Route::Held;
+            }
+            // The strum starts a spacing after the first note of the chord, which gives the rest
+            // of the chord time to arrive and be put in order
+            return self.start(now + self.strum_spacing);
+        }
+
+        if self.held.push(note).is_err() {
+            return Route::Held;
+        }
+        if self.next_at.is_some() {
+            return Route::Held;
+        }
+        self.position = 0;
+        self.pulses = 0;
+        self.start(now)
+    }
+
+    fn start(&mut self, t: Instant) -> Route {
+        self.next_at = Some(t);
+        Route::Start(t, self.generation.advance())
+    }
+
+    /// Handle a note off from a MIDI input
+    pub fn note_off(&mut self, channel: u8, note: u8) -> Route {
+        let i = self.held.iter().position(|held| held.is(channel, note));
+        match (self.mode, i) {
+            (Mode::Off, _) => Route::Through,
+            // Notes of a strum that already played are turned off like any other
+            (Mode::StrumUp | Mode::StrumDown, None) => Route::Through,
+            (Mode::StrumUp | Mode::StrumDown, Some(i)) => {
+                self.held.remove(i);
+                if self.held.is_empty() {
+                    self.next_at = None;
+                }
+                Route::Held
+            }
+            (_, None) => Route::Held,
+            (_, Some(i)) => {
+                self.held.remove(i);
+                if !self.held.is_empty() {
+                    return Route::Held;
+                }
+                self.stop().map_or(Route::Held, Route::Release)
+            }
+        }
+    }
+
+    /// Count a MIDI clock pulse, returning the generation to step if a step is due
+    pub fn clock(&mut self) -> Option<Generation> {
+        if self.clock_pulses == 0 || self.mode.is_strum() || self.next_at.is_none() {
+            return None;
+        }
+        self.pulses += 1;
+        if self.pulses < self.clock_pulses {
+            return None;
+        }
+        self.pulses = 0;
+        Some(self.generation)
+    }
+
+    pub fn generation(&self) -> Generation {
+        self.generation
+    }
+
+    fn next_random(&mut self) -> u32 {
+        // xorshift32
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 17;
+        self.random ^= self.random << 5;
+        self.random
+    }
+
+    /// Pick the note for the current step of an arpeggio
+    fn choose(&mut self) -> Note {
+        let len = self.held.len() as u32;
+        match self.mode {
+            Mode::Random => {
+                let i = self.next_random() % len;
+                return self.held[i as usize];
+            }
+            Mode::AsPlayed => return self.held[(self.position % len) as usize],
+            _ => {}
+        }
+
+        let mut sorted = self.held.clone();
+        sorted.sort_unstable_by_key(|note| (note.note, note.channel));
+        let i = match self.mode {
+            Mode::Down => len - 1 - self.position % len,
+            Mode::UpDown => {
+                let period = (2 * len - 2).max(1);
+                let i = self.position % period;
+                if i < len {
+                    i
+                } else {
+                    period - i
+                }
+            }
+            _ => self.position % len,
+        };
+        sorted[i as usize]
+    }
+
+    /// Play the next note, ending the one before it
+    pub fn step(&mut self) -> Step {
+        let at = match self.next_at {
+            Some(at) if !self.held.is_empty() => at,
+            _ => return Step::default(),
+        };
+
+        if self.mode.is_strum() {
+            let on = self.held.remove(0);
+            self.next_at = Some(at + self.strum_spacing).filter(|_| !self.held.is_empty());
+            return Step {
+                off: None,
+                on: Some(on),
+                next: self.next_at,
+            };
+        }
+
+        let on = self.choose();
+        self.position = self.position.wrapping_add(1);
+        let off = self.sounding.replace(on);
+        let next = if self.clock_pulses == 0 {
+            self.next_at = Some(at + self.interval);
+            self.next_at
+        } else {
+            None
+        };
+        Step {
+            off,
+            on: Some(on),
+            next,
+        }
+    }
+}
@@ -0,0 +1,45 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+use crate::NUM_STRINGS;
+
+/// Selects which MIDI channels the strings respond to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChannelMode {
+    /// Respond to every channel
+    Omni,
+    /// Respond only to a single channel, numbered from 0
+    Single(u8),
+    /// String `i` responds only to channel `base + i`, so channel messages like pitch bend,
+    /// pressure and control changes affect individual strings, like an MPE controller expects
+    PerString { base: u8 },
+}
+
+impl Default for ChannelMode {
+    fn default() -> Self {
+        Self::Omni
+    }
+}
+
+impl ChannelMode {
+    /// Whether a note mapped to `string` should be played when it arrives on `channel`
+    pub fn accepts_note(&self, channel: u8, string: u8) -> bool {
+        match *self {
+            Self::Omni => true,
+            Self::Single(c) => channel == c,
+            Self::PerString { base } => channel.checked_sub(base) == Some(string),
+        }
+    }
+
+    /// Strings affected by channel messages, like control changes, arriving on `channel`, as a
+    /// bitmask with bit `i` set for string `i`
+    pub fn strings(&self, channel: u8) -> u8 {
+        match *self {
+            Self::Omni => u8::MAX,
+            Self::Single(c) if channel == c => u8::MAX,
+            Self::Single(_) => 0,
+            Self::PerString { base } => match channel.checked_sub(base) {
+                Some(i) if i < NUM_STRINGS => 1 << i,
+                _ => 0,
+            },
+        }
+    }
+}
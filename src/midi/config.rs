@@ -0,0 +1,181 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+use crate::hal::rtc::Duration;
+use crate::hal::time::Nanoseconds;
+use crate::string::{self, BowingController};
+
+use super::arp;
+use super::control;
+use super::sysex::{arp_mode, ErrorCode, StringParam};
+use super::ChannelMode;
+
+/// Longest duration that can be set, which keeps the conversion to RTC ticks from overflowing
+const MAX_DURATION_MS: u32 = 1_000_000;
+
+/// Shortest report interval, which keeps reports from crowding out replies
+const MIN_REPORT_INTERVAL_MS: u32 = 10;
+
+/// Shortest time between arpeggiated notes, which also keeps the steps from hogging the CPU
+const MIN_ARP_INTERVAL_MS: u32 = 10;
+
+/// Check that `harmonic` of a string tuned to `period` can be driven, so the DAC has enough
+/// samples in each period of the drive waveform
+pub fn check_drive_period(period: u32, harmonic: u8) -> Result<(), ErrorCode> {
+    match period.checked_div(harmonic as u32) {
+        Some(period) if period >= string::MIN_DRIVE_PERIOD.0 => Ok(()),
+        _ => Err(ErrorCode::InvalidValue),
+    }
+}
+
+pub fn string_param(config: &string::Config, param: StringParam) -> u32 {
+    match param {
+        StringParam::Period => config.period.0,
+        StringParam::AttackTime => config.attack_time.to_millis(),
+        StringParam::AttackAmplitude => config.attack_amplitude as u32,
+        StringParam::SustainAmplitude => config.sustain_amplitude as u32,
+        StringParam::ReleaseTime => config.release_time.to_millis(),
+        StringParam::ReleaseAmplitude => config.release_amplitude as u32,
+        StringParam::StabilizeTime => config.stabilize_time.to_millis(),
+        StringParam::SampleTime => config.sample_time.to_millis(),
+        StringParam::MaxNoteTime => config.max_note_time.map_or(0, |t| t.to_millis()),
+        StringParam::ThermalFastTimeConstant => config.thermal.fast_time_constant.to_millis(),
+        StringParam::ThermalSlowTimeConstant => config.thermal.slow_time_constant.to_millis(),
+        StringParam::ThermalFastRise => config.thermal.fast_rise,
+        StringParam::ThermalSlowRise => config.thermal.slow_rise,
+        StringParam::ThermalDerateStart => config.thermal.derate_start,
+        StringParam::ThermalDerateEnd => config.thermal.derate_end,
+        StringParam::PressureDepth => config.pressure_depth as u32,
+        StringParam::PressureSmoothing => config.pressure_smoothing.to_millis(),
+        StringParam::BowingController => match config.bowing {
+            None => 0,
+            Some(BowingController::Breath) => control::BREATH_CONTROLLER as u32,
+            Some(BowingController::Expression) => control::EXPRESSION_CONTROLLER as u32,
+        },
+    }
+}
+
+pub fn set_string_param(
+    config: &mut string::Config,
+    param: StringParam,
+    value: u32,
+) -> Result<(), ErrorCode> {
+    let amplitude = || u8::try_from(value).map_err(|_| ErrorCode::InvalidValue);
+    let duration = || {
+        if value <= MAX_DURATION_MS {
+            Ok(Duration::millis(value))
+        } else {
+            Err(ErrorCode::InvalidValue)
+        }
+    };
+
+    match param {
+        StringParam::Period => {
+            check_drive_period(value, 1)?;
+            config.period = Nanoseconds(value)
+        }
+        StringParam::AttackTime => config.attack_time = duration()?,
+        StringParam::AttackAmplitude => config.attack_amplitude = amplitude()?,
+        StringParam::SustainAmplitude => config.sustain_amplitude = amplitude()?,
+        StringParam::ReleaseTime => config.release_time = duration()?,
+        StringParam::ReleaseAmplitude => config.release_amplitude = amplitude()?,
+        StringParam::StabilizeTime => config.stabilize_time = duration()?,
+        StringParam::SampleTime => config.sample_time = duration()?,
+        StringParam::MaxNoteTime if value == 0 => config.max_note_time = None,
+        StringParam::MaxNoteTime => config.max_note_time = Some(duration()?),
+        StringParam::ThermalFastTimeConstant => config.thermal.fast_time_constant = duration()?,
+        StringParam::ThermalSlowTimeConstant => config.thermal.slow_time_constant = duration()?,
+        StringParam::ThermalFastRise => config.thermal.fast_rise = value,
+        StringParam::ThermalSlowRise => config.thermal.slow_rise = value,
+        StringParam::ThermalDerateStart => config.thermal.derate_start = value,
+        StringParam::ThermalDerateEnd => config.thermal.derate_end = value,
+        StringParam::PressureDepth => config.pressure_depth = amplitude()?,
+        StringParam::PressureSmoothing => config.pressure_smoothing = duration()?,
+        StringParam::BowingController => {
+            config.bowing = match u8::try_from(value) {
+                Ok(0) => None,
+                Ok(control::BREATH_CONTROLLER) => Some(BowingController::Breath),
+                Ok(control::EXPRESSION_CONTROLLER) => Some(BowingController::Expression),
+                _ => return Err(ErrorCode::InvalidValue),
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn channel_mode_value(mode: ChannelMode) -> u32 {
+    match mode {
+        ChannelMode::Omni => 0,
+        ChannelMode::Single(channel) => 0x10 | channel as u32,
+        ChannelMode::PerString { base } => 0x20 | base as u32,
+    }
+}
+
+pub fn channel_mode_from_value(value: u32) -> Result<ChannelMode, ErrorCode> {
+    let channel = (value & 0xf) as u8;
+    match value & !0xf {
+        0 if channel == 0 => Ok(ChannelMode::Omni),
+        0x10 => Ok(ChannelMode::Single(channel)),
+        0x20 => Ok(ChannelMode::PerString { base: channel }),
+        _ => Err(ErrorCode::InvalidValue),
+    }
+}
+
+pub fn report_interval_value(interval: Option<Duration>) -> u32 {
+    interval.map_or(0, |i| i.to_millis())
+}
+
+pub fn report_interval_from_value(value: u32) -> Result<Option<Duration>, ErrorCode> {
+    match value {
+        0 => Ok(None),
+        MIN_REPORT_INTERVAL_MS..=MAX_DURATION_MS => Ok(Some(Duration::millis(value))),
+        _ => Err(ErrorCode::InvalidValue),
+    }
+}
+
+pub fn arp_mode_value(mode: arp::Mode) -> u32 {
+    (match mode {
+        arp::Mode::Off => arp_mode::OFF,
+        arp::Mode::Up => arp_mode::UP,
+        arp::Mode::Down => arp_mode::DOWN,
+        arp::Mode::UpDown => arp_mode::UP_DOWN,
+        arp::Mode::Random => arp_mode::RANDOM,
+        arp::Mode::AsPlayed => arp_mode::AS_PLAYED,
+        arp::Mode::StrumUp => arp_mode::STRUM_UP,
+        arp::Mode::StrumDown => arp_mode::STRUM_DOWN,
+    }) as u32
+}
+
+pub fn arp_mode_from_value(value: u32) -> Result<arp::Mode, ErrorCode> {
+    match u8::try_from(value) {
+        Ok(arp_mode::OFF) => Ok(arp::Mode::Off),
+        Ok(arp_mode::UP) => Ok(arp::Mode::Up),
+        Ok(arp_mode::DOWN) => Ok(arp::Mode::Down),
+        Ok(arp_mode::UP_DOWN) => Ok(arp::Mode::UpDown),
+        Ok(arp_mode::RANDOM) => Ok(arp::Mode::Random),
+        Ok(arp_mode::AS_PLAYED) => Ok(arp::Mode::AsPlayed),
+        Ok(arp_mode::STRUM_UP) => Ok(arp::Mode::StrumUp),
+        Ok(arp_mode::STRUM_DOWN) => Ok(arp::Mode::StrumDown),
+        _ => Err(ErrorCode::InvalidValue),
+    }
+}
+
+pub fn arp_interval_from_value(value: u32) -> Result<Duration, ErrorCode> {
+    match value {
+        MIN_ARP_INTERVAL_MS..=MAX_DURATION_MS => Ok(Duration::millis(value)),
+        _ => Err(ErrorCode::InvalidValue),
+    }
+}
+
+pub fn strum_spacing_from_value(value: u32) -> Result<Duration, ErrorCode> {
+    match value {
+        0..=MAX_DURATION_MS => Ok(Duration::millis(value)),
+        _ => Err(ErrorCode::InvalidValue),
+    }
+}
+
+/// Converts a setting made up of the bits in `valid`
+pub fn bits_from_value(value: u32, valid: u8) -> Result<u8, ErrorCode> {
+    match u8::try_from(value) {
+        Ok(bits) if bits & !valid == 0 => Ok(bits),
+        _ => Err(ErrorCode::InvalidValue),
+    }
+}
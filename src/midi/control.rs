@@ -0,0 +1,114 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+pub const BREATH_CONTROLLER: u8 = 2;
+pub const DATA_ENTRY_MSB: u8 = 6;
+pub const EXPRESSION_CONTROLLER: u8 = 11;
+pub const DATA_ENTRY_LSB: u8 = 38;
+pub const SUSTAIN_PEDAL: u8 = 64;
+/// General purpose controllers 5 to 7, used as buttons for the looper
+pub const LOOPER_RECORD: u8 = 80;
+pub const LOOPER_OVERDUB: u8 = 81;
+pub const LOOPER_CLEAR: u8 = 82;
+pub const NRPN_LSB: u8 = 98;
+pub const NRPN_MSB: u8 = 99;
+pub const RPN_LSB: u8 = 100;
+pub const RPN_MSB: u8 = 101;
+pub const ALL_SOUND_OFF: u8 = 120;
+pub const RESET_ALL_CONTROLLERS: u8 = 121;
+pub const ALL_NOTES_OFF: u8 = 123;
+pub const OMNI_MODE_OFF: u8 = 124;
+pub const POLY_MODE_ON: u8 = 127;
+
+/// Tracks the sustain pedal of each string, which follows the pedal on the string's channel.
+/// Strings are passed as bitmasks, like `ChannelMode::strings()` returns.
+pub struct SustainPedal {
+    /// Strings whose pedal is down
+    down: u8,
+    /// Strings that received a note off while their pedal was down
+    held: u8,
+}
+
+impl SustainPedal {
+    pub const fn new() -> Self {
+        Self { down: 0, held: 0 }
+    }
+
+    pub fn press(&mut self, strings: u8) {
+        self.down |= strings;
+    }
+
+    /// Lift the pedal, returning the strings whose notes should be released now
+    pub fn release(&mut self, strings: u8) -> u8 {
+        self.down &= !strings;
+        let released = self.held & strings;
+        self.held &= !strings;
+        released
+    }
+
+    /// Returns the strings that should be released now. Note offs on strings with the pedal down
+    /// are deferred until it is lifted.
+    pub fn note_off(&mut self, strings: u8) -> u8 {
+        self.held |= strings & self.down;
+        strings & !self.down
+    }
+
+    /// A new note replaces any note that was being held by the pedal
+    pub fn note_on(&mut self, string: u8) {
+        self.held &= !(1 << string);
+    }
+
+    /// Forget held notes, because their strings were muted
+    pub fn mute(&mut self, strings: u8) {
+        self.held &= !strings;
+    }
+}
+
+/// Selects no parameter, so data entry is ignored
+const RPN_NULL: u16 = 0x3fff;
+
+#[derive(Clone, Copy)]
+struct Selection {
+    /// Registered parameter number, or `RPN_NULL` if a non-registered parameter is selected
+    parameter: u16,
+    data: u16,
+}
+
+/// Tracks the registered parameter selected on each channel and assembles the values sent to it
+/// by data entry. Non-registered parameters aren't supported, but selecting one deselects the
+/// registered parameter, so its data entry isn't misapplied.
+pub struct ParameterNumbers {
+    channels: [Selection; 16],
+}
+
+impl ParameterNumbers {
+    pub const fn new() -> Self {
+        Self {
+            channels: [Selection {
+                parameter: RPN_NULL,
+                data: 0,
+            }; 16],
+        }
+    }
+
+    /// Handle a control change, returning the registered parameter and its new 14-bit value if
+    /// it was data entry
+    pub fn control_change(&mut self, channel: u8, control: u8, value: u8) -> Option<(u16, u16)> {
+        let selection = &mut self.channels[channel as usize % 16];
+        let value = value as u16;
+        match control {
+            RPN_MSB => selection.parameter = (selection.parameter & 0x7f) | value << 7,
+            RPN_LSB => selection.parameter = (selection.parameter & !0x7f) | value,
+            NRPN_MSB | NRPN_LSB => selection.parameter = RPN_NULL,
+            DATA_ENTRY_MSB if selection.parameter != RPN_NULL => {
+                selection.data = value << 7;
+                return Some((selection.parameter, selection.data));
+            }
+            DATA_ENTRY_LSB if selection.parameter != RPN_NULL => {
+                selection.data = (selection.data & !0x7f) | value;
+                return Some((selection.parameter, selection.data));
+            }
+            _ => {}
+        }
+        None
+    }
+}
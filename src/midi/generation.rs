@@ -0,0 +1,24 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+/// Identifies a run of something that schedules its own tasks, like playing the sequence. It
+/// changes whenever the run starts or stops, and scheduled tasks carry the generation they were
+/// scheduled in, so ones left over from an earlier run can be dropped.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Generation(u32);
+
+impl Generation {
+    pub const fn new() -> Self {
+        Self(0)
+    }
+
+    /// Start a new generation, making tasks scheduled in earlier ones stale
+    pub fn advance(&mut self) -> Self {
+        self.0 = self.0.wrapping_add(1);
+        *self
+    }
+
+    /// Whether a task scheduled in `scheduled` should still run
+    pub fn is_current(self, scheduled: Self) -> bool {
+        self == scheduled
+    }
+}
@@ -0,0 +1,223 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+use heapless::Vec;
+
+use crate::hal::rtc::{Duration, Instant};
+
+use super::{control, Generation};
+
+/// Most events a loop can hold. Events after that aren't recorded.
+pub const MAX_EVENTS: usize = 512;
+
+#[derive(Clone, Copy)]
+struct Event {
+    /// Time since the start of the loop
+    offset: Duration,
+    message: [u8; 3],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    /// Nothing has been recorded
+    Empty,
+    /// Recording the first pass, which sets the length of the loop
+    Recording,
+    Playing,
+    /// Playing while recording more events on top
+    Overdubbing,
+    Stopped,
+}
+
+/// What the caller needs to do after a looper control
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Change {
+    None,
+    /// Playback started, and the next event needs to be scheduled
+    Started,
+    /// Playback stopped, so notes the loop left playing need to be released
+    Stopped,
+}
+
+/// Whether a message is one that gets recorded: notes and control changes, other than those
+/// controlling the looper
+fn is_recordable(message: &[u8; 3]) -> bool {
+    match message[0] & 0xf0 {
+        0x80 | 0x90 => true,
+        0xb0 => !matches!(
+            message[1],
+            control::LOOPER_RECORD | control::LOOPER_OVERDUB | control::LOOPER_CLEAR
+        ),
+        _ => false,
+    }
+}
+
+/// Records MIDI input and plays it back in a loop, so a performer can layer parts on top of
+/// each other. Controlled by control changes acting as buttons:
+///
+/// * Record starts recording, then ends the first pass and starts playing it back. While
+///   playing or stopped, it stops or restarts playback.
+/// * Overdub switches between playing and recording on top of what is playing.
+/// * Clear throws away the loop.
+pub struct Looper {
+    /// Sorted by offset
+    events: Vec<Event, MAX_EVENTS>,
+    state: State,
+    /// When recording began or the current pass of the loop started
+    start: Option<Instant>,
+    length: Duration,
+    /// Index of the next event to play in the current pass
+    next: usize,
+    /// Advanced whenever playback starts or stops
+    generation: Generation,
+}
+
+impl Looper {
+    pub const fn new() -> Self {
+        Self {
+            events: Vec::new(),
+            state: State::Empty,
+            start: None,
+            length: Duration::from_ticks(0),
+            next: 0,
+            generation: Generation::new(),
+        }
+    }
+
+    pub fn generation(&self) -> Generation {
+        self.generation
+    }
+
+    /// Record a message received from a MIDI input, if recording or overdubbing
+    pub fn record(&mut self, message: [u8; 3], received: Instant) {
+        let start = match (self.state, self.start) {
+            (State::Recording | State::Overdubbing, Some(start)) => start,
+            _ => return,
+        };
+        if !is_recordable(&message) {
+            return;
+        }
+
+        let mut offset = received
+            .checked_duration_since(start)
+            .unwrap_or(Duration::from_ticks(0));
+        if self.state == State::Overdubbing {
+            // The message might have arrived after the end of the pass, before playback moved on
+            // to the next one
+            offset = Duration::from_ticks(offset.ticks() % self.length.ticks());
+        }
+
+        let i = self
+            .events
+            .iter()
+            .position(|event| event.offset > offset)
+            .unwrap_or(self.events.len());
+        if self.events.push(Event { offset, message }).is_err() {
+            return;
+        }
+        self.events[i..].rotate_right(1);
+        // Overdubbed events were just played live, so they aren't played again until the next
+        // pass
+        if self.state == State::Overdubbing && i <= self.next {
+            self.next += 1;
+        }
+    }
+
+    /// Start the current pass over from `now`
+    fn play(&mut self, now: Instant) -> Change {
+        self.state = State::Playing;
+        self.start = Some(now);
+        self.next = 0;
+        self.generation.advance();
+        Change::Started
+    }
+
+    /// Whether the loop is playing, with or without overdubbing
+    pub fn is_playing(&self) -> bool {
+        matches!(self.state, State::Playing | State::Overdubbing)
+    }
+
+    /// Stop playing, if playing
+    pub fn stop(&mut self) -> Change {
+        match self.state {
+            State::Playing | State::Overdubbing => {
+                self.state = State::Stopped;
+                self.generation.advance();
+                Change::Stopped
+            }
+            _ => Change::None,
+        }
+    }
+
+    /// Handle a looper control change, given its controller number
+    pub fn control(&mut self, control: u8, now: Instant) -> Change {
+        match (control, self.state) {
+            (control::LOOPER_RECORD, State::Empty) => {
+                self.events.clear();
+                self.state = State::Recording;
+                self.start = Some(now);
+                Change::None
+            }
+            (control::LOOPER_RECORD, State::Recording) => {
+                self.length = self
+                    .start
+                    .and_then(|start| now.checked_duration_since(start))
+                    .unwrap_or(Duration::from_ticks(0));
+                // A loop without any length would never get anywhere
+                if self.length.ticks() == 0 {
+                    self.state = State::Empty;
+                    return Change::None;
+                }
+                self.play(now)
+            }
+            (control::LOOPER_RECORD, State::Overdubbing) => {
+                self.state = State::Playing;
+                Change::None
+            }
+            (control::LOOPER_RECORD, State::Playing) => self.stop(),
+            (control::LOOPER_RECORD, State::Stopped) => self.play(now),
+            (control::LOOPER_OVERDUB, State::Playing) => {
+                self.state = State::Overdubbing;
+                Change::None
+            }
+            (control::LOOPER_OVERDUB, State::Overdubbing) => {
+                self.state = State::Playing;
+                Change::None
+            }
+            (control::LOOPER_CLEAR, _) => {
+                let change = self.stop();
+                self.events.clear();
+                self.state = State::Empty;
+                change
+            }
+            _ => Change::None,
+        }
+    }
+
+    /// When the next event is due, or the end of the pass if there are no more events in it.
+    /// `None` if not playing.
+    pub fn next_due(&self) -> Option<Instant> {
+        if !self.is_playing() {
+            return None;
+        }
+        let start = self.start?;
+        Some(match self.events.get(self.next) {
+            Some(event) => start + event.offset,
+            None => start + self.length,
+        })
+    }
+
+    /// Take the event that `next_due` was for, or move on to the next pass if it was the end of
+    /// this one
+    pub fn take_due(&mut self) -> Option<[u8; 3]> {
+        match self.events.get(self.next) {
+            Some(event) => {
+                self.next += 1;
+                Some(event.message)
+            }
+            None => {
+                self.start = self.start.map(|start| start + self.length);
+                self.next = 0;
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,21 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+pub use channel::ChannelMode;
+pub use generation::Generation;
+pub use magnet_zither_protocol as sysex;
+pub use note_map::NoteMap;
+pub use sysex::{smf, SYSEX_END, SYSEX_START};
+
+pub mod arp;
+mod channel;
+pub mod config;
+pub mod control;
+mod generation;
+pub mod looper;
+mod note_map;
+pub mod preset;
+pub mod report;
+pub mod sequence;
+pub mod serial;
+pub mod thru;
+pub mod tuning;
+pub mod usb;
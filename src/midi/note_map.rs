@@ -0,0 +1,57 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+/// Number of MIDI note numbers
+const NUM_NOTES: usize = 128;
+
+/// Maps MIDI notes to the string and harmonic that play them
+#[derive(Clone, PartialEq)]
+pub struct NoteMap([Option<(u8, u8)>; NUM_NOTES]);
+
+impl NoteMap {
+    pub fn get(&self, note: u8) -> Option<(u8, u8)> {
+        self.0.get(note as usize).copied().flatten()
+    }
+
+    pub fn set(&mut self, note: u8, mapping: Option<(u8, u8)>) {
+        if let Some(entry) = self.0.get_mut(note as usize) {
+            *entry = mapping;
+        }
+    }
+
+    /// Highest harmonic of `string` that any note is mapped to, or 1 if none are
+    pub fn highest_harmonic(&self, string: u8) -> u8 {
+        self.iter()
+            .filter(|&(_, (s, _))| s == string)
+            .map(|(_, (_, harmonic))| harmonic)
+            .fold(1, u8::max)
+    }
+
+    /// All notes that are mapped to a string
+    pub fn iter(&self) -> impl Iterator<Item = (u8, (u8, u8))> + '_ {
+        self.0
+            .iter()
+            .enumerate()
+            .filter_map(|(note, mapping)| mapping.map(|m| (note as u8, m)))
+    }
+}
+
+impl Default for NoteMap {
+    /// Strings tuned to a G major scale from G4 to G5, with the second and third harmonics of
+    /// all but the lowest string covering the next two octaves
+    fn default() -> Self {
+        // G4, A4, B4, C5, D5, E5, F5, G5
+        const FUNDAMENTALS: [u8; 8] = [67, 69, 71, 72, 74, 76, 77, 79];
+
+        let mut map = Self([None; NUM_NOTES]);
+        for (string, &note) in FUNDAMENTALS.iter().enumerate() {
+            map.set(note, Some((string as u8, 1)));
+        }
+        // The second harmonic of the lowest string is the same note as the highest string
+        for harmonic in 2..=3 {
+            for (string, &note) in FUNDAMENTALS.iter().enumerate().skip(1) {
+                map.set(note + 12 * (harmonic - 1), Some((string as u8, harmonic)));
+            }
+        }
+        map
+    }
+}
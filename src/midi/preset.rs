@@ -0,0 +1,178 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+use crate::const_assert::const_assert;
+use crate::nvm;
+use crate::string;
+use crate::NUM_STRINGS;
+
+use super::config;
+use super::sysex::{ErrorCode, PresetName, StringParam, MAX_PRESET_NAME_LEN};
+use super::{ChannelMode, NoteMap};
+
+pub const NUM_PRESETS: u8 = 16;
+
+/// Start of the flash reserved for presets in memory.x
+const FLASH_START: u32 = 0x0003_c000;
+
+/// Flash reserved for each preset, which leaves room for more string parameters
+const SLOT_SIZE: usize = 4 * nvm::ROW_SIZE;
+
+const SLOT_WORDS: usize = SLOT_SIZE / 4;
+
+/// Marks a slot that holds a preset. Changes whenever the layout does.
+const MAGIC: u32 = 0x5053_5431;
+
+/// Words before the string parameters: the magic number, the number of parameters stored for
+/// each string, the name length, the name and the channel mode
+const HEADER_WORDS: usize = 3 + MAX_PRESET_NAME_LEN / 4 + 1;
+
+/// Each note takes half a word, holding the string and harmonic
+const NOTE_MAP_WORDS: usize = 128 / 2;
+
+/// Stored in place of the string and harmonic of an unmapped note
+const UNMAPPED: u16 = 0xffff;
+
+/// A complete set of string configurations, note map and channel mode
+pub struct Preset {
+    pub name: PresetName,
+    /// The period of each string is stored, but ignored when switching presets, since it belongs
+    /// to the physical string and is kept up to date by calibration
+    pub configs: [string::Config; NUM_STRINGS as usize],
+    pub note_map: NoteMap,
+    pub channel_mode: ChannelMode,
+}
+
+fn slot_address(index: u8) -> Result<u32, ErrorCode> {
+    if index < NUM_PRESETS {
+        Ok(FLASH_START + (index as usize * SLOT_SIZE) as u32)
+    } else {
+        Err(ErrorCode::InvalidPreset)
+    }
+}
+
+fn slot(index: u8) -> Result<&'static [u32; SLOT_WORDS], ErrorCode> {
+    let address = slot_address(index)?;
+    Ok(unsafe { &*(address as *const [u32; SLOT_WORDS]) })
+}
+
+/// Number of words used by a preset with `num_params` parameters for each string, not including
+/// the checksum
+const fn preset_words(num_params: usize) -> usize {
+    HEADER_WORDS + NUM_STRINGS as usize * num_params + NOTE_MAP_WORDS
+}
+
+const_assert!(preset_words(StringParam::ALL.len()) < SLOT_WORDS);
+
+/// Read the header and check the checksum of a slot, returning the number of string parameters
+fn validate(words: &[u32; SLOT_WORDS]) -> Option<usize> {
+    let num_params = words[1] as usize;
+    if words[0] != MAGIC || preset_words(num_params) >= SLOT_WORDS {
+        return None;
+    }
+    let len = preset_words(num_params);
+    if nvm::checksum(&words[..len]) != words[len] {
+        return None;
+    }
+    Some(num_params)
+}
+
+fn read_name(words: &[u32; SLOT_WORDS]) -> Option<PresetName> {
+    let mut bytes = [0; MAX_PRESET_NAME_LEN];
+    for (chunk, &word) in bytes.chunks_mut(4).zip(&words[3..]) {
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
+    PresetName::new(bytes.get(..words[2] as usize)?)
+}
+
+/// Name of the preset stored in a slot, or `None` if the slot is empty
+pub fn name(index: u8) -> Option<PresetName> {
+    let words = slot(index).ok()?;
+    validate(words)?;
+    read_name(words)
+}
+
+impl Preset {
+    pub fn read(index: u8) -> Result<Self, ErrorCode> {
+        let words = slot(index)?;
+        let num_params = validate(words).ok_or(ErrorCode::InvalidPreset)?;
+        let name = read_name(words).ok_or(ErrorCode::InvalidPreset)?;
+        let channel_mode = config::channel_mode_from_value(words[HEADER_WORDS - 1])
+            .map_err(|_| ErrorCode::InvalidPreset)?;
+
+        // Parameters that weren't stored by older firmware keep their defaults
+        let mut configs = [string::Config::default(); NUM_STRINGS as usize];
+        let params = &words[HEADER_WORDS..HEADER_WORDS + NUM_STRINGS as usize * num_params];
+        for (config, values) in configs.iter_mut().zip(params.chunks(num_params.max(1))) {
+            for (&param, &value) in StringParam::ALL.iter().zip(values) {
+                config::set_string_param(config, param, value)
+                    .map_err(|_| ErrorCode::InvalidPreset)?;
+            }
+        }
+
+        let mut note_map = NoteMap::default();
+        let notes = &words[HEADER_WORDS + NUM_STRINGS as usize * num_params..][..NOTE_MAP_WORDS];
+        for (note, &word) in notes.iter().enumerate() {
+            for (half, entry) in [word as u16, (word >> 16) as u16].into_iter().enumerate() {
+                let mapping = match entry {
+                    UNMAPPED => None,
+                    entry => Some(((entry >> 8) as u8, entry as u8)),
+                };
+                note_map.set((note * 2 + half) as u8, mapping);
+            }
+        }
+
+        Ok(Self {
+            name,
+            configs,
+            note_map,
+            channel_mode,
+        })
+    }
+
+    pub fn write(&self, nvm: &mut nvm::Nvm, index: u8) -> Result<(), ErrorCode> {
+        let address = slot_address(index)?;
+
+        let mut words = [0; preset_words(StringParam::ALL.len()) + 1];
+        words[0] = MAGIC;
+        words[1] = StringParam::ALL.len() as u32;
+
+        let name = self.name.as_bytes();
+        words[2] = name.len() as u32;
+        let mut bytes = [0; MAX_PRESET_NAME_LEN];
+        bytes[..name.len()].copy_from_slice(name);
+        for (word, chunk) in words[3..].iter_mut().zip(bytes.chunks(4)) {
+            *word = u32::from_le_bytes(chunk.try_into().unwrap());
+        }
+        words[HEADER_WORDS - 1] = config::channel_mode_value(self.channel_mode);
+
+        let (params, rest) =
+            words[HEADER_WORDS..].split_at_mut(NUM_STRINGS as usize * StringParam::ALL.len());
+        for (config, values) in self
+            .configs
+            .iter()
+            .zip(params.chunks_mut(StringParam::ALL.len()))
+        {
+            for (&param, value) in StringParam::ALL.iter().zip(values) {
+                *value = config::string_param(config, param);
+            }
+        }
+
+        for (note, word) in rest[..NOTE_MAP_WORDS].iter_mut().enumerate() {
+            let entry = |note: usize| match self.note_map.get(note as u8) {
+                Some((string, harmonic)) => (string as u16) << 8 | harmonic as u16,
+                None => UNMAPPED,
+            };
+            *word = entry(note * 2) as u32 | (entry(note * 2 + 1) as u32) << 16;
+        }
+
+        let len = words.len() - 1;
+        words[len] = nvm::checksum(&words[..len]);
+
+        nvm.write(address, &words)
+            .map_err(|_| ErrorCode::StorageFailed)
+    }
+
+    pub fn delete(nvm: &mut nvm::Nvm, index: u8) -> Result<(), ErrorCode> {
+        nvm.erase(slot_address(index)?, SLOT_SIZE)
+            .map_err(|_| ErrorCode::StorageFailed)
+    }
+}
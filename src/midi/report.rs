@@ -0,0 +1,59 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+use crate::hal::time::Nanoseconds;
+use crate::pwm_dac::Fault;
+use crate::string;
+
+use super::sysex::{fault, Report, SampleResult, StringState};
+
+pub fn string_state(phase: string::Phase) -> StringState {
+    match phase {
+        string::Phase::Attack => StringState::Attack,
+        string::Phase::Sustain => StringState::Sustain,
+        string::Phase::Release => StringState::Release,
+        string::Phase::WaitStabilize => StringState::Stabilize,
+        string::Phase::SampleFrequency => StringState::Sample,
+        string::Phase::Off => StringState::Off,
+    }
+}
+
+pub fn fault(last: Option<Fault>) -> u8 {
+    match last {
+        Some(Fault::Recoverable) => fault::RECOVERABLE,
+        Some(Fault::NonRecoverable) => fault::NON_RECOVERABLE,
+        None => fault::NONE,
+    }
+}
+
+pub fn sample_report(string: u8, sample: string::Sample, period: Nanoseconds) -> Report {
+    let (result, sample) = match sample {
+        string::Sample::Accepted(sample) => (SampleResult::Accepted, sample.0),
+        string::Sample::Outlier(sample) => (SampleResult::Outlier, sample.0),
+        string::Sample::Error => (SampleResult::Error, 0),
+    };
+    Report::Sample {
+        string,
+        result,
+        sample,
+        period: period.0,
+    }
+}
+
+/// A value that is reported to the host whenever it changes
+#[derive(Clone, Copy)]
+pub struct Reported<T>(Option<T>);
+
+impl<T> Reported<T> {
+    pub const fn new() -> Self {
+        Self(None)
+    }
+}
+
+impl<T: Copy + PartialEq> Reported<T> {
+    /// Call `send` if the value is different from the last one that was sent successfully. The
+    /// first value is always sent.
+    pub fn update(&mut self, value: T, send: impl FnOnce(T) -> bool) {
+        if self.0 != Some(value) && send(value) {
+            self.0 = Some(value);
+        }
+    }
+}
@@ -0,0 +1,132 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+use crate::nvm;
+
+use super::smf;
+use super::sysex::ErrorCode;
+
+/// Start of the flash reserved for the sequence in memory.x
+const FLASH_START: u32 = 0x0003_4000;
+
+const FLASH_SIZE: usize = 32 * 1024;
+
+/// The first page holds the header, and the file follows it
+const DATA_START: u32 = FLASH_START + nvm::PAGE_SIZE as u32;
+
+/// Longest file that can be stored
+pub const MAX_LEN: usize = FLASH_SIZE - nvm::PAGE_SIZE;
+
+/// Marks a stored sequence. Changes whenever the layout does.
+const MAGIC: u32 = 0x5345_5131;
+
+/// The magic number, the length of the file in bytes and the checksum of the file
+const HEADER_WORDS: usize = 3;
+
+/// Sequence built into the firmware, which plays when none has been uploaded. Empty unless the
+/// `MAGNET_ZITHER_SEQUENCE` environment variable named a file at build time.
+static EMBEDDED: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/sequence.mid"));
+
+/// Words of flash covering `len` bytes from `address`
+fn words(address: u32, len: usize) -> &'static [u32] {
+    unsafe { core::slice::from_raw_parts(address as *const u32, len.div_ceil(4)) }
+}
+
+/// Uploaded sequence, or `None` if there isn't one or it was only partially written
+pub fn stored() -> Option<&'static [u8]> {
+    let header = unsafe { &*(FLASH_START as *const [u32; HEADER_WORDS]) };
+    let len = header[1] as usize;
+    if header[0] != MAGIC || len > MAX_LEN || nvm::checksum(words(DATA_START, len)) != header[2] {
+        return None;
+    }
+    Some(unsafe { core::slice::from_raw_parts(DATA_START as *const u8, len) })
+}
+
+pub fn embedded() -> Option<&'static [u8]> {
+    Some(EMBEDDED).filter(|data| !data.is_empty())
+}
+
+/// Sequence to play: the uploaded one if there is one, otherwise the one built into the firmware
+pub fn current() -> Option<&'static [u8]> {
+    stored().or_else(embedded)
+}
+
+/// Writes a sequence received in parts to flash. The stored sequence is erased when the upload
+/// begins, and the new one only becomes valid once it has been completely written and checked.
+pub struct Upload {
+    len: usize,
+    written: usize,
+    /// Data waiting for a whole page to be received
+    page: [u8; nvm::PAGE_SIZE],
+}
+
+impl Upload {
+    pub fn begin(nvm: &mut nvm::Nvm, len: usize) -> Result<Self, ErrorCode> {
+        if len > MAX_LEN {
+            return Err(ErrorCode::InvalidValue);
+        }
+        // Only the row with the header needs to be erased now, which invalidates the old
+        // sequence. Later rows are erased as the upload reaches them.
+        nvm.erase(FLASH_START, nvm::ROW_SIZE)
+            .map_err(|_| ErrorCode::StorageFailed)?;
+        Ok(Self {
+            len,
+            written: 0,
+            page: [0xff; nvm::PAGE_SIZE],
+        })
+    }
+
+    /// Write the page buffer to flash, padding it with erased bytes
+    fn flush(&mut self, nvm: &mut nvm::Nvm) -> Result<(), nvm::Error> {
+        let offset = (self.written - 1) / nvm::PAGE_SIZE * nvm::PAGE_SIZE;
+        let address = DATA_START + offset as u32;
+        if address as usize % nvm::ROW_SIZE == 0 {
+            nvm.erase(address, nvm::ROW_SIZE)?;
+        }
+
+        let mut words = [0; nvm::PAGE_SIZE / 4];
+        for (word, bytes) in words.iter_mut().zip(self.page.chunks(4)) {
+            *word = u32::from_le_bytes(bytes.try_into().unwrap());
+        }
+        self.page = [0xff; nvm::PAGE_SIZE];
+        nvm.program(address, &words)
+    }
+
+    /// Add the next part of the sequence
+    pub fn push(&mut self, nvm: &mut nvm::Nvm, data: &[u8]) -> Result<(), ErrorCode> {
+        if self.written + data.len() > self.len {
+            return Err(ErrorCode::InvalidSequence);
+        }
+        for &byte in data {
+            self.page[self.written % nvm::PAGE_SIZE] = byte;
+            self.written += 1;
+            if self.written % nvm::PAGE_SIZE == 0 {
+                self.flush(nvm).map_err(|_| ErrorCode::StorageFailed)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that the whole sequence arrived and can be played, then mark it as valid. An empty
+    /// upload leaves no sequence stored.
+    pub fn finish(mut self, nvm: &mut nvm::Nvm) -> Result<(), ErrorCode> {
+        if self.written != self.len {
+            return Err(ErrorCode::InvalidSequence);
+        }
+        if self.len == 0 {
+            return Ok(());
+        }
+        if self.written % nvm::PAGE_SIZE != 0 {
+            self.flush(nvm).map_err(|_| ErrorCode::StorageFailed)?;
+        }
+
+        let data = unsafe { core::slice::from_raw_parts(DATA_START as *const u8, self.len) };
+        smf::Sequence::parse(data).map_err(|_| ErrorCode::InvalidSequence)?;
+
+        let header: [u32; HEADER_WORDS] = [
+            MAGIC,
+            self.len as u32,
+            nvm::checksum(words(DATA_START, self.len)),
+        ];
+        nvm.program(FLASH_START, &header)
+            .map_err(|_| ErrorCode::StorageFailed)
+    }
+}
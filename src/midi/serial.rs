@@ -0,0 +1,133 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+use super::usb::{cin, Packet};
+use super::{SYSEX_END, SYSEX_START};
+
+/// Number of data bytes that follow a status byte
+fn data_len(status: u8) -> usize {
+    match status {
+        0xc0..=0xdf => 1,
+        0x80..=0xef => 2,
+        // MIDI time code quarter frame and song select
+        0xf1 | 0xf3 => 1,
+        // Song position pointer
+        0xf2 => 2,
+        _ => 0,
+    }
+}
+
+/// Converts the byte stream of a serial MIDI port into USB MIDI event packets, so messages from
+/// both can be handled the same way. Handles running status, realtime messages in the middle of
+/// other messages, and SysEx.
+pub struct Parser {
+    cable: u8,
+    /// Status of the message being received, which is kept after channel messages for running
+    /// status
+    status: Option<u8>,
+    /// Bytes of the current message, or of the current chunk of a SysEx message
+    data: [u8; 3],
+    len: usize,
+    sysex: bool,
+}
+
+impl Parser {
+    pub const fn new(cable: u8) -> Self {
+        Self {
+            cable,
+            status: None,
+            data: [0; 3],
+            len: 0,
+            sysex: false,
+        }
+    }
+
+    fn packet(&self, cin: u8, bytes: &[u8]) -> Packet {
+        let mut packet = [self.cable << 4 | cin, 0, 0, 0];
+        packet[1..=bytes.len()].copy_from_slice(bytes);
+        packet
+    }
+
+    /// Add a byte from the port, returning a packet once one is complete
+    pub fn push(&mut self, byte: u8) -> Option<Packet> {
+        match byte {
+            // Realtime messages can appear anywhere, even in SysEx, and don't affect anything else
+            0xf8..=0xff => Some(self.packet(cin::REALTIME, &[byte])),
+            SYSEX_START => {
+                self.status = None;
+                self.sysex = true;
+                self.data[0] = byte;
+                self.len = 1;
+                None
+            }
+            SYSEX_END if self.sysex => {
+                self.sysex = false;
+                self.data[self.len] = byte;
+                self.len += 1;
+                let cin = match self.len {
+                    1 => cin::SYSEX_END_1,
+                    2 => cin::SYSEX_END_2,
+                    _ => cin::SYSEX_END_3,
+                };
+                let len = core::mem::replace(&mut self.len, 0);
+                Some(self.packet(cin, &self.data[..len]))
+            }
+            0x80..=0xff => {
+                // Any other status byte ends SysEx, and whatever was received of it is dropped
+                // when the next message starts
+                self.sysex = false;
+                self.len = 0;
+                match byte {
+                    // Tune request
+                    0xf6 => {
+                        self.status = None;
+                        Some(self.packet(cin::SINGLE_BYTE, &[byte]))
+                    }
+                    0xf1..=0xf3 | 0x80..=0xef => {
+                        self.status = Some(byte);
+                        None
+                    }
+                    // Undefined, or an end of SysEx without a start
+                    _ => {
+                        self.status = None;
+                        None
+                    }
+                }
+            }
+            _ if self.sysex => {
+                self.data[self.len] = byte;
+                self.len += 1;
+                if self.len == 3 {
+                    self.len = 0;
+                    Some(self.packet(cin::SYSEX_START, &self.data))
+                } else {
+                    None
+                }
+            }
+            _ => {
+                // Data without a status byte is ignored
+                let status = self.status?;
+                self.data[self.len] = byte;
+                self.len += 1;
+                if self.len < data_len(status) {
+                    return None;
+                }
+
+                let len = core::mem::replace(&mut self.len, 0);
+                let cin = match status {
+                    0xf0..=0xff => {
+                        // Running status only applies to channel messages
+                        self.status = None;
+                        if len == 1 {
+                            cin::SYSTEM_COMMON_2
+                        } else {
+                            cin::SYSTEM_COMMON_3
+                        }
+                    }
+                    _ => status >> 4,
+                };
+                let mut bytes = [status, 0, 0];
+                bytes[1..=len].copy_from_slice(&self.data[..len]);
+                Some(self.packet(cin, &bytes[..=len]))
+            }
+        }
+    }
+}
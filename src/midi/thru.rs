@@ -0,0 +1,140 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+use heapless::Deque;
+
+use super::sysex::{thru, thru_filter};
+use super::usb::{Packet, SysExPackets, SysExReceiver};
+use super::SYSEX_END;
+
+/// Longest SysEx message that can be relayed. Messages are relayed whole, so they don't get
+/// mixed up with messages from other sources.
+pub const MAX_SYSEX_LEN: usize = 192;
+
+/// Number of MIDI bytes in a USB MIDI event packet
+fn packet_len(packet: &Packet) -> usize {
+    match packet[0] & 0xf {
+        0x5 | 0xf => 1,
+        0x2 | 0x6 | 0xc | 0xd => 2,
+        0x3 | 0x4 | 0x7 | 0x8..=0xe => 3,
+        // Reserved
+        _ => 0,
+    }
+}
+
+/// Kind of message a packet belongs to, as a bit of the `thru-filter` setting
+fn kind(packet: &Packet) -> u8 {
+    match packet[0] & 0xf {
+        0x4 | 0x6 | 0x7 => thru_filter::SYSEX,
+        0x5 if packet[1] == SYSEX_END => thru_filter::SYSEX,
+        0x2 | 0x3 | 0x5 => thru_filter::SYSTEM_COMMON,
+        0xf => thru_filter::REALTIME,
+        _ => thru_filter::CHANNEL,
+    }
+}
+
+/// MIDI waiting to be sent out of each port. Everything sent out of a port goes through here, so
+/// messages from different sources are merged without splitting any of them.
+pub struct Output {
+    /// Packets waiting to be sent to the host
+    pub usb: Deque<Packet, 256>,
+    /// Bytes waiting to be sent out of the DIN port
+    pub din: Deque<u8, 256>,
+    /// Bits of the `thru` setting
+    pub routes: u8,
+    /// Bits of the `thru-filter` setting
+    pub filter: u8,
+}
+
+impl Output {
+    pub const fn new() -> Self {
+        Self {
+            usb: Deque::new(),
+            din: Deque::new(),
+            routes: 0,
+            filter: 0,
+        }
+    }
+
+    fn send_usb_sysex(&mut self, message: &[u8]) -> bool {
+        let packets = SysExPackets::new(0, message);
+        if self.usb.capacity() - self.usb.len() < packets.clone().count() {
+            return false;
+        }
+        for packet in packets {
+            self.usb.push_back(packet).ok();
+        }
+        true
+    }
+
+    fn send_din(&mut self, bytes: &[u8]) -> bool {
+        if self.din.capacity() - self.din.len() < bytes.len() {
+            return false;
+        }
+        for &byte in bytes {
+            self.din.push_back(byte).ok();
+        }
+        true
+    }
+
+    /// Queue a SysEx message from the firmware itself. Messages that don't fit in the queue are
+    /// dropped whole, rather than sending a truncated message. Returns whether the message was
+    /// queued for the host.
+    pub fn send_sysex(&mut self, message: &[u8]) -> bool {
+        if self.routes & thru::STATUS_TO_DIN != 0 {
+            self.send_din(message);
+        }
+        self.send_usb_sysex(message)
+    }
+}
+
+/// Relays messages received on one port to the other
+pub struct Relay {
+    /// Bit of the `thru` setting that enables this relay
+    route: u8,
+    sysex: SysExReceiver<MAX_SYSEX_LEN>,
+}
+
+impl Relay {
+    /// Relay from the DIN input to the host
+    pub const fn din_to_usb() -> Self {
+        Self::new(thru::DIN_TO_USB)
+    }
+
+    /// Relay from the host to the DIN output
+    pub const fn usb_to_din() -> Self {
+        Self::new(thru::USB_TO_DIN)
+    }
+
+    const fn new(route: u8) -> Self {
+        Self {
+            route,
+            sysex: SysExReceiver::new(),
+        }
+    }
+
+    /// Relay a packet, if the settings allow it. Returns false if the output was full and the
+    /// message was dropped.
+    pub fn push(&mut self, output: &mut Output, packet: &Packet) -> bool {
+        let kind = kind(packet);
+        // SysEx is always reassembled, so enabling the relay partway through a message doesn't
+        // send the rest of it
+        let sysex = if kind == thru_filter::SYSEX {
+            match self.sysex.push(packet) {
+                Some(message) => Some(message),
+                None => return true,
+            }
+        } else {
+            None
+        };
+
+        if output.routes & self.route == 0 || output.filter & kind != 0 {
+            return true;
+        }
+
+        match (self.route, sysex) {
+            (thru::DIN_TO_USB, Some(message)) => output.send_usb_sysex(&message),
+            (thru::DIN_TO_USB, None) => output.usb.push_back(*packet).is_ok(),
+            (_, Some(message)) => output.send_din(&message),
+            (_, None) => output.send_din(&packet[1..=packet_len(packet)]),
+        }
+    }
+}
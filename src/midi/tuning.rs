@@ -0,0 +1,215 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+use core::f32::consts::LN_2;
+
+use crate::hal::time::Nanoseconds;
+use crate::NUM_STRINGS;
+
+use super::{NoteMap, SYSEX_END, SYSEX_START};
+
+/// Registered parameter numbers
+pub const MASTER_FINE_TUNING: u16 = 1;
+pub const MASTER_COARSE_TUNING: u16 = 2;
+
+/// Pitches are in units of 1/16384 of a semitone, the resolution of the MIDI Tuning Standard
+const SEMITONE: i32 = 1 << 14;
+
+const OCTAVE: i32 = 12 * SEMITONE;
+
+/// Note of the reference pitch, A4
+const REFERENCE_NOTE: i32 = 69;
+
+const REFERENCE_PERIOD_NS: f32 = 1e9 / 440.0;
+
+/// 2^(n/12) for each semitone of an octave
+const SEMITONE_RATIOS: [f32; 12] = [
+    1.0,
+    1.059_463_1,
+    1.122_462,
+    1.189_207_1,
+    1.259_921_1,
+    1.334_839_8,
+    1.414_213_5,
+    1.498_307_1,
+    1.587_401,
+    1.681_792_9,
+    1.781_797_4,
+    1.887_748_6,
+];
+
+const NUM_NOTES: usize = 128;
+
+/// Universal SysEx IDs
+const NON_REAL_TIME: u8 = 0x7e;
+const REAL_TIME: u8 = 0x7f;
+const MIDI_TUNING_STANDARD: u8 = 0x08;
+
+/// MIDI Tuning Standard messages
+const SINGLE_NOTE_TUNING_CHANGE: u8 = 0x02;
+const SINGLE_NOTE_TUNING_CHANGE_BANK: u8 = 0x07;
+const SCALE_OCTAVE_TUNING_1: u8 = 0x08;
+const SCALE_OCTAVE_TUNING_2: u8 = 0x09;
+
+/// Most notes that a single note tuning change can retune. The standard allows all 127, but
+/// tuning programs split large changes over several messages anyway.
+const MAX_NOTE_CHANGES: usize = 16;
+
+/// Longest MIDI Tuning Standard message, including the framing bytes. This is a single note
+/// tuning change with a bank number.
+pub const MAX_MESSAGE_LEN: usize = 9 + 4 * MAX_NOTE_CHANGES;
+
+/// Frequency that each MIDI note is tuned to. Starts out as equal temperament with A4 at 440 Hz,
+/// and can be changed with the master tuning registered parameters and MIDI Tuning Standard
+/// messages.
+pub struct Tuning {
+    /// Pitch of each note before master tuning
+    notes: [i32; NUM_NOTES],
+    /// Master coarse and fine tuning, which shift every note
+    coarse: i32,
+    fine: i32,
+}
+
+impl Default for Tuning {
+    fn default() -> Self {
+        let mut notes = [0; NUM_NOTES];
+        for (note, pitch) in notes.iter_mut().enumerate() {
+            *pitch = note as i32 * SEMITONE;
+        }
+        Self {
+            notes,
+            coarse: 0,
+            fine: 0,
+        }
+    }
+}
+
+impl Tuning {
+    /// Set the master fine tuning from its 14-bit registered parameter value, where 0x2000 is
+    /// in tune and the range covers 100 cents either way
+    pub fn set_master_fine(&mut self, value: u16) {
+        self.fine = (value as i32 - 0x2000) * SEMITONE / 0x2000;
+    }
+
+    /// Set the master coarse tuning from the MSB of its registered parameter value, where 0x40
+    /// is in tune and each step is a semitone
+    pub fn set_master_coarse(&mut self, value: u8) {
+        self.coarse = (value as i32 - 0x40) * SEMITONE;
+    }
+
+    pub fn period(&self, note: u8) -> Nanoseconds {
+        let pitch = self.notes[note as usize % NUM_NOTES] + self.coarse + self.fine
+            - REFERENCE_NOTE * SEMITONE;
+        let octave = pitch.div_euclid(OCTAVE);
+        let pitch = pitch.rem_euclid(OCTAVE);
+
+        // The Taylor series of 2^x converges quickly within a semitone, so this is accurate to a
+        // small fraction of a cent
+        let x = (pitch % SEMITONE) as f32 / SEMITONE as f32 * (LN_2 / 12.0);
+        let ratio = SEMITONE_RATIOS[(pitch / SEMITONE) as usize]
+            * (1.0 + x + x * x / 2.0 + x * x * x / 6.0);
+
+        let period = REFERENCE_PERIOD_NS / ratio;
+        let period = if octave >= 0 {
+            period / (1u32 << octave) as f32
+        } else {
+            period * (1u32 << -octave) as f32
+        };
+        // Saturates for notes too low to be played anyway
+        Nanoseconds(period as u32)
+    }
+
+    /// Period that each string's fundamental needs to have for the notes mapped to it to be in
+    /// tune. Strings playing several notes use the one on their lowest harmonic.
+    pub fn string_periods(
+        &self,
+        note_map: &NoteMap,
+    ) -> [Option<Nanoseconds>; NUM_STRINGS as usize] {
+        let mut periods = [None; NUM_STRINGS as usize];
+        let mut harmonics = [u8::MAX; NUM_STRINGS as usize];
+        for (note, (string, harmonic)) in note_map.iter() {
+            let string = string as usize;
+            if string < NUM_STRINGS as usize && harmonic < harmonics[string] {
+                harmonics[string] = harmonic;
+                periods[string] = Some(Nanoseconds(
+                    self.period(note).0.saturating_mul(harmonic as u32),
+                ));
+            }
+        }
+        periods
+    }
+
+    /// Apply a MIDI Tuning Standard message. Scale/octave tuning only applies if it includes one
+    /// of `channels`, given as a bitmask. Returns whether the message was a tuning change.
+    pub fn handle_sysex(&mut self, message: &[u8], channels: u16) -> bool {
+        // The device ID is ignored, since there is only one device
+        let body = match message {
+            [SYSEX_START, id, _, MIDI_TUNING_STANDARD, body @ .., SYSEX_END]
+                if matches!(*id, NON_REAL_TIME | REAL_TIME) =>
+            {
+                body
+            }
+            _ => return false,
+        };
+
+        match body {
+            // The tuning program and bank are ignored, since there is only one tuning
+            [SINGLE_NOTE_TUNING_CHANGE, _, count, changes @ ..]
+            | [SINGLE_NOTE_TUNING_CHANGE_BANK, _, _, count, changes @ ..]
+                if changes.len() == *count as usize * 4 =>
+            {
+                for change in changes.chunks(4) {
+                    self.change_note(change[0], &change[1..]);
+                }
+                true
+            }
+            [SCALE_OCTAVE_TUNING_1, ff, gg, hh, offsets @ ..] if offsets.len() == 12 => {
+                if Self::channel_mask(*ff, *gg, *hh) & channels != 0 {
+                    let mut scale = [0; 12];
+                    // Cents, where 0x40 is in tune
+                    for (offset, &cents) in scale.iter_mut().zip(offsets) {
+                        *offset = (cents as i32 - 0x40) * SEMITONE / 100;
+                    }
+                    self.set_scale(&scale);
+                }
+                true
+            }
+            [SCALE_OCTAVE_TUNING_2, ff, gg, hh, offsets @ ..] if offsets.len() == 24 => {
+                if Self::channel_mask(*ff, *gg, *hh) & channels != 0 {
+                    let mut scale = [0; 12];
+                    // 14-bit values, where 0x2000 is in tune and the range covers 100 cents
+                    // either way
+                    for (offset, value) in scale.iter_mut().zip(offsets.chunks(2)) {
+                        let value = (value[0] as i32) << 7 | value[1] as i32;
+                        *offset = (value - 0x2000) * SEMITONE / 0x2000;
+                    }
+                    self.set_scale(&scale);
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Retune a note to an absolute pitch, given as a semitone followed by a 14-bit fraction of a
+    /// semitone
+    fn change_note(&mut self, note: u8, pitch: &[u8]) {
+        // Reserved to mean no change
+        if pitch == [0x7f, 0x7f, 0x7f] {
+            return;
+        }
+        if let Some(entry) = self.notes.get_mut(note as usize) {
+            *entry = (pitch[0] as i32 * SEMITONE) | (pitch[1] as i32) << 7 | pitch[2] as i32;
+        }
+    }
+
+    /// Tune every octave the same way, offsetting each note from equal temperament
+    fn set_scale(&mut self, offsets: &[i32; 12]) {
+        for (note, pitch) in self.notes.iter_mut().enumerate() {
+            *pitch = note as i32 * SEMITONE + offsets[note % 12];
+        }
+    }
+
+    /// Channels affected by a scale/octave tuning message, from its three channel bytes
+    fn channel_mask(ff: u8, gg: u8, hh: u8) -> u16 {
+        (ff as u16 & 0x3) << 14 | (gg as u16 & 0x7f) << 7 | hh as u16 & 0x7f
+    }
+}
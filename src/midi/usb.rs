@@ -0,0 +1,134 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+use heapless::Vec;
+
+use super::SYSEX_END;
+
+/// Code index numbers used in the header of USB MIDI event packets
+pub mod cin {
+    pub const SYSTEM_COMMON_2: u8 = 0x2;
+    pub const SYSTEM_COMMON_3: u8 = 0x3;
+    pub const SYSEX_START: u8 = 0x4;
+    /// A system common message with no data bytes, which shares its code with the end of a
+    /// SysEx message
+    pub const SINGLE_BYTE: u8 = 0x5;
+    pub const SYSEX_END_1: u8 = 0x5;
+    pub const SYSEX_END_2: u8 = 0x6;
+    pub const SYSEX_END_3: u8 = 0x7;
+    /// A single byte that doesn't need parsing, used for system realtime messages
+    pub const REALTIME: u8 = 0xf;
+}
+
+/// System realtime messages that control playback and time the arpeggiator
+pub mod realtime {
+    /// Sent 24 times per quarter note
+    pub const CLOCK: u8 = 0xf8;
+    pub const START: u8 = 0xfa;
+    pub const CONTINUE: u8 = 0xfb;
+    pub const STOP: u8 = 0xfc;
+}
+
+pub type Packet = [u8; 4];
+
+/// Splits a complete SysEx message, including the start and end bytes, into USB MIDI event
+/// packets.
+#[derive(Clone)]
+pub struct SysExPackets<'a> {
+    cable: u8,
+    data: &'a [u8],
+}
+
+impl<'a> SysExPackets<'a> {
+    pub fn new(cable: u8, data: &'a [u8]) -> Self {
+        Self { cable, data }
+    }
+}
+
+impl<'a> Iterator for SysExPackets<'a> {
+    type Item = Packet;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.is_empty() {
+            return None;
+        }
+
+        let len = self.data.len().min(3);
+        let (chunk, rest) = self.data.split_at(len);
+        self.data = rest;
+
+        let end = rest.is_empty();
+        let cin = match (end, len) {
+            (true, 1) => cin::SYSEX_END_1,
+            (true, 2) => cin::SYSEX_END_2,
+            (true, _) => cin::SYSEX_END_3,
+            (false, _) => cin::SYSEX_START,
+        };
+
+        let mut packet = [self.cable << 4 | cin, 0, 0, 0];
+        packet[1..=len].copy_from_slice(chunk);
+        Some(packet)
+    }
+}
+
+/// Whether a packet carries part of a SysEx message rather than a complete MIDI message
+pub fn is_sysex(packet: &Packet) -> bool {
+    matches!(
+        packet[0] & 0xf,
+        cin::SYSEX_START | cin::SYSEX_END_1 | cin::SYSEX_END_2 | cin::SYSEX_END_3
+    )
+}
+
+/// Status byte of a system realtime message, or `None` if the packet carries something else
+pub fn realtime_status(packet: &Packet) -> Option<u8> {
+    match packet[0] & 0xf {
+        cin::REALTIME if packet[1] >= 0xf8 => Some(packet[1]),
+        _ => None,
+    }
+}
+
+/// Reassembles SysEx messages from USB MIDI event packets. Messages longer than `N` bytes are
+/// discarded.
+pub struct SysExReceiver<const N: usize> {
+    message: Vec<u8, N>,
+    overflow: bool,
+}
+
+impl<const N: usize> SysExReceiver<N> {
+    pub const fn new() -> Self {
+        Self {
+            message: Vec::new(),
+            overflow: false,
+        }
+    }
+
+    /// Add a SysEx packet to the message, returning the message once it is complete
+    pub fn push(&mut self, packet: &Packet) -> Option<Vec<u8, N>> {
+        let (len, end) = match packet[0] & 0xf {
+            cin::SYSEX_START => (3, false),
+            // Also used for single byte system common messages, which aren't SysEx
+            cin::SYSEX_END_1 if packet[1] != SYSEX_END => return None,
+            cin::SYSEX_END_1 => (1, true),
+            cin::SYSEX_END_2 => (2, true),
+            cin::SYSEX_END_3 => (3, true),
+            _ => return None,
+        };
+
+        // Drop the remains of a message that was never terminated
+        if packet[1] == super::SYSEX_START {
+            self.message.clear();
+            self.overflow = false;
+        }
+
+        if self.message.extend_from_slice(&packet[1..=len]).is_err() {
+            self.overflow = true;
+        }
+
+        if end {
+            let message = core::mem::replace(&mut self.message, Vec::new());
+            let overflow = core::mem::replace(&mut self.overflow, false);
+            if !overflow {
+                return Some(message);
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,110 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+use core::ptr;
+
+use crate::pac;
+use pac::NVMCTRL;
+
+pub type Command = pac::nvmctrl::ctrla::CMD_A;
+
+/// Smallest unit of flash that can be written, in bytes
+pub const PAGE_SIZE: usize = 64;
+
+/// Smallest unit of flash that can be erased, in bytes
+pub const ROW_SIZE: usize = 4 * PAGE_SIZE;
+
+const PAGE_WORDS: usize = PAGE_SIZE / 4;
+
+/// Detects corrupted or partially written data in flash
+pub fn checksum(words: &[u32]) -> u32 {
+    words
+        .iter()
+        .fold(0u32, |sum, &word| sum.rotate_left(1).wrapping_add(word))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// The region is protected by the lock bits
+    Locked,
+    /// The controller rejected the command
+    Programming,
+}
+
+/// Erases and writes the internal flash. Reading needs no help, since the flash is mapped into
+/// the address space. The CPU stalls whenever it reads flash during an operation, which takes a
+/// few milliseconds for each row. Interrupt handlers run from flash too, so in the meantime the
+/// UART can overrun and sample buffers can miss their deadline; only use this while nothing is
+/// playing.
+pub struct Nvm {
+    nvmctrl: NVMCTRL,
+}
+
+impl Nvm {
+    pub fn new(nvmctrl: NVMCTRL) -> Self {
+        // Only write pages with an explicit command, so a page is never written half filled
+        nvmctrl.ctrlb.modify(|_, w| w.manw().set_bit());
+        Self { nvmctrl }
+    }
+
+    fn wait_ready(&self) {
+        while self.nvmctrl.intflag.read().ready().bit_is_clear() {}
+    }
+
+    fn command(&mut self, command: Command, address: u32) -> Result<(), Error> {
+        self.wait_ready();
+        // Clear the errors of earlier commands
+        self.nvmctrl
+            .status
+            .write(|w| w.locke().set_bit().proge().set_bit());
+        // The address is in 16-bit words
+        self.nvmctrl
+            .addr
+            .write(|w| unsafe { w.addr().bits(address >> 1) });
+        self.nvmctrl
+            .ctrla
+            .write(|w| w.cmdex().key().cmd().variant(command));
+        self.wait_ready();
+
+        let status = self.nvmctrl.status.read();
+        if status.locke().bit_is_set() {
+            Err(Error::Locked)
+        } else if status.proge().bit_is_set() {
+            Err(Error::Programming)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Erase the rows covering `len` bytes from `address`, which must be row aligned. Erased flash
+    /// reads as all ones.
+    pub fn erase(&mut self, address: u32, len: usize) -> Result<(), Error> {
+        assert_eq!(address as usize % ROW_SIZE, 0);
+        for row in (0..len).step_by(ROW_SIZE) {
+            self.command(Command::ER, address + row as u32)?;
+        }
+        Ok(())
+    }
+
+    /// Erase the rows starting at `address`, which must be row aligned, and write `data` to them.
+    /// Whatever `data` doesn't cover in the last row is left erased.
+    pub fn write(&mut self, address: u32, data: &[u32]) -> Result<(), Error> {
+        self.erase(address, data.len() * 4)?;
+        self.program(address, data)
+    }
+
+    /// Write `data` to the pages starting at `address`, which must be page aligned and already
+    /// erased. Whatever `data` doesn't cover in the last page is left erased.
+    pub fn program(&mut self, address: u32, data: &[u32]) -> Result<(), Error> {
+        assert_eq!(address as usize % PAGE_SIZE, 0);
+        for (i, page) in data.chunks(PAGE_WORDS).enumerate() {
+            let page_address = address + (i * PAGE_SIZE) as u32;
+            self.command(Command::PBC, page_address)?;
+            // Writes to the flash address space fill the page buffer, which only accepts 16 and
+            // 32-bit writes
+            for (j, &word) in page.iter().enumerate() {
+                unsafe { ptr::write_volatile((page_address as *mut u32).add(j), word) };
+            }
+            self.command(Command::WP, page_address)?;
+        }
+        Ok(())
+    }
+}
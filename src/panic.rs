@@ -0,0 +1,136 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+use core::fmt::{self, Write};
+use core::mem::MaybeUninit;
+use core::panic::PanicInfo;
+use core::sync::atomic::{self, Ordering};
+
+use crate::pac;
+
+/// Marks a valid record, which is unlikely to appear in uninitialized RAM by chance
+const MAGIC: u32 = 0x5041_4e43;
+
+const MESSAGE_LEN: usize = 128;
+
+/// Information about a panic, which survives a reset
+#[repr(C)]
+pub struct PanicRecord {
+    magic: u32,
+    line: u32,
+    column: u32,
+    message_len: u32,
+    message: [u8; MESSAGE_LEN],
+}
+
+impl PanicRecord {
+    pub fn line(&self) -> u32 {
+        self.line
+    }
+
+    pub fn column(&self) -> u32 {
+        self.column
+    }
+
+    /// Panic message, including the file name, truncated to fit in the record
+    pub fn message(&self) -> &[u8] {
+        &self.message[..(self.message_len as usize).min(MESSAGE_LEN)]
+    }
+}
+
+struct MessageWriter<'a> {
+    record: &'a mut PanicRecord,
+}
+
+impl<'a> Write for MessageWriter<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let start = self.record.message_len as usize;
+        let len = s.len().min(MESSAGE_LEN - start);
+        self.record.message[start..start + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.record.message_len += len as u32;
+        Ok(())
+    }
+}
+
+// Placed in the .uninit section so that it isn't zeroed at startup
+#[link_section = ".uninit.PANIC_RECORD"]
+static mut PANIC_RECORD: MaybeUninit<PanicRecord> = MaybeUninit::uninit();
+
+/// Return the record of a panic that happened before the last reset, if there was one. The record
+/// is cleared, so this only returns it once.
+pub fn take_record() -> Option<PanicRecord> {
+    let record = unsafe { PANIC_RECORD.assume_init_mut() };
+    if record.magic == MAGIC {
+        record.magic = 0;
+        Some(PanicRecord {
+            magic: 0,
+            line: record.line,
+            column: record.column,
+            message_len: record.message_len,
+            message: record.message,
+        })
+    } else {
+        None
+    }
+}
+
+/// Stop driving all coils, without relying on any driver state
+pub fn silence() {
+    let peripherals = unsafe { pac::Peripherals::steal() };
+
+    // Stop DMA so nothing can write the PWM registers anymore
+    peripherals.DMAC.ctrl.modify(|_, w| w.dmaenable().clear_bit());
+
+    peripherals.TCC0.ctrla.modify(|_, w| w.enable().clear_bit());
+    peripherals.TCC1.ctrla.modify(|_, w| w.enable().clear_bit());
+    peripherals.TCC2.ctrla.modify(|_, w| w.enable().clear_bit());
+
+    // A disabled TCC doesn't guarantee the outputs are low, so take the string pins (PA07-PA10
+    // and PA16-PA19) away from the TCCs and drive them low
+    let mut pins: u32 = 0b1111 << 7 | 0b1111 << 16;
+    // The analog string's amplifier input (PA02) too, rather than leaving it at the DAC's last
+    // output
+    if cfg!(feature = "analog-string") {
+        pins |= 1 << 2;
+    }
+    // And the low sides of the H-bridges (PA20-PA23), so no bridge is left half on
+    if cfg!(feature = "bipolar-strings") {
+        pins |= 0b1111 << 20;
+    }
+    peripherals.PORT.outclr0.write(|w| unsafe { w.bits(pins) });
+    peripherals.PORT.dirset0.write(|w| unsafe { w.bits(pins) });
+    for pin in (0..32).filter(|pin| pins & (1 << pin) != 0) {
+        peripherals.PORT.pincfg0_[pin].modify(|_, w| w.pmuxen().clear_bit());
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    cortex_m::interrupt::disable();
+
+    silence();
+
+    let record = unsafe { PANIC_RECORD.write(PanicRecord {
+        magic: 0,
+        line: 0,
+        column: 0,
+        message_len: 0,
+        message: [0; MESSAGE_LEN],
+    }) };
+    if let Some(location) = info.location() {
+        record.line = location.line();
+        record.column = location.column();
+    }
+    let mut writer = MessageWriter {
+        record: &mut *record,
+    };
+    // The line and column are kept separately, leaving more room for the message
+    match info.location() {
+        Some(location) => write!(writer, "{}: {}", location.file(), info.message()),
+        None => write!(writer, "{}", info.message()),
+    }
+    .ok();
+    record.magic = MAGIC;
+
+    loop {
+        atomic::compiler_fence(Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,107 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+use crate::pac;
+use core::ops::Deref;
+
+/// How a TCC responds to an event on its fault input
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fault {
+    /// Fault A, fed from the MC0 event input. Outputs are halted only while the fault input is
+    /// active, which suits cycle-by-cycle current limiting.
+    Recoverable,
+    /// Non-recoverable fault 1, fed from the EV1 event input. All outputs are forced low until
+    /// the fault is cleared by software.
+    NonRecoverable,
+}
+
+/// Handle to the fault state of a TCC, which remains usable after the DAC has been split into
+/// channels.
+pub struct FaultMonitor<TCC>
+where
+    TCC: Deref<Target = pac::tcc0::RegisterBlock>,
+{
+    tcc: TCC,
+}
+
+impl<TCC> FaultMonitor<TCC>
+where
+    TCC: Deref<Target = pac::tcc0::RegisterBlock>,
+{
+    /// Safety: the monitor only touches fault related registers, so it can coexist with the
+    /// PwmDac and its channels.
+    pub(super) unsafe fn new(tcc: &TCC) -> Self {
+        Self {
+            tcc: core::ptr::read(tcc),
+        }
+    }
+
+    /// Clear the fault interrupt flags, returning the fault that caused the interrupt, if any
+    pub fn on_interrupt(&self) -> Option<Fault> {
+        let flags = self.tcc.intflag.read();
+        self.tcc
+            .intflag
+            .write(|w| w.faulta().set_bit().fault1().set_bit());
+
+        if flags.fault1().bit() {
+            Some(Fault::NonRecoverable)
+        } else if flags.faulta().bit() {
+            Some(Fault::Recoverable)
+        } else {
+            None
+        }
+    }
+
+    /// Whether outputs are currently being held low by a non-recoverable fault
+    pub fn is_latched(&self) -> bool {
+        self.tcc.status.read().fault1().bit()
+    }
+
+    /// Release outputs held low by a non-recoverable fault. This has no effect while the fault
+    /// input is still active.
+    pub fn clear(&self) {
+        self.tcc.status.write(|w| w.fault1().set_bit());
+    }
+}
+
+pub(super) fn configure<TCC>(tcc: &TCC, fault: Fault)
+where
+    TCC: Deref<Target = pac::tcc0::RegisterBlock>,
+{
+    // Drive every output low when a non-recoverable fault occurs, and also while halted by a
+    // recoverable one
+    tcc.drvctrl.modify(|_, w| w
+        .nre0().set_bit()
+        .nre1().set_bit()
+        .nre2().set_bit()
+        .nre3().set_bit()
+        .nre4().set_bit()
+        .nre5().set_bit()
+        .nre6().set_bit()
+        .nre7().set_bit()
+        .nrv0().clear_bit()
+        .nrv1().clear_bit()
+        .nrv2().clear_bit()
+        .nrv3().clear_bit()
+        .nrv4().clear_bit()
+        .nrv5().clear_bit()
+        .nrv6().clear_bit()
+        .nrv7().clear_bit()
+    );
+
+    match fault {
+        Fault::Recoverable => {
+            tcc.fctrla.write(|w| w
+                .src().enable()
+                // Keep the outputs halted until the end of the PWM cycle, then restart
+                .keep().set_bit()
+                .restart().set_bit()
+                .halt().hw()
+            );
+            tcc.evctrl.modify(|_, w| w.mcei0().set_bit());
+            tcc.intenset.write(|w| w.faulta().set_bit());
+        }
+        Fault::NonRecoverable => {
+            tcc.evctrl.modify(|_, w| w.evact1().fault().tcei1().set_bit());
+            tcc.intenset.write(|w| w.fault1().set_bit());
+        }
+    }
+}
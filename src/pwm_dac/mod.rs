@@ -1,133 +1,176 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 use crate::hal::clock;
-use crate::hal::time::{Hertz, Nanoseconds, U32Ext};
+use crate::hal::time::{Hertz, Nanoseconds};
 
 use crate::dac::{Dac, DacDmaTrigger};
 use crate::pac;
+use core::marker::PhantomData;
 use core::ops::Deref;
 use pac::{PM, TCC0, TCC1, TCC2};
 use paste::paste;
 use seq_macro::seq;
 
+pub use fault::{Fault, FaultMonitor};
+pub use magnet_zither_protocol::pwm_plan::{Error, Plan};
+
+mod fault;
 mod reg;
 
-pub struct Channel<TCC, const ID: u8>
+pub trait Mode {
+    /// Whether the output can be driven in both directions
+    const BIPOLAR: bool;
+
+    /// PWM duty cycle that produces no net output, given the number of PWM steps
+    fn zero(top: u8) -> u8 {
+        if Self::BIPOLAR {
+            top / 2
+        } else {
+            0
+        }
+    }
+}
+
+/// Each channel drives a single output, which is either off or pulling in one direction.
+pub struct Unipolar;
+
+impl Mode for Unipolar {
+    const BIPOLAR: bool = false;
+}
+
+/// Each channel drives an H-bridge from a pair of complementary outputs, with dead time inserted
+/// between them. The bridge is driven in locked anti-phase, so a 50% duty cycle produces no net
+/// current and the coil can be driven in both directions. Only TCC0 has the waveform extension
+/// needed for this.
+pub struct Bipolar;
+
+impl Mode for Bipolar {
+    const BIPOLAR: bool = true;
+}
+
+pub struct Channel<TCC, const ID: u8, M = Unipolar>
 where
     TCC: Deref<Target = pac::tcc0::RegisterBlock>,
 {
     reg: reg::RegisterBlock<TCC, ID>,
     sample_period: Nanoseconds,
+    top: u8,
+    _mode: PhantomData<M>,
 }
 
-impl<TCC, const ID: u8> Channel<TCC, ID>
+impl<TCC, const ID: u8, M> Channel<TCC, ID, M>
 where
     TCC: Deref<Target = pac::tcc0::RegisterBlock>,
 {
-    unsafe fn new(driver: &PwmDac<TCC>) -> Self {
+    unsafe fn new(driver: &PwmDac<TCC, M>) -> Self {
         Self {
             reg: reg::RegisterBlock::new(&driver.tcc),
             sample_period: driver.sample_period,
+            top: driver.top,
+            _mode: PhantomData,
         }
     }
 }
 
-impl<TCC, const ID: u8> Dac for Channel<TCC, ID>
+impl<TCC, const ID: u8, M: Mode> Dac for Channel<TCC, ID, M>
 where
     Self: DacDmaTrigger,
     TCC: Deref<Target = pac::tcc0::RegisterBlock>,
 {
-    type Amplitude = u8;
-    const MAX_AMPLITUDE: Self::Amplitude = PwmDac::<TCC>::MAX_AMPLITUDE;
+    type Sample = u8;
+    // A few hundred steps is not enough for quiet sustain levels, so gain 4 more bits by
+    // modulating the duty cycle over successive PWM periods
+    const NOISE_SHAPING_BITS: u32 = 4;
 
-    fn set_amplitude(&mut self, amplitude: Self::Amplitude) {
+    fn max_amplitude(&self) -> u32 {
+        // Bipolar outputs must be able to swing equally far in both directions
+        let max = if M::BIPOLAR {
+            M::zero(self.top)
+        } else {
+            self.top
+        };
+        (max as u32) << Self::NOISE_SHAPING_BITS
+    }
+
+    fn zero(&self) -> u32 {
+        (M::zero(self.top) as u32) << Self::NOISE_SHAPING_BITS
+    }
+
+    fn set_amplitude(&mut self, sample: Self::Sample) {
         while self.reg.syncbusy_ccb().bit() {}
         self.reg
             .ccb()
-            .write(|w| unsafe { w.ccb().bits(amplitude as u32) });
+            .write(|w| unsafe { w.ccb().bits(sample as u32) });
     }
 
     fn sample_period(&self) -> Nanoseconds {
         self.sample_period
     }
 
-    fn dma_ptr(&self) -> *mut Self::Amplitude {
+    fn dma_ptr(&self) -> *mut Self::Sample {
         self.reg.ccb().as_ptr().cast()
     }
 }
 
-impl<const ID: u8> DacDmaTrigger for Channel<pac::TCC0, ID> {
+impl<const ID: u8, M> DacDmaTrigger for Channel<pac::TCC0, ID, M> {
     const DMA_TRIGGER_SOURCE: samd_dma::TriggerSource = samd_dma::TriggerSource::Tcc0Ovf;
 }
 
-impl<const ID: u8> DacDmaTrigger for Channel<pac::TCC1, ID> {
+impl<const ID: u8, M> DacDmaTrigger for Channel<pac::TCC1, ID, M> {
     const DMA_TRIGGER_SOURCE: samd_dma::TriggerSource = samd_dma::TriggerSource::Tcc1Ovf;
 }
 
-impl<const ID: u8> DacDmaTrigger for Channel<pac::TCC2, ID> {
+impl<const ID: u8, M> DacDmaTrigger for Channel<pac::TCC2, ID, M> {
     const DMA_TRIGGER_SOURCE: samd_dma::TriggerSource = samd_dma::TriggerSource::Tcc2Ovf;
 }
 
-pub struct PwmDac<TCC>
+pub struct PwmDac<TCC, M = Unipolar>
 where
     TCC: Deref<Target = pac::tcc0::RegisterBlock>,
 {
     tcc: TCC,
     sample_period: Nanoseconds,
+    top: u8,
+    _mode: PhantomData<M>,
 }
 
-impl<TCC> PwmDac<TCC>
+impl<TCC, M: Mode> PwmDac<TCC, M>
 where
     TCC: Deref<Target = pac::tcc0::RegisterBlock>,
 {
-    const MAX_AMPLITUDE: u8 = 240;
-
     pub fn sample_period(&self) -> Nanoseconds {
         self.sample_period
     }
-}
 
-macro_rules! pwm_dac {
-    ($(($TCC:ident, $channels: literal, $clock:ident, $apmask:ident, $apbits:ident),)+) => {
-        paste! {
-        $(
-        seq!(CH in 0..$channels {
+    /// Shut down the outputs in hardware in response to a fault event, which must be routed to the
+    /// TCC through the event system.
+    pub fn enable_fault(&mut self, fault: Fault) {
+        // Fault configuration is enable-protected
+        while self.tcc.syncbusy.read().enable().bit() {}
+        self.tcc.ctrla.modify(|_, w| w.enable().clear_bit());
+        while self.tcc.syncbusy.read().enable().bit() {}
 
-pub struct [<Channels $TCC>] (
-    #(pub Channel<$TCC, CH>,)*
-);
+        fault::configure(&self.tcc, fault);
 
-impl PwmDac<$TCC> {
-    pub fn new(
-        clock: &clock::$clock,
-        freq: impl Into<Hertz>,
-        tcc: $TCC,
-        pm: &mut PM,
-    ) -> Self {
-        // Power on TCC
-        pm.$apmask.modify(|_, w| w.$apbits().set_bit());
+        self.tcc.ctrla.modify(|_, w| w.enable().set_bit());
+        while self.tcc.syncbusy.read().enable().bit() {}
 
-        let divider = {
-            // 240 PWM steps and twice the frequency because dual-slope PWM has two overflows per
-            // cycle
-            let ideal_timer_freq = freq.into().0 * Self::MAX_AMPLITUDE as u32 * 2;
-
-            let divider = (clock.freq().0 / ideal_timer_freq).next_power_of_two();
-            match divider {
-                1 | 2 | 4 | 8 | 16 | 64 | 256 | 1024 => divider,
-                // There are a couple of gaps, so we round up to the next largest
-                // divider; we'll need to count twice as many but it will work.
-                32 => 64,
-                128 => 256,
-                512 => 1024,
-                // Catch all case; this is lame.  Would be great to detect this
-                // and fail at compile time.
-                _ => 1024,
-            }
-        };
+        while self.tcc.syncbusy.read().ctrlb().bit() {}
+        self.tcc.ctrlbset.write(|w| w.cmd().retrigger());
+    }
 
-        let sample_period = (clock.freq().0 / divider / Self::MAX_AMPLITUDE as u32 / 2).hz().into();
+    pub fn fault_monitor(&self) -> FaultMonitor<TCC> {
+        unsafe { FaultMonitor::new(&self.tcc) }
+    }
+
+    fn init(tcc: TCC, clock_freq: Hertz, plan: Plan, dead_time: Option<u8>) -> Result<Self, Error> {
+        plan.check_clock(clock_freq.0)?;
 
-        let s = Self { tcc, sample_period };
+        let s = Self {
+            tcc,
+            sample_period: Hertz(plan.sample_rate()).into(),
+            top: plan.top(),
+            _mode: PhantomData,
+        };
 
         // Disable TCC
         while s.tcc.syncbusy.read().enable().bit() {}
@@ -139,7 +182,7 @@ impl PwmDac<$TCC> {
 
         // Set prescaler
         s.tcc.ctrla.write(|w| {
-            match divider {
+            match plan.divider() {
                 1 => w.prescaler().div1(),
                 2 => w.prescaler().div2(),
                 4 => w.prescaler().div4(),
@@ -152,6 +195,21 @@ impl PwmDac<$TCC> {
             }
         });
 
+        if let Some(dead_time) = dead_time {
+            // Output the complement of each channel on WO[x + 4], with dead time inserted
+            // between the low and high side switching. This register is enable-protected, so it
+            // must be written before the TCC is enabled.
+            s.tcc.wexctrl.write(|w| unsafe { w
+                .otmx().bits(0)
+                .dtien0().set_bit()
+                .dtien1().set_bit()
+                .dtien2().set_bit()
+                .dtien3().set_bit()
+                .dtls().bits(dead_time)
+                .dths().bits(dead_time)
+            });
+        }
+
         while s.tcc.syncbusy.read().wave().bit() {}
         // Enable dual-slope PWM (DSTOP) and set correct output polarity
         s.tcc.wave.write(|w| w
@@ -164,7 +222,15 @@ impl PwmDac<$TCC> {
 
         // Set PWM duty cycle range
         while s.tcc.syncbusy.read().per().bit() {}
-        s.tcc.per().write(|w| unsafe { w.bits(Self::MAX_AMPLITUDE as u32) });
+        s.tcc.per().write(|w| unsafe { w.bits(s.top as u32) });
+
+        if M::BIPOLAR {
+            // A zero duty cycle drives the bridge fully in one direction, so start at the
+            // midpoint instead
+            for cc in s.tcc.cc().iter() {
+                cc.write(|w| unsafe { w.cc().bits(M::zero(s.top) as u32) });
+            }
+        }
 
         // Enable TCC
         s.tcc.ctrla.modify(|_, w| w.enable().set_bit());
@@ -174,10 +240,54 @@ impl PwmDac<$TCC> {
         while s.tcc.syncbusy.read().ctrlb().bit() {}
         s.tcc.ctrlbset.write(|w| w.cmd().retrigger());
 
-        s
+        Ok(s)
+    }
+}
+
+impl PwmDac<TCC0, Bipolar> {
+    /// Create a bipolar PWM DAC, which drives an H-bridge for each channel using the
+    /// complementary outputs. `dead_time` is the number of GCLK cycles during which both sides of
+    /// a bridge leg are turned off when switching.
+    pub fn new_bipolar(
+        clock: &clock::Tcc0Tcc1Clock,
+        plan: Plan,
+        dead_time: u8,
+        tcc: TCC0,
+        pm: &mut PM,
+    ) -> Result<Self, Error> {
+        // Power on TCC
+        pm.apbcmask.modify(|_, w| w.tcc0_().set_bit());
+
+        Self::init(tcc, clock.freq(), plan, Some(dead_time))
+    }
+}
+
+macro_rules! pwm_dac {
+    ($(($TCC:ident, $channels: literal, $clock:ident, $apmask:ident, $apbits:ident),)+) => {
+        paste! {
+        $(
+        seq!(CH in 0..$channels {
+
+pub struct [<Channels $TCC>]<M = Unipolar> (
+    #(pub Channel<$TCC, CH, M>,)*
+);
+
+impl PwmDac<$TCC> {
+    pub fn new(
+        clock: &clock::$clock,
+        plan: Plan,
+        tcc: $TCC,
+        pm: &mut PM,
+    ) -> Result<Self, Error> {
+        // Power on TCC
+        pm.$apmask.modify(|_, w| w.$apbits().set_bit());
+
+        Self::init(tcc, clock.freq(), plan, None)
     }
+}
 
-    pub fn split(self) -> [<Channels $TCC>] {
+impl<M: Mode> PwmDac<$TCC, M> {
+    pub fn split(self) -> [<Channels $TCC>]<M> {
         unsafe {
             [<Channels $TCC>](
                 #(Channel::new(&self),)*
@@ -4,6 +4,7 @@ use hal::time::{Nanoseconds, U32Ext};
 use num_rational::Ratio;
 
 pub use dac_driver::DacDriver;
+pub use thermal::{ThermalConfig, ThermalState};
 
 use crate::ac;
 use crate::app::monotonics;
@@ -11,9 +12,53 @@ use crate::hal;
 use crate::pac;
 
 pub mod dac_driver;
+mod thermal;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CalibrationError {
+    /// The string has no frequency meter attached
+    NoFrequencyMeter,
+    /// The string is already playing
+    Busy,
+}
+
+/// What a string is doing, without the details of the note
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Phase {
+    Attack,
+    Sustain,
+    Release,
+    WaitStabilize,
+    SampleFrequency,
+    Off,
+}
+
+/// Result of a single frequency measurement
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Sample {
+    /// The measured period was used to update the tuning
+    Accepted(Nanoseconds),
+    /// The measured period was too far from the current tuning and was ignored
+    Outlier(Nanoseconds),
+    /// The frequency meter overflowed
+    Error,
+}
+
+/// Shortest period a string can be driven at, which leaves the PWM DACs at least two samples per
+/// period at their 25 kHz sample rate
+pub const MIN_DRIVE_PERIOD: Nanoseconds = Nanoseconds(80_000);
 
 pub trait Driver {
-    fn set(&mut self, period: Nanoseconds, amplitude: u8, invert: bool);
+    /// Drive the string at `period` and `amplitude`, where `u16::MAX` is full scale. With
+    /// `glide`, the amplitude moves there smoothly over the next buffer instead of jumping.
+    fn set(&mut self, period: Nanoseconds, amplitude: u16, invert: bool, glide: bool);
+}
+
+/// Controller that shapes the amplitude of a note in bowing mode
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BowingController {
+    Breath,
+    Expression,
 }
 
 struct ScheduledState {
@@ -46,6 +91,7 @@ impl State {
     }
 }
 
+#[derive(Clone, Copy)]
 pub struct Config {
     pub period: Nanoseconds,
     pub attack_time: Duration,
@@ -55,6 +101,17 @@ pub struct Config {
     pub release_amplitude: u8,
     pub stabilize_time: Duration,
     pub sample_time: Duration,
+    /// Notes held for longer than this are released, in case the note off was lost
+    pub max_note_time: Option<Duration>,
+    pub thermal: ThermalConfig,
+    /// How much pressure scales the sustain amplitude, where 0 ignores pressure and `u8::MAX`
+    /// silences the string without any
+    pub pressure_depth: u8,
+    /// Time constant of the smoothing applied to pressure, so changes don't click
+    pub pressure_smoothing: Duration,
+    /// In bowing mode, notes skip the attack and their amplitude follows this controller for as
+    /// long as they are held, scaled to the sustain amplitude
+    pub bowing: Option<BowingController>,
 }
 
 impl Default for Config {
@@ -68,7 +125,75 @@ impl Default for Config {
             release_amplitude: 0,
             stabilize_time: Duration::millis(50),
             sample_time: Duration::millis(500),
+            max_note_time: Some(Duration::secs(60)),
+            thermal: ThermalConfig::default(),
+            pressure_depth: 0,
+            pressure_smoothing: Duration::millis(50),
+            bowing: None,
+        }
+    }
+}
+
+/// Gradual change of the drive amplitude, so a configuration change doesn't make it jump, which
+/// would click
+struct Ramp {
+    from: u16,
+    start: Instant,
+    duration: Duration,
+    /// Ramps only smooth over a configuration change, so they end when the envelope moves on
+    phase: Phase,
+}
+
+impl Ramp {
+    /// Amplitude partway from the starting amplitude to `to`, or `None` once the ramp is over
+    fn amplitude(&self, now: Instant, to: u16) -> Option<u16> {
+        let elapsed = (now - self.start).to_millis();
+        let duration = self.duration.to_millis();
+        if elapsed >= duration {
+            return None;
+        }
+        let (from, to) = (self.from as i32, to as i32);
+        Some((from + (to - from) * elapsed as i32 / duration as i32) as u16)
+    }
+}
+
+/// Channel pressure or polyphonic aftertouch applied to the string, smoothed so it changes
+/// gradually
+struct Pressure {
+    /// Most recently received pressure
+    target: u8,
+    /// Pressure after smoothing, in 1/256ths
+    smoothed: u32,
+    updated: Instant,
+}
+
+impl Pressure {
+    const MAX: u8 = 127;
+
+    /// Move the smoothed pressure towards the target by the time elapsed since the last step
+    fn step(&mut self, now: Instant, time_constant: Duration) {
+        // Limited so the step can't overflow
+        let elapsed = (now - self.updated).to_millis().min(u16::MAX as u32) as i32;
+        if elapsed == 0 {
+            return;
         }
+        self.updated = now;
+
+        let target = (self.target as i32) << 8;
+        let error = target - self.smoothed as i32;
+        // First order low pass filter, which jumps to the target once the error rounds to zero
+        let step = error * elapsed / (time_constant.to_millis() as i32 + elapsed).max(1);
+        self.smoothed = if step == 0 {
+            target as u32
+        } else {
+            (self.smoothed as i32 + step) as u32
+        };
+    }
+
+    /// Scale factor for the sustain amplitude
+    fn scale(&self, depth: u8) -> u8 {
+        let depth = depth as u32;
+        (u8::MAX as u32 - depth + depth * self.smoothed / ((Self::MAX as u32) << 8)) as u8
     }
 }
 
@@ -77,6 +202,28 @@ pub struct Controller<D: Driver> {
     freq_meter: Option<ac::FrequencyMeter<pac::TC3>>,
     config: Config,
     state: ScheduledState,
+    thermal: thermal::ThermalModel,
+    /// Amplitude requested by the envelope, after thermal derating
+    commanded_amplitude: u16,
+    /// Scale factor applied by the global power budget, where `u8::MAX` means no limiting
+    power_scale: u8,
+    /// Amplitude currently applied to the driver
+    amplitude: u16,
+    note_start: Instant,
+    forced_releases: u16,
+    /// Release the current note as soon as the attack finishes, so the frequency can be measured
+    calibrating: bool,
+    /// Most recent frequency measurement that hasn't been reported yet
+    last_sample: Option<Sample>,
+    ramp: Option<Ramp>,
+    pressure: Pressure,
+    /// Latest breath controller value
+    breath: u8,
+    /// Latest expression controller value
+    expression: u8,
+    /// Period the string is supposed to be tuned to, which frequency measurements are checked
+    /// against
+    target_period: Option<Nanoseconds>,
 }
 
 impl<D: Driver> Controller<D> {
@@ -87,22 +234,52 @@ impl<D: Driver> Controller<D> {
         freq_meter: Option<ac::FrequencyMeter<pac::TC3>>,
         config: Config,
     ) -> Self {
-        monotonics::now();
+        let now = monotonics::now();
 
         Self {
             driver,
             freq_meter,
             config,
             state: State::Off.indefinite(),
+            thermal: thermal::ThermalModel::new(now),
+            commanded_amplitude: 0,
+            power_scale: u8::MAX,
+            amplitude: 0,
+            note_start: now,
+            forced_releases: 0,
+            calibrating: false,
+            last_sample: None,
+            ramp: None,
+            pressure: Pressure {
+                target: 0,
+                smoothed: 0,
+                updated: now,
+            },
+            breath: 0,
+            expression: Self::MAX_VELOCITY,
+            target_period: None,
         }
     }
 
-    fn apply_velocity(amplitude: u8, velocity: u8) -> u8 {
-        (amplitude as u32 * velocity.min(Self::MAX_VELOCITY) as u32 / Self::MAX_VELOCITY as u32)
-            as u8
+    /// Scale a configured amplitude by the note velocity. The result is 16-bit, so the scale
+    /// factors applied after this don't compound the rounding of the 8-bit configuration.
+    fn apply_velocity(amplitude: u8, velocity: u8) -> u16 {
+        // Multiplying by 257 maps u8::MAX to u16::MAX
+        (amplitude as u32 * 257 * velocity.min(Self::MAX_VELOCITY) as u32
+            / Self::MAX_VELOCITY as u32) as u16
+    }
+
+    fn scale(amplitude: u16, scale: u8) -> u16 {
+        (amplitude as u32 * scale as u32 / u8::MAX as u32) as u16
     }
 
     fn update_driver(&mut self) {
+        self.drive(false);
+    }
+
+    /// Apply the current state to the driver. With `glide`, the amplitude moves to its new value
+    /// over the next buffer, for changes that are steps along a smooth curve.
+    fn drive(&mut self, glide: bool) {
         let mut invert = false;
         let (amplitude, harmonic) = match self.state.state {
             State::Attack { velocity, harmonic } => (
@@ -110,7 +287,13 @@ impl<D: Driver> Controller<D> {
                 harmonic,
             ),
             State::Sustain { velocity, harmonic } => (
-                Self::apply_velocity(self.config.sustain_amplitude, velocity),
+                Self::scale(
+                    Self::apply_velocity(
+                        self.config.sustain_amplitude,
+                        self.bow_level().unwrap_or(velocity),
+                    ),
+                    self.pressure.scale(self.config.pressure_depth),
+                ),
                 harmonic,
             ),
             State::Release { velocity, harmonic } => {
@@ -122,11 +305,36 @@ impl<D: Driver> Controller<D> {
             }
             _ => (0, 1),
         };
-        self.driver.set(
-            (self.config.period.0 / harmonic as u32).ns(),
-            amplitude,
-            invert,
-        );
+
+        let now = monotonics::now();
+        // Integrate the heat from the previous amplitude before derating the new one
+        self.thermal
+            .update(&self.config.thermal, now, self.amplitude);
+        let derating = self.thermal.state(&self.config.thermal).derating;
+        let amplitude = Self::scale(amplitude, derating);
+        self.commanded_amplitude = amplitude;
+        let amplitude = Self::scale(amplitude, self.power_scale);
+        let phase = self.phase();
+        let amplitude = match self.ramp.take() {
+            Some(ramp) if ramp.phase == phase => match ramp.amplitude(now, amplitude) {
+                Some(ramped) => {
+                    self.ramp = Some(ramp);
+                    ramped
+                }
+                None => amplitude,
+            },
+            _ => amplitude,
+        };
+        self.thermal.update(&self.config.thermal, now, amplitude);
+        self.amplitude = amplitude;
+
+        // A bowed note follows its controller continuously, so the steps between its values are
+        // smoothed over
+        let glide = glide || (phase == Phase::Sustain && self.config.bowing.is_some());
+        // Settings are checked against the minimum, but calibration can still nudge the period
+        // a little below it
+        let period = (self.config.period.0 / harmonic as u32).max(MIN_DRIVE_PERIOD.0);
+        self.driver.set(period.ns(), amplitude, invert, glide);
 
         if let Some(freq_meter) = &self.freq_meter {
             match self.state.state {
@@ -140,22 +348,192 @@ impl<D: Driver> Controller<D> {
         &mut self.driver
     }
 
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Modify the configuration, applying it immediately to the current note
+    pub fn update_config<R>(&mut self, f: impl FnOnce(&mut Config) -> R) -> R {
+        let r = f(&mut self.config);
+        self.update_driver();
+        r
+    }
+
+    /// Replace the configuration. A note that is playing moves to its new amplitude over
+    /// `duration`, rather than jumping to it.
+    pub fn ramp_config(&mut self, config: Config, duration: Duration) {
+        self.config = config;
+        self.ramp = Some(Ramp {
+            from: self.amplitude,
+            start: monotonics::now(),
+            duration,
+            phase: self.phase(),
+        });
+        self.update_driver();
+    }
+
+    /// Step the amplitude along a ramp started by `ramp_config()`. This should be called
+    /// regularly, like `update_thermal()`.
+    pub fn update_ramp(&mut self) {
+        if self.ramp.is_some() {
+            self.update_driver();
+        }
+    }
+
+    /// Set the pressure applied to the string, from 0 to 127. The sustain amplitude follows it
+    /// gradually as `update_pressure()` is called.
+    pub fn set_pressure(&mut self, pressure: u8) {
+        self.pressure.target = pressure.min(Pressure::MAX);
+    }
+
+    /// Step the smoothed pressure towards the last pressure that was set. This should be called
+    /// regularly, like `update_thermal()`.
+    pub fn update_pressure(&mut self) {
+        let depth = self.config.pressure_depth;
+        let scale = self.pressure.scale(depth);
+        self.pressure
+            .step(monotonics::now(), self.config.pressure_smoothing);
+        if self.phase() == Phase::Sustain && self.pressure.scale(depth) != scale {
+            // The smoothed pressure only changes between buffers, so glide to avoid stepping
+            self.drive(true);
+        }
+    }
+
+    /// Level of the controller that shapes the note in bowing mode, or `None` outside of it
+    fn bow_level(&self) -> Option<u8> {
+        self.config.bowing.map(|controller| match controller {
+            BowingController::Breath => self.breath,
+            BowingController::Expression => self.expression,
+        })
+    }
+
+    /// Set the value of a controller that can be used for bowing, from 0 to 127
+    pub fn set_bowing_controller(&mut self, controller: BowingController, value: u8) {
+        let level = self.bow_level();
+        match controller {
+            BowingController::Breath => self.breath = value,
+            BowingController::Expression => self.expression = value,
+        }
+        if self.phase() == Phase::Sustain && self.bow_level() != level {
+            self.update_driver();
+        }
+    }
+
+    /// Set the period the string is supposed to be tuned to. Without one, measurements are
+    /// checked against the current period instead.
+    pub fn set_target_period(&mut self, period: Option<Nanoseconds>) {
+        self.target_period = period;
+    }
+
+    pub fn target_period(&self) -> Option<Nanoseconds> {
+        self.target_period
+    }
+
+    /// Integrate the heat produced by the coil since the last update, and reduce the amplitude if
+    /// the coil is getting too hot. This should be called regularly, since a sustained note
+    /// doesn't otherwise update anything.
+    pub fn update_thermal(&mut self) {
+        let derating = self.thermal.state(&self.config.thermal).derating;
+        self.thermal
+            .update(&self.config.thermal, monotonics::now(), self.amplitude);
+        if self.thermal.state(&self.config.thermal).derating != derating {
+            self.update_driver();
+        }
+    }
+
+    pub fn thermal_state(&self) -> ThermalState {
+        self.thermal.state(&self.config.thermal)
+    }
+
+    /// Amplitude the string would be driven at without the global power budget, where
+    /// `u8::MAX` is full scale
+    pub fn commanded_amplitude(&self) -> u8 {
+        (self.commanded_amplitude >> 8) as u8
+    }
+
+    /// Scale the drive amplitude to keep the total power of all strings within budget
+    pub fn set_power_scale(&mut self, scale: u8) {
+        if scale != self.power_scale {
+            self.power_scale = scale;
+            self.update_driver();
+        }
+    }
+
+    pub fn phase(&self) -> Phase {
+        match self.state.state {
+            State::Attack { .. } => Phase::Attack,
+            State::Sustain { .. } => Phase::Sustain,
+            State::Release { .. } => Phase::Release,
+            State::WaitStabilize => Phase::WaitStabilize,
+            State::SampleFrequency => Phase::SampleFrequency,
+            State::Off => Phase::Off,
+        }
+    }
+
+    /// Most recent frequency measurement since the last call
+    pub fn take_sample(&mut self) -> Option<Sample> {
+        self.last_sample.take()
+    }
+
+    /// Number of notes that were released because they exceeded the maximum note time
+    pub fn forced_releases(&self) -> u16 {
+        self.forced_releases
+    }
+
+    /// Sustain a note started at `start`, until it's released or held for too long
+    fn sustain(&self, velocity: u8, harmonic: u8, start: Instant) -> ScheduledState {
+        let state = State::Sustain { velocity, harmonic };
+        match self.config.max_note_time {
+            Some(max_note_time) => state.schedule((self.note_start + max_note_time).max(start)),
+            None => state.indefinite(),
+        }
+    }
+
     pub fn on(&mut self, velocity: u8, harmonic: u8) -> Option<Instant> {
+        self.start(velocity, harmonic, self.config.bowing.is_some())
+    }
+
+    /// Start a note, going straight to the sustain if it's bowed
+    fn start(&mut self, velocity: u8, harmonic: u8, bowed: bool) -> Option<Instant> {
         let now = monotonics::now();
 
         match &self.state.state {
             State::Off | State::Release { .. } | State::WaitStabilize | State::SampleFrequency => {
-                Some(State::Attack { velocity, harmonic }.schedule(now + self.config.attack_time))
+                self.note_start = now;
+                Some(if bowed {
+                    self.sustain(velocity, harmonic, now)
+                } else {
+                    State::Attack { velocity, harmonic }.schedule(now + self.config.attack_time)
+                })
             }
             _ => None,
         }
         .map(|state| {
             self.state = state;
+            self.calibrating = false;
             self.update_driver();
         })
         .and(self.state.end)
     }
 
+    /// Pluck the string at full velocity and release it after the attack, so its frequency is
+    /// measured as it rings down
+    pub fn calibrate(&mut self) -> Result<Instant, CalibrationError> {
+        if self.freq_meter.is_none() {
+            return Err(CalibrationError::NoFrequencyMeter);
+        }
+        if !matches!(self.state.state, State::Off) {
+            return Err(CalibrationError::Busy);
+        }
+
+        // Calibration needs the attack to excite the string, even in bowing mode
+        let next = self
+            .start(Self::MAX_VELOCITY, 1, false)
+            .ok_or(CalibrationError::Busy)?;
+        self.calibrating = true;
+        Ok(next)
+    }
+
     pub fn off(&mut self, _velocity: u8) -> Option<Instant> {
         let now = monotonics::now();
 
@@ -176,28 +554,46 @@ impl<D: Driver> Controller<D> {
         .and(self.state.end)
     }
 
+    /// Immediately stop driving the string, skipping the release
+    pub fn mute(&mut self) {
+        self.state = State::Off.indefinite();
+        self.update_driver();
+    }
+
     pub fn update(&mut self) -> Option<Instant> {
         let now = monotonics::now();
 
         // When new commands come in, old updates remain scheduled but are no longer valid.
         // Therefore, we need to check whether the current state is really supposed to end
-        // now.
-        if let Some(end) = self.state.end {
-            if now < end {
-                return None;
-            }
-        }
-
-        let start = self.state.end.unwrap_or(now);
+        // now. Indefinite states only end when a new command comes in.
+        let start = match self.state.end {
+            Some(end) if now >= end => end,
+            _ => return None,
+        };
 
         match &self.state.state {
-            State::Attack { velocity, harmonic } => Some(
-                State::Sustain {
-                    velocity: *velocity,
-                    harmonic: *harmonic,
-                }
-                .indefinite(),
-            ),
+            State::Attack { velocity, harmonic } if self.calibrating => {
+                self.calibrating = false;
+                Some(
+                    State::Release {
+                        velocity: *velocity,
+                        harmonic: *harmonic,
+                    }
+                    .schedule(start + self.config.release_time),
+                )
+            }
+            State::Attack { velocity, harmonic } => Some(self.sustain(*velocity, *harmonic, start)),
+            State::Sustain { velocity, harmonic } => {
+                // Held for too long
+                self.forced_releases = self.forced_releases.saturating_add(1);
+                Some(
+                    State::Release {
+                        velocity: *velocity,
+                        harmonic: *harmonic,
+                    }
+                    .schedule(start + self.config.release_time),
+                )
+            }
             State::Release { .. } if self.freq_meter.is_some() => {
                 Some(State::WaitStabilize.schedule(start + self.config.stabilize_time))
             }
@@ -206,7 +602,7 @@ impl<D: Driver> Controller<D> {
                 Some(State::SampleFrequency.schedule(start + self.config.sample_time))
             }
             State::SampleFrequency => Some(State::Off.indefinite()),
-            State::Sustain { .. } | State::Off => None,
+            State::Off => None,
         }
         .map(|state| {
             self.state = state;
@@ -225,16 +621,16 @@ impl<D: Driver> Controller<D> {
         if let Some(freq_meter) = &self.freq_meter {
             // Ignore readings if the error flag is set
             if freq_meter.on_interrupt().is_err() {
-                // writeln!(uart as &mut dyn hal::ehal::serial::Write<_, Error = _>, "error");
+                self.last_sample = Some(Sample::Error);
                 return;
             }
 
-            // Ignore outliers more than 10% different from the current value
+            // Ignore outliers more than 10% different from the target, which lets the period
+            // follow the string as it is tuned
+            let expected = self.target_period.unwrap_or(self.config.period);
             let period_sample = freq_meter.period_ns();
-            if ((self.config.period.0 as i32 - period_sample.0 as i32).abs() as u32)
-                > self.config.period.0 / 10
-            {
-                // writeln!(uart as &mut dyn hal::ehal::serial::Write<_, Error = _>, "out of range: {}", Hertz::from(period_sample).0);
+            if ((expected.0 as i32 - period_sample.0 as i32).abs() as u32) > expected.0 / 10 {
+                self.last_sample = Some(Sample::Outlier(period_sample));
                 return;
             }
 
@@ -243,8 +639,7 @@ impl<D: Driver> Controller<D> {
             .round()
             .numer()
             .ns();
-
-            // writeln!(uart as &mut dyn hal::ehal::serial::Write<_, Error = _>, "sample: {}, new: {}", Hertz::from(period_sample).0, Hertz::from(self.config.period).0);
+            self.last_sample = Some(Sample::Accepted(period_sample));
         }
     }
 }
@@ -1,5 +1,5 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
-use num_traits::{cast, PrimInt, Zero};
+use num_traits::{cast, PrimInt};
 
 use crate::dac::Dac;
 use crate::hal::time::{Nanoseconds, U32Ext};
@@ -25,45 +25,92 @@ impl DmaResources<u8> {
     }
 }
 
+// Only used by the analog string
+#[cfg_attr(not(feature = "analog-string"), allow(dead_code))]
+impl DmaResources<u16> {
+    pub const fn new() -> Self {
+        Self {
+            buffer_1: [0; BUFFER_SIZE],
+            buffer_2: [0; BUFFER_SIZE],
+            descriptor_2: samd_dma::TransferDescriptor::new(),
+        }
+    }
+}
+
 pub type SampleBuffer<S> = &'static mut [S];
 
 pub struct FillableBuffer<S: 'static + PrimInt> {
     pub period: Nanoseconds,
-    pub amplitude: S,
+    /// Amplitude at the start of the buffer, which moves linearly to `amplitude` by the end
+    pub start_amplitude: u32,
+    pub amplitude: u32,
     pub invert: bool,
     pub phase_offset: Nanoseconds,
     pub sample_period: Nanoseconds,
+    /// Amplitude that produces no output, which the drive waveform swings around
+    zero: u32,
+    noise_shaping_bits: u32,
+    noise_shaping_error: u32,
     buffer: SampleBuffer<S>,
 }
 
 impl<S: PrimInt> FillableBuffer<S> {
     fn calculate(&mut self) {
+        let mask = (1 << self.noise_shaping_bits) - 1;
+        let len = self.buffer.len() as i32;
+        let step = self.amplitude as i32 - self.start_amplitude as i32;
         for (i, sample) in self.buffer.iter_mut().enumerate() {
             let t = (self.phase_offset.0 + i as u32 * self.sample_period.0) % self.period.0;
-            *sample = if (t > self.period.0 / 2) != self.invert {
-                self.amplitude
+            let amplitude = (self.start_amplitude as i32 + step * (i as i32 + 1) / len) as u32;
+            // The drive waveform is signed: it pushes for half of each period, and pulls for the
+            // other half on bipolar DACs. Unipolar DACs can't pull, so they output nothing then.
+            let drive = if (t > self.period.0 / 2) != self.invert {
+                amplitude as i32
+            } else if self.zero > 0 {
+                -(amplitude as i32)
             } else {
-                S::zero()
-            }
+                0
+            };
+            let amplitude = (self.zero as i32 + drive) as u32;
+            // First order sigma-delta modulation: the low bits that can't be output in this
+            // sample are carried over to the next one. The error is never larger than one sample
+            // step, so this can't exceed the maximum sample value. DACs without extra resolution
+            // have no low bits, so the error stays zero.
+            let amplitude = amplitude + self.noise_shaping_error;
+            self.noise_shaping_error = amplitude & mask;
+            *sample = cast(amplitude >> self.noise_shaping_bits).unwrap();
         }
     }
 
-    pub fn fill(mut self) -> SampleBuffer<S> {
+    pub fn fill(mut self) -> FilledBuffer<S> {
         self.calculate();
-        self.buffer
+        FilledBuffer {
+            noise_shaping_error: self.noise_shaping_error,
+            buffer: self.buffer,
+        }
     }
 }
 
+pub struct FilledBuffer<S: 'static> {
+    noise_shaping_error: u32,
+    buffer: SampleBuffer<S>,
+}
+
 pub struct DacDriver<D: Dac> {
     dac: D,
     dma_channel: samd_dma::Channel,
     descriptor_2: &'static mut samd_dma::TransferDescriptor,
     period: Nanoseconds,
-    amplitude: D::Amplitude,
+    amplitude: u32,
+    /// Interpolate from the amplitude at the end of the previous buffer, instead of jumping
+    glide: bool,
+    /// Amplitude at the end of the last buffer handed out to be filled
+    filled_amplitude: u32,
     invert: bool,
     phase_offset: Nanoseconds,
-    current_buffer: SampleBuffer<D::Amplitude>,
-    filled_buffer: Option<SampleBuffer<D::Amplitude>>,
+    noise_shaping_error: u32,
+    current_buffer: SampleBuffer<D::Sample>,
+    filled_buffer: Option<SampleBuffer<D::Sample>>,
     first_descriptor: bool,
 }
 
@@ -71,7 +118,7 @@ impl<D: Dac> DacDriver<D> {
     pub fn new(
         dac: D,
         mut dma_channel: samd_dma::Channel,
-        dma_resources: &'static mut DmaResources<D::Amplitude>,
+        dma_resources: &'static mut DmaResources<D::Sample>,
     ) -> Self {
         // Configure DMA channel
         // Only transfer one sample each time we are triggered
@@ -82,12 +129,24 @@ impl<D: Dac> DacDriver<D> {
         let buffer_1 = &mut dma_resources.buffer_1;
         let buffer_2 = &mut dma_resources.buffer_2;
 
+        // Start with no output, which isn't necessarily a zero sample
+        let zero = cast(dac.zero() >> D::NOISE_SHAPING_BITS).unwrap();
+        buffer_1.fill(zero);
+        buffer_2.fill(zero);
+
         let descriptor_1 = dma_channel.get_first_descriptor();
         let descriptor_2 = &mut dma_resources.descriptor_2;
 
         // Configure descriptors
-        descriptor_1.set_beat_size(samd_dma::BeatSize::Byte);
-        descriptor_2.set_beat_size(samd_dma::BeatSize::Byte);
+        // Each beat transfers one sample, whatever size the DAC uses
+        let beat_size = match core::mem::size_of::<D::Sample>() {
+            1 => samd_dma::BeatSize::Byte,
+            2 => samd_dma::BeatSize::HalfWord,
+            4 => samd_dma::BeatSize::Word,
+            _ => unreachable!(),
+        };
+        descriptor_1.set_beat_size(beat_size);
+        descriptor_2.set_beat_size(beat_size);
 
         descriptor_1.set_step_size(samd_dma::StepSize::X1);
         descriptor_2.set_step_size(samd_dma::StepSize::X1);
@@ -128,16 +187,24 @@ impl<D: Dac> DacDriver<D> {
             dma_channel,
             descriptor_2,
             period: 400.hz().into(),
-            amplitude: D::Amplitude::zero(),
+            amplitude: 0,
+            glide: false,
+            filled_amplitude: 0,
             invert: false,
             phase_offset: 0.ns(),
+            noise_shaping_error: 0,
             current_buffer: buffer_1,
             filled_buffer: Some(buffer_2),
             first_descriptor: true,
         }
     }
 
-    pub fn submit(&mut self, new_buffer: SampleBuffer<D::Amplitude>) {
+    pub fn submit(&mut self, new_buffer: FilledBuffer<D::Sample>) {
+        let FilledBuffer {
+            noise_shaping_error,
+            buffer: new_buffer,
+        } = new_buffer;
+
         let next_descriptor = if self.first_descriptor {
             &mut *self.descriptor_2
         } else {
@@ -155,13 +222,14 @@ impl<D: Dac> DacDriver<D> {
             + self.dac.sample_period().0 * new_buffer.len() as u32)
             % self.period.0)
             .ns();
+        self.noise_shaping_error = noise_shaping_error;
         self.filled_buffer = Some(new_buffer);
 
         // Resume in case we underflowed
         self.dma_channel.resume();
     }
 
-    pub fn request(&mut self) -> Option<FillableBuffer<D::Amplitude>> {
+    pub fn request(&mut self) -> Option<FillableBuffer<D::Sample>> {
         let flags = self.dma_channel.get_interrupt_flags();
         self.dma_channel.clear_interrupt_flags(flags);
 
@@ -189,13 +257,23 @@ impl<D: Dac> DacDriver<D> {
 
             self.first_descriptor = !self.first_descriptor;
             let old_buffer = core::mem::replace(&mut self.current_buffer, filled_buffer);
+            let start_amplitude = if self.glide {
+                self.filled_amplitude
+            } else {
+                self.amplitude
+            };
+            self.filled_amplitude = self.amplitude;
 
             Some(FillableBuffer {
                 period: self.period,
+                start_amplitude,
                 amplitude: self.amplitude,
                 invert: self.invert,
                 phase_offset: self.phase_offset,
                 sample_period: self.dac.sample_period(),
+                zero: self.dac.zero(),
+                noise_shaping_bits: D::NOISE_SHAPING_BITS,
+                noise_shaping_error: self.noise_shaping_error,
                 buffer: old_buffer,
             })
         } else {
@@ -205,11 +283,10 @@ impl<D: Dac> DacDriver<D> {
 }
 
 impl<D: Dac> Driver for DacDriver<D> {
-    fn set(&mut self, period: Nanoseconds, amplitude: u8, invert: bool) {
+    fn set(&mut self, period: Nanoseconds, amplitude: u16, invert: bool, glide: bool) {
         self.period = period;
-        self.amplitude =
-            cast(amplitude as u32 * cast::<_, u32>(D::MAX_AMPLITUDE).unwrap() / u8::MAX as u32)
-                .unwrap();
+        self.amplitude = amplitude as u32 * self.dac.max_amplitude() / u16::MAX as u32;
+        self.glide = glide;
         self.invert = invert;
     }
 }
@@ -0,0 +1,131 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+use hal::rtc::{Duration, Instant};
+
+use crate::hal;
+
+/// Parameters of the coil thermal model. Temperatures are rises above ambient, in millidegrees
+/// Celsius.
+#[derive(Clone, Copy)]
+pub struct ThermalConfig {
+    /// Time constant of the coil winding, which heats up quickly
+    pub fast_time_constant: Duration,
+    /// Time constant of the coil core and mounting, which heat up slowly
+    pub slow_time_constant: Duration,
+    /// Steady state rise of the winding over the core when driven continuously at full amplitude
+    pub fast_rise: u32,
+    /// Steady state rise of the core over ambient when driven continuously at full amplitude
+    pub slow_rise: u32,
+    /// Temperature rise at which amplitudes start to be reduced
+    pub derate_start: u32,
+    /// Temperature rise at which the coil is no longer driven at all
+    pub derate_end: u32,
+}
+
+impl Default for ThermalConfig {
+    fn default() -> Self {
+        Self {
+            fast_time_constant: Duration::secs(10),
+            slow_time_constant: Duration::secs(300),
+            fast_rise: 20_000,
+            slow_rise: 60_000,
+            derate_start: 40_000,
+            derate_end: 60_000,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ThermalState {
+    /// Estimated temperature rise above ambient, in millidegrees Celsius
+    pub temperature: u32,
+    /// Scale factor applied to amplitudes, where `u8::MAX` means no limiting
+    pub derating: u8,
+}
+
+impl ThermalState {
+    pub fn is_limiting(&self) -> bool {
+        self.derating < u8::MAX
+    }
+}
+
+/// Estimates coil temperature by integrating drive power, which is proportional to the square of
+/// the amplitude, through a two stage first order model.
+pub struct ThermalModel {
+    /// Temperature rise of each stage, with `FRACTION_BITS` bits below a millidegree so that
+    /// small steps towards a distant steady state aren't truncated away
+    fast: u64,
+    slow: u64,
+    amplitude: u16,
+    last_update: Instant,
+}
+
+impl ThermalModel {
+    const FRACTION_BITS: u32 = 16;
+
+    pub fn new(now: Instant) -> Self {
+        Self {
+            fast: 0,
+            slow: 0,
+            amplitude: 0,
+            last_update: now,
+        }
+    }
+
+    /// Advance one stage of the model towards its steady state by `dt`
+    fn step(temperature: u64, steady_state: u32, dt: u32, time_constant: u32) -> u64 {
+        let steady_state = (steady_state as u64) << Self::FRACTION_BITS;
+        if dt >= time_constant {
+            // The forward Euler approximation isn't stable for long steps
+            steady_state
+        } else {
+            (temperature as i64
+                + (steady_state as i64 - temperature as i64) * dt as i64 / time_constant as i64)
+                as u64
+        }
+    }
+
+    /// Integrate the heat produced by the amplitude that was applied since the last update, then
+    /// start applying `amplitude`, where `u16::MAX` is full scale.
+    pub fn update(&mut self, config: &ThermalConfig, now: Instant, amplitude: u16) {
+        if let Some(dt) = now.checked_duration_since(self.last_update) {
+            let dt = dt.to_millis();
+
+            // Power relative to full amplitude, as a 16-bit fraction
+            let power = self.amplitude as u64 * self.amplitude as u64 * (1 << 16)
+                / (u16::MAX as u64 * u16::MAX as u64);
+
+            self.fast = Self::step(
+                self.fast,
+                ((config.fast_rise as u64 * power) >> 16) as u32,
+                dt,
+                config.fast_time_constant.to_millis().max(1),
+            );
+            self.slow = Self::step(
+                self.slow,
+                ((config.slow_rise as u64 * power) >> 16) as u32,
+                dt,
+                config.slow_time_constant.to_millis().max(1),
+            );
+        }
+
+        self.amplitude = amplitude;
+        self.last_update = now;
+    }
+
+    pub fn state(&self, config: &ThermalConfig) -> ThermalState {
+        let temperature = ((self.fast + self.slow) >> Self::FRACTION_BITS) as u32;
+        let derating = if temperature <= config.derate_start {
+            u8::MAX
+        } else if temperature >= config.derate_end {
+            0
+        } else {
+            // Reduce linearly to nothing between the start and end temperatures
+            ((config.derate_end - temperature) as u64 * u8::MAX as u64
+                / (config.derate_end - config.derate_start) as u64) as u8
+        };
+        ThermalState {
+            temperature,
+            derating,
+        }
+    }
+}
@@ -0,0 +1,82 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::hal::clock;
+use crate::pac;
+use pac::WDT;
+
+pub type Period = pac::wdt::config::PER_A;
+
+pub type EarlyWarningOffset = pac::wdt::ewctrl::EWOFFSET_A;
+
+pub struct Watchdog {
+    wdt: WDT,
+}
+
+impl Watchdog {
+    /// The WDT is always powered, but it needs a clock; 1.024 kHz is conventional
+    pub fn new(_clock: clock::WdtClock, wdt: WDT) -> Self {
+        Self { wdt }
+    }
+
+    fn sync(&self) {
+        while self.wdt.status.read().syncbusy().bit() {}
+    }
+
+    /// Start the watchdog, which resets the device if it isn't fed for `period` cycles. An early
+    /// warning interrupt is raised `early_warning` cycles after the last feed.
+    pub fn start(&mut self, period: Period, early_warning: EarlyWarningOffset) {
+        self.sync();
+        self.wdt.ctrl.modify(|_, w| w.enable().clear_bit());
+        self.sync();
+
+        self.wdt.config.write(|w| w.per().variant(period));
+        self.wdt
+            .ewctrl
+            .write(|w| w.ewoffset().variant(early_warning));
+        self.wdt.intflag.write(|w| w.ew().set_bit());
+        self.wdt.intenset.write(|w| w.ew().set_bit());
+
+        self.sync();
+        self.wdt.ctrl.modify(|_, w| w.enable().set_bit());
+        self.sync();
+    }
+
+    pub fn feed(&self) {
+        // Writing anything other than the key causes an immediate reset
+        self.sync();
+        self.wdt.clear.write(|w| unsafe { w.clear().bits(0xa5) });
+    }
+
+    /// Clear the early warning interrupt flag. This only touches the flag, so it is safe to use
+    /// from the interrupt handler while the watchdog itself is owned by another task.
+    pub fn clear_early_warning() {
+        unsafe { pac::Peripherals::steal() }
+            .WDT
+            .intflag
+            .write(|w| w.ew().set_bit());
+    }
+}
+
+/// Records that a task has run since the last check. This can be shared between tasks of any
+/// priority without locking.
+pub struct Heartbeat(AtomicBool);
+
+impl Heartbeat {
+    pub const fn new() -> Self {
+        Self(AtomicBool::new(false))
+    }
+
+    pub fn beat(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether the task has run since the last check
+    pub fn check(&self) -> bool {
+        // thumbv6m has no atomic swap, but missing a beat that happens in between just makes the
+        // next check fail, which is harmless as long as the task keeps running
+        let alive = self.0.load(Ordering::Relaxed);
+        self.0.store(false, Ordering::Relaxed);
+        alive
+    }
+}